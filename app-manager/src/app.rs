@@ -1,15 +1,25 @@
-use std::{fs::create_dir, path::PathBuf, process::ExitStatus, sync::Arc};
+use std::{collections::{HashMap, HashSet}, error::Error, fs::{create_dir, read, remove_dir_all, remove_file}, path::{Path, PathBuf}, process::ExitStatus, sync::Arc, time::Duration};
+
+use devicemapper::{DmFlags, DmOptions};
 
 use ir_client::async_client::Client;
 use handler::{ImageError, Installer, InstallerTrait, Launcher};
-use log::{debug, info};
-use protocol::ApplicationInfo;
+use log::{debug, error, info, warn};
+use nix::{errno::Errno, libc::sync};
+use protocol::{AppStatus, ApplicationInfo, ProvisionStage, StopPath, StorageFilesystem};
 use thiserror::Error;
-use tokio::task::JoinHandle;
+use futures::{future::BoxFuture, FutureExt};
+use tokio::{fs::File, io::AsyncRead, task::{block_in_place, JoinError}};
 use uuid::Uuid;
 
-use crate::{diskmanager::{DiskManager, DiskManagerError, Partition}, dm::DeviceMapperError, dmcrypt::{CryptDevice, CryptoParams, DmCryptError, DmCryptTable, Key}, manager::AppManagerCtx, utils::{format_ext2, mount_ext2, mount_overlay, UtilitiesError}};
-use crate::dm::DeviceHandleWrapper;
+use crate::{diskmanager::DiskManagerError, dm::DeviceMapperError, dmcrypt::{CryptDevice, CryptoParams, DmCryptError, DmCryptTable, Key}, dmintegrity::{self, Integrity, IntegrityDevice, IntegrityDeviceError}, dmlinear::{LinearDevice, LinearDeviceError}, dmverity::{self, VerityDevice, VerityDeviceError, VerityParams, BLOCK_SIZE}, filesystem::{self, FilesystemError}, header::{HeaderError, PartitionHeader, HEADER_SECTORS}, keyring::KeyringError, keys::{wipe, KeyManagerError, KeyPurpose}, manager::AppManagerCtx, reencrypt::{KeyRotation, ReencryptError}, utils::{write_atomic, zero_device, UtilitiesError}};
+use crate::dm::{DeviceHandle, DeviceHandleWrapper};
+
+// Main storage layout: a small writable state filesystem, then the image
+// filesystem and its hash tree. Sizes are fixed once the image is installed.
+const STATE_STORAGE_SIZE: u64 = 16 * 1024 * 1024;
+const VERITY_PARAMS: &str = "verity.json";
+const RELEASED_IMAGE: &str = "released-image";
 
 #[derive(Error, Debug)]
 pub enum ApplicationError {
@@ -28,15 +38,66 @@ pub enum ApplicationError {
     #[error("Device mapper error")]
     DeviceMapperError(#[from] DeviceMapperError),
 
+    #[error("Key derivation error")]
+    KeyManagerError(#[from] KeyManagerError),
+
+    #[error("Kernel keyring error")]
+    KeyringError(#[from] KeyringError),
+
+    #[error("Re-encryption error")]
+    ReencryptError(#[from] ReencryptError),
+
+    #[error("Partition header error")]
+    HeaderError(#[from] HeaderError),
+
+    #[error("Partition {0} has no crypto header, the application has to be provisioned again")]
+    MissingHeader(Uuid),
+
+    #[error("{0} holds no filesystem, the application has to be provisioned again with reformat")]
+    NoFilesystem(String),
+
+    #[error("Integrity device error")]
+    IntegrityDeviceError(#[from] IntegrityDeviceError),
+
+    #[error("Partition {0} has no integrity metadata, the application has to be provisioned again")]
+    IntegrityNotFormatted(Uuid),
+
+    #[error("Integrity check of {0} failed, the storage was modified outside the realm")]
+    IntegrityViolation(String),
+
+    #[error("Linear device error")]
+    LinearDeviceError(#[from] LinearDeviceError),
+
+    #[error("Verity device error")]
+    VerityDeviceError(#[from] VerityDeviceError),
+
+    #[error("Cannot read sealed verity parameters from {0:?}")]
+    VerityParamsReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write sealed verity parameters to {0:?}")]
+    VerityParamsWriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Main storage of {0} bytes is too small")]
+    MainStorageTooSmall(u64),
+
     #[error("Main storage was not decrypted")]
     MainStorageNotDecrypted(),
 
     #[error("Secure storage was not decrypted")]
     SecureStorageNotDecrypted(),
 
+    #[error("Key rotation stopped with the secure storage under two keys, it resumes on the next boot")]
+    RotationInterrupted(),
+
     #[error("Utilities error")]
     UtilitiesError(#[from] UtilitiesError),
 
+    #[error("Filesystem error")]
+    FilesystemError(#[from] FilesystemError),
+
+    #[error("Cannot remove image staging directory {0:?}")]
+    StagingCleanupError(PathBuf, #[source] std::io::Error),
+
     #[error("Failed to create mountpoint {0:?}")]
     MkdirError(PathBuf, #[source] std::io::Error),
 
@@ -47,7 +108,28 @@ pub enum ApplicationError {
     ImageRegistryError(ir_client::error::Error),
 
     #[error("Application not installed")]
-    ApplicationNotInstalled()
+    ApplicationNotInstalled(),
+
+    #[error("Application is already running")]
+    AlreadyRunning(),
+
+    #[error("Application is not running")]
+    NotRunning(),
+
+    #[error("Storage with hmac integrity is keyed by the image and can't be moved to another one")]
+    UpdateWithHmacIntegrity(),
+
+    #[error("Image fills the whole main storage, reformat the application to make room for updates")]
+    NoUpdateSlot(),
+
+    #[error("Image {0} has the root of trust of the installed one, there is nothing to update")]
+    SameRootOfTrust(Uuid),
+
+    #[error("Image {0} was not released by the verifier")]
+    ImageNotReleased(Uuid),
+
+    #[error("Cannot access the released image {0:?}")]
+    ReleasedImageError(PathBuf, #[source] std::io::Error)
 }
 
 impl From<ir_client::error::Error> for ApplicationError {
@@ -56,50 +138,296 @@ impl From<ir_client::error::Error> for ApplicationError {
     }
 }
 
+#[derive(Clone, Copy)]
+struct ImageSlot {
+    start_block: u64,
+    data_blocks: u64
+}
+
+impl ImageSlot {
+    fn hash_start_block(&self) -> u64 {
+        self.start_block + self.data_blocks
+    }
+
+    fn end_block(&self) -> u64 {
+        self.hash_start_block() + dmverity::hash_blocks(self.data_blocks)
+    }
+}
+
+struct StagedImage {
+    image: Uuid,
+    root_of_trust: Box<[u8]>,
+    device: LinearDevice,
+    verity: VerityDevice,
+    params: VerityParams,
+    launcher: Box<dyn Launcher>,
+
+    // Sealed verity params of the current image, restored on rollback
+    previous_params: Vec<u8>,
+    secure_generation: u32
+}
+
+// Where the image of a fresh installation comes from
+enum ImageSource {
+    Registry,
+
+    /// Downloaded from the verifier while the realm booted, if it had to be
+    Verifier(Option<PathBuf>)
+}
+
+/// Resolves once the process of an application launch is gone
+pub type AppTask = BoxFuture<'static, (String, u64, Result<handler::Result<ExitStatus>, JoinError>)>;
+
 pub struct Application {
     ctx: Arc<AppManagerCtx>,
     workdir: PathBuf,
+    name: String,
     info: ApplicationInfo,
+    root_of_trust: Box<[u8]>,
+    image_source: ImageSource,
+    headers: HashMap<Uuid, PartitionHeader>,
+    fresh: HashSet<Uuid>,
+    integrity: HashMap<Uuid, (IntegrityDevice, u64)>,
     main_storage: Option<CryptDevice>,
+    state_storage: Option<LinearDevice>,
+    image_storage: Option<LinearDevice>,
+    verity: Option<VerityDevice>,
+    image_params: Option<VerityParams>,
     secure_storage: Option<CryptDevice>,
     installer: Box<dyn InstallerTrait>,
-    launcher: Option<Box<dyn Launcher>>
+    launcher: Option<Box<dyn Launcher>>,
+    stage: ProvisionStage,
+    failure: Option<(ProvisionStage, String)>,
+    launches: u64,
+    running: bool,
+    exit_status: Option<ExitStatus>
 }
 
 impl Application {
-    pub fn new(ctx: Arc<AppManagerCtx>, workdir: PathBuf, info: ApplicationInfo) -> Result<Self, ApplicationError> {
+    pub fn new(ctx: Arc<AppManagerCtx>, workdir: PathBuf, name: String, info: ApplicationInfo) -> Result<Self, ApplicationError> {
         if !workdir.exists() {
             create_dir(&workdir).map_err(ApplicationError::WorkdirCreation)?;
         }
-        let app_main_storage = workdir.join("main");
+        let installer = ctx.installers.installer(workdir.join("main"));
 
         Ok(Self {
             ctx,
             workdir,
+            name,
             info,
+            root_of_trust: Box::new([]),
+            image_source: ImageSource::Registry,
+            headers: HashMap::new(),
+            fresh: HashSet::new(),
+            integrity: HashMap::new(),
             main_storage: None,
+            state_storage: None,
+            image_storage: None,
+            verity: None,
+            image_params: None,
             secure_storage: None,
-            installer: Box::new(Installer::target(app_main_storage)),
-            launcher: None
+            installer,
+            launcher: None,
+            stage: ProvisionStage::RootOfTrust,
+            failure: None,
+            launches: 0,
+            running: false,
+            exit_status: None
         })
     }
 
-    fn decrypt_partition(&mut self, uuid: Uuid, params: &CryptoParams, key: &Key) -> Result<CryptDevice, ApplicationError> {
-        let partition = self.ctx.disks.partition_path_by_uuid(&uuid)
-            .ok_or(ApplicationError::PartitionNotFound(uuid.clone()))?;
-        let crypt_device_name = uuid.to_string();
+    /// Stage the provisioning reached, the one that failed if it did
+    pub fn stage(&self) -> ProvisionStage {
+        self.stage
+    }
 
-        info!("Creating dmcrypt device {}", crypt_device_name);
-        let device = CryptDevice(self.ctx.devicemapper.create(&crypt_device_name, None)?);
+    pub fn fail(&mut self, stage: ProvisionStage, error: String) {
+        error!("{} failed at {}: {}", self.name, stage, error);
+        self.failure = Some((stage, error));
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failure.is_some()
+    }
+
+    pub fn status(&self) -> AppStatus {
+        match (&self.failure, self.running, self.exit_status) {
+            (Some((stage, error)), _, _) => AppStatus::Failed { stage: *stage, error: error.clone() },
+            (None, true, _) => AppStatus::Running,
+            (None, false, Some(status)) => AppStatus::Exited(status),
+            (None, false, None) => AppStatus::Ready
+        }
+    }
+
+    fn enter(&mut self, stage: ProvisionStage) {
+        info!("{}: provisioning {}", self.name, stage);
+        self.stage = stage;
+    }
+
+    /// Looks up the root of trust of the application image, the storage keys
+    /// depend on it so it has to be known before anything is decrypted
+    pub async fn fetch_root_of_trust(&mut self, image_registry: &String) -> Result<(), ApplicationError> {
+        if let Some(uuid) = self.image_uuid() {
+            debug!("Fetching root of trust of image {}", uuid);
+            let client = Client::new(image_registry.to_string());
+            self.root_of_trust = client.get_manifest(uuid).await?.root_of_trust.into();
+        }
+
+        Ok(())
+    }
+
+    /// Image the application runs, installed or about to be provisioned
+    pub fn image_uuid(&self) -> Option<Uuid> {
+        self.info.image_uuid
+            .or(self.info.provision_info.as_ref().map(|info| info.uuid))
+    }
+
+    /// Root of trust and, for a fresh installation, the image handed out by
+    /// the verifier. Nothing is fetched from the registry afterwards.
+    pub fn release(&mut self, root_of_trust: Vec<u8>, image: Option<PathBuf>) {
+        self.root_of_trust = root_of_trust.into();
+        self.image_source = ImageSource::Verifier(image);
+    }
+
+    /// Where the image released by the verifier is kept until provisioning succeeds
+    pub fn released_image_path(&self) -> PathBuf {
+        self.workdir.join(RELEASED_IMAGE)
+    }
+
+    /// Whether provisioning installs the image, it only goes to a main
+    /// partition without a header or one being reformatted
+    pub fn needs_image(&self) -> bool {
+        match self.info.provision_info.as_ref() {
+            None => false,
+            Some(info) if info.reformat => true,
+            Some(_) => !matches!(self.partition_path(self.info.main_partition_uuid).map(|path| PartitionHeader::read(&path)), Ok(Ok(Some(_))))
+        }
+    }
+
+    fn sealing_key(&self, purpose: KeyPurpose, generation: u32) -> Result<Key, ApplicationError> {
+        self.image_sealing_key(&self.root_of_trust, purpose, generation)
+    }
+
+    // Sealing key as it would be for the image with `root_of_trust`
+    fn image_sealing_key(&self, root_of_trust: &[u8], purpose: KeyPurpose, generation: u32) -> Result<Key, ApplicationError> {
+        let partitions = [self.info.main_partition_uuid, self.info.secure_partition_uuid];
+        let mut key = self.ctx.keymanager.application_sealing_key(&partitions, root_of_trust, purpose, generation)?;
+        let raw = Key::Raw(key.to_vec());
+        wipe(&mut key);
+        Ok(raw)
+    }
+
+    fn verity_key(&self) -> Result<Key, ApplicationError> {
+        self.sealing_key(KeyPurpose::MainStorageVerity, 0)
+    }
+
+    // Secure storage key generation is kept on the state storage
+    fn key_rotation(&self) -> KeyRotation {
+        KeyRotation::new(self.workdir.join("state"))
+    }
+
+    fn partition_path(&self, uuid: Uuid) -> Result<PathBuf, ApplicationError> {
+        self.ctx.disks.partition_path(&uuid)
+            .ok_or(ApplicationError::PartitionNotFound(uuid))
+    }
+
+    // Device under the crypt mapping of a partition, offset and size in sectors
+    fn crypt_backing(&self, uuid: Uuid) -> Result<(PathBuf, u64, u64), ApplicationError> {
+        if let Some((integrity, sectors)) = self.integrity.get(&uuid) {
+            return Ok((integrity.path()?, 0, *sectors));
+        }
+
+        let path = self.partition_path(uuid)?;
+        Ok((path, HEADER_SECTORS, self.ctx.disks.partition_size(&uuid)?.saturating_sub(HEADER_SECTORS)))
+    }
+
+    // A provisioned partition is described by its header, the configured
+    // parameters only apply to fresh ones. The host keeps asking for
+    // provisioning until told otherwise, so a partition that already has a
+    // header keeps its data unless a reformat was requested.
+    fn open_header(&mut self, uuid: Uuid, params: &CryptoParams) -> Result<(), ApplicationError> {
+        let path = self.partition_path(uuid)?;
+        // A retry starts over on partitions this boot began to provision
+        let existing = match self.info.provision_info.as_ref() {
+            Some(info) if info.reformat => None,
+            Some(_) if self.fresh.contains(&uuid) => None,
+            _ => PartitionHeader::read(&path)?
+        };
+
+        let header = match (existing, self.info.provision_info.as_ref()) {
+            (Some(header), Some(_)) => {
+                info!("Partition {} is already provisioned, keeping its data", uuid);
+                header
+            },
+            (Some(header), None) => header,
+            (None, Some(info)) => {
+                match info.reformat {
+                    true => warn!("Reformatting partition {}", uuid),
+                    false => info!("Provisioning partition {}", uuid)
+                }
+                self.fresh.insert(uuid);
+                PartitionHeader::new(params.clone())
+            },
+            (None, None) => return Err(ApplicationError::MissingHeader(uuid))
+        };
+
+        debug!("Crypto parameters of {}: {:?}", uuid, header.crypto);
+        header.crypto.validate()?;
+        self.headers.insert(uuid, header);
+        Ok(())
+    }
+
+    fn header(&self, uuid: Uuid) -> &PartitionHeader {
+        self.headers.get(&uuid).expect("header is opened before mapping")
+    }
+
+    fn write_header(&mut self, uuid: Uuid) -> Result<(), ApplicationError> {
+        let path = self.partition_path(uuid)?;
+        self.headers.get_mut(&uuid).expect("header is opened before mapping").write(&path)?;
+        Ok(())
+    }
+
+    // Data key of a generation, unwrapped with the sealing key of the same generation
+    fn data_key(&self, uuid: Uuid, purpose: KeyPurpose, generation: u32) -> Result<Key, ApplicationError> {
+        match &self.sealing_key(purpose, generation)? {
+            Key::Raw(kek) => Ok(self.header(uuid).unlock(generation, kek)?),
+            _ => unreachable!("sealing keys are always raw")
+        }
+    }
+
+    fn add_data_key(&mut self, uuid: Uuid, purpose: KeyPurpose, generation: u32) -> Result<Key, ApplicationError> {
+        let key = match &self.sealing_key(purpose, generation)? {
+            Key::Raw(kek) => self.headers.get_mut(&uuid).expect("header is opened before mapping").add_key_slot(generation, kek)?,
+            _ => unreachable!("sealing keys are always raw")
+        };
+
+        self.write_header(uuid)?;
+        Ok(key)
+    }
+
+    // The key goes to the table through the kernel keyring so it never shows
+    // up in the table string, dm-crypt keeps its own copy once loaded
+    fn load_crypt_table(&self, device: &CryptDevice, uuid: Uuid, key: &Key) -> Result<(), ApplicationError> {
+        let (backing, offset, len) = self.crypt_backing(uuid)?;
         let table = DmCryptTable {
             start: 0,
-            len: partition.sz()?,
-            params,
-            offset: 0
+            len,
+            params: &self.header(uuid).crypto,
+            offset
         };
 
+        let (key, _kernel_key) = self.ctx.devicemapper.crypt_key(&format!("app-manager:{}", device.name()), key)?;
+
         debug!("Loading table for device with: {:#?}", table);
-        device.load(table, &partition.path(), key, None)?;
+        device.load(table, &backing, &key, None)?;
+
+        Ok(())
+    }
+
+    fn map_partition(&self, uuid: Uuid, crypt_device_name: String, key: &Key) -> Result<CryptDevice, ApplicationError> {
+        info!("Creating dmcrypt device {}", crypt_device_name);
+        let device = CryptDevice(self.ctx.devicemapper.open_or_create(&crypt_device_name, None)?);
+        self.load_crypt_table(&device, uuid, key)?;
 
         info!("Starting crypt device {}", crypt_device_name);
         device.resume()?;
@@ -107,72 +435,464 @@ impl Application {
         Ok(device)
     }
 
-    pub fn decrypt_main_storage(&mut self, params: &CryptoParams, key: &Key) -> Result<(), ApplicationError> {
+    // A fresh device gets its superblock formatted by the kernel, the
+    // provided size is only known afterwards
+    fn open_integrity(&mut self, uuid: Uuid) -> Result<(), ApplicationError> {
+        let integrity = match self.header(uuid).crypto.integrity.clone() {
+            Some(integrity) => integrity,
+            None => return Ok(())
+        };
+        let integrity = &integrity;
+
+        let path = self.partition_path(uuid)?;
+        let purpose = match uuid == self.info.main_partition_uuid {
+            true => KeyPurpose::MainStorageIntegrity,
+            false => KeyPurpose::SecureStorageIntegrity
+        };
+        let key = self.sealing_key(purpose, 0)?;
+
+        let name = format!("{}-integrity", uuid);
+        info!("Creating integrity device {}", name);
+        let device = IntegrityDevice(self.ctx.devicemapper.open_or_create(&name, None)?);
+
+        // A reformat drops whatever metadata the partition had before
+        let sectors = match (self.fresh.contains(&uuid), dmintegrity::provided_sectors(&path, HEADER_SECTORS)?) {
+            (true, _) => {
+                info!("Formatting integrity metadata on {:?}", path);
+                dmintegrity::clear_superblock(&path, HEADER_SECTORS)?;
+                device.load(&path, HEADER_SECTORS, 8, integrity, Some(&key), None)?;
+                device.resume()?;
+                dmintegrity::provided_sectors(&path, HEADER_SECTORS)?.ok_or(ApplicationError::IntegrityNotFormatted(uuid))?
+            },
+            (false, Some(sectors)) => sectors,
+            (false, None) => return Err(ApplicationError::IntegrityNotFormatted(uuid))
+        };
+
+        device.load(&path, HEADER_SECTORS, sectors, integrity, Some(&key), None)?;
+        device.resume()?;
+
+        self.integrity.insert(uuid, (device, sectors));
+        Ok(())
+    }
+
+    // The header selects the parameters and a wrong key is caught by its
+    // slot before anything gets mapped
+    fn decrypt_partition(&mut self, uuid: Uuid, params: &CryptoParams, purpose: KeyPurpose, generation: u32) -> Result<CryptDevice, ApplicationError> {
+        self.open_header(uuid, params)?;
+
+        let key = match self.fresh.contains(&uuid) {
+            true => self.add_data_key(uuid, purpose, generation)?,
+            false => self.data_key(uuid, purpose, generation)?
+        };
+
+        self.open_integrity(uuid)?;
+        let device = self.map_partition(uuid, uuid.to_string(), &key)?;
+
+        // Sectors never written have no valid tag and fail to read
+        if self.header(uuid).crypto.integrity.is_some() && self.fresh.contains(&uuid) {
+            info!("Initializing integrity tags of {}", uuid);
+            zero_device(&device.path()?)?;
+        }
+
+        Ok(device)
+    }
+
+    /// Fails if the host tampered with any authenticated storage
+    pub fn check_integrity(&self) -> Result<(), ApplicationError> {
+        for (integrity, _) in self.integrity.values() {
+            let mismatches = integrity.mismatches()?;
+
+            if mismatches > 0 {
+                error!("{} sectors of {} failed the integrity check", mismatches, integrity.name());
+                return Err(ApplicationError::IntegrityViolation(integrity.name()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Authentication failures reach userspace as EILSEQ, report them as such
+    // rather than as whatever operation happened to hit them
+    fn integrity_failure(&self, err: ApplicationError) -> ApplicationError {
+        if let Err(violation) = self.check_integrity() {
+            return violation;
+        }
+
+        let mut source: Option<&dyn Error> = Some(&err);
+        while let Some(e) = source {
+            let eilseq = e.downcast_ref::<std::io::Error>().and_then(|e| e.raw_os_error()) == Some(Errno::EILSEQ as i32)
+                || e.downcast_ref::<Errno>() == Some(&Errno::EILSEQ);
+
+            if eilseq && !self.integrity.is_empty() {
+                return ApplicationError::IntegrityViolation(self.name.clone());
+            }
+            source = e.source();
+        }
+
+        err
+    }
+
+    // Offset and length in sectors
+    fn map_linear(&self, backing: &PathBuf, name: String, offset: u64, len: u64) -> Result<LinearDevice, ApplicationError> {
+        info!("Creating linear device {}", name);
+        let linear = LinearDevice(self.ctx.devicemapper.open_or_create(&name, None)?);
+        linear.load(backing, offset, len, None)?;
+        linear.resume()?;
+
+        Ok(linear)
+    }
+
+    // The kernel refuses verity tables on writable devices
+    fn open_verity(&self, image: &LinearDevice, hash: &PathBuf, params: &VerityParams, slot: usize) -> Result<VerityDevice, ApplicationError> {
+        let name = self.image_device_name("verity", slot);
+        let readonly = || Some(DmOptions::default().set_flags(DmFlags::DM_READONLY));
+
+        info!("Creating verity device {}", name);
+        let device = VerityDevice(self.ctx.devicemapper.open_or_create(&name, readonly())?);
+        device.load(&image.path()?, hash, params, readonly())?;
+        device.resume()?;
+
+        Ok(device)
+    }
+
+    pub fn decrypt_main_storage(&mut self, params: &CryptoParams) -> Result<(), ApplicationError> {
         info!("Decrypting main partition");
-        self.main_storage = Some(self.decrypt_partition(self.info.main_partition_uuid, params, key)?);
+        let uuid = self.info.main_partition_uuid;
+        let device = self.decrypt_partition(uuid, params, KeyPurpose::MainStorage, 0)?;
+
+        let state = self.map_linear(&device.path()?, format!("{}-state", uuid), 0, STATE_STORAGE_SIZE / 512)?;
+        self.mount_storage(uuid, &state, "state", "State storage", &StorageFilesystem::default())?;
+
+        self.main_storage = Some(device);
+        self.state_storage = Some(state);
         Ok(())
     }
 
-    fn mount_storage(&self, device: &impl DeviceHandleWrapper, target: impl AsRef<str>, label: impl AsRef<str>) -> Result<(), ApplicationError> {
+    // Only storage on a freshly provisioned partition gets formatted, an
+    // existing one without a filesystem was never finished
+    fn mount_storage(&self, uuid: Uuid, device: &impl DeviceHandleWrapper, target: impl AsRef<str>, label: impl AsRef<str>, fs: &StorageFilesystem) -> Result<(), ApplicationError> {
         let path = device.path()?;
 
-        if self.info.provision_info.is_some() {
-            info!("Formatting storage: {}", label.as_ref());
-            format_ext2(&path, Some(label.as_ref()))?;
+        if self.fresh.contains(&uuid) {
+            info!("Formatting storage: {} as {}", label.as_ref(), fs.filesystem);
+            self.ctx.filesystems.format(fs.filesystem, &path, label.as_ref())?;
         }
 
         let target = self.workdir.join(target.as_ref());
-        create_dir(&target).map_err(|e| ApplicationError::MkdirError(target.clone(), e))?;
+        if !target.exists() {
+            create_dir(&target).map_err(|e| ApplicationError::MkdirError(target.clone(), e))?;
+        }
 
-        info!("Mounting {:?} storage in {:?}", path, target);
-        mount_ext2(&path, &target)?;
+        self.mount_existing(&path, &target, label.as_ref(), fs, false)
+    }
+
+    // Like the crypto header, the filesystem found on the device wins over
+    // the configured one, the options are only used when they match
+    fn mount_existing(&self, path: &PathBuf, target: &PathBuf, label: &str, fs: &StorageFilesystem, readonly: bool) -> Result<(), ApplicationError> {
+        let found = filesystem::probe(path)
+            .map_err(|e| self.integrity_failure(e.into()))?
+            .ok_or(ApplicationError::NoFilesystem(label.to_owned()))?;
+
+        let options = match found == fs.filesystem {
+            true => fs.mount_options.as_deref(),
+            false => {
+                warn!("{} holds {} instead of {}, mounting it without options", label, found, fs.filesystem);
+                None
+            }
+        };
+
+        if !readonly {
+            self.ctx.filesystems.grow_unmounted(found, path)?;
+        }
+
+        info!("Mounting {:?} ({}) in {:?}", path, found, target);
+        self.ctx.filesystems.mount(found, path, target, readonly, options)
+            .map_err(|e| self.integrity_failure(e.into()))?;
+
+        if !readonly {
+            self.ctx.filesystems.grow_mounted(found, path, target)?;
+        }
 
         Ok(())
     }
 
-    async fn install_app_from_registry(&mut self, url: &String, uuid: &Uuid) -> Result<Box<dyn Launcher>, ApplicationError> {
-        let client = Client::new(url.to_string());
-        let manifest = client.get_manifest(*uuid).await?;
-        let stream = client.get_image_stream(*uuid).await?;
-        Ok(self.installer.install(manifest.root_of_trust.into(), Box::new(stream)).await?)
+    // The image is checked against the root of trust the storage keys were derived from
+    async fn install_app_from_registry(&self, installer: &dyn InstallerTrait, url: &String, uuid: &Uuid, root_of_trust: &[u8]) -> Result<Box<dyn Launcher>, ApplicationError> {
+        let stream: Box<dyn AsyncRead + Unpin + Send> = match &self.image_source {
+            ImageSource::Registry => Box::new(Client::new(url.to_string()).get_image_stream(*uuid).await?),
+            ImageSource::Verifier(Some(path)) => Box::new(File::open(path).await.map_err(|e| ApplicationError::ReleasedImageError(path.clone(), e))?),
+            ImageSource::Verifier(None) => return Err(ApplicationError::ImageNotReleased(*uuid))
+        };
+
+        Ok(installer.install(root_of_trust.into(), stream).await?)
+    }
+
+    // The space after the state storage is split in two image slots, each
+    // followed by its hash tree, so an update fits next to the running image
+    fn image_slots(&self) -> Result<[ImageSlot; 2], ApplicationError> {
+        let (_, _, sectors) = self.crypt_backing(self.info.main_partition_uuid)?;
+
+        let state_blocks = STATE_STORAGE_SIZE / BLOCK_SIZE;
+        let slot_blocks = (sectors * 512 / BLOCK_SIZE)
+            .checked_sub(state_blocks)
+            .ok_or(ApplicationError::MainStorageTooSmall(sectors * 512))? / 2;
+        let data_blocks = slot_blocks - dmverity::hash_blocks(slot_blocks);
+
+        Ok([0, 1].map(|i| ImageSlot { start_block: state_blocks + i * slot_blocks, data_blocks }))
+    }
+
+    // Images installed before the slots were introduced fill the whole
+    // main storage from the first slot on
+    fn image_slot_of(&self, params: &VerityParams) -> Result<(usize, ImageSlot), ApplicationError> {
+        let slots = self.image_slots()?;
+        let start_block = params.data_start_block.unwrap_or(slots[0].start_block);
+        let index = slots.iter().position(|s| s.start_block == start_block).unwrap_or(0);
+
+        Ok((index, ImageSlot { start_block, data_blocks: params.data_blocks }))
+    }
+
+    // The first slot keeps the device names from before the slots
+    fn image_device_name(&self, kind: &str, slot: usize) -> String {
+        match slot {
+            0 => format!("{}-{}", self.info.main_partition_uuid, kind),
+            n => format!("{}-{}-{}", self.info.main_partition_uuid, kind, n)
+        }
     }
 
+    // Installs the image on a fresh filesystem in `slot` and builds its hash
+    // tree, `target` is where the image gets unpacked
+    async fn install_image(&self, image_registry: &String, image: Uuid, root_of_trust: &[u8], slot: usize, target: &PathBuf) -> Result<(LinearDevice, VerityParams, Box<dyn Launcher>), ApplicationError> {
+        let main = self.main_storage.as_ref().unwrap().path()?;
+        let location = self.image_slots()?[slot];
+
+        let device = self.map_linear(&main, self.image_device_name("image", slot), location.start_block * BLOCK_SIZE / 512, location.data_blocks * BLOCK_SIZE / 512)?;
+        let path = device.path()?;
+
+        let fs = self.info.image_fs.filesystem;
+        if !target.exists() {
+            create_dir(target).map_err(|e| ApplicationError::MkdirError(target.clone(), e))?;
+        }
+
+        // Read-only filesystems are built from the image unpacked in memory
+        if filesystem::is_writable(fs) {
+            info!("Formatting storage: Main storage as {}", fs);
+            self.ctx.filesystems.format(fs, &path, "Main storage")?;
+            self.ctx.filesystems.mount(fs, &path, target, false, None)?;
+        }
+
+        let installer = self.ctx.installers.installer(target.clone());
+        let launcher = self.install_app_from_registry(installer.as_ref(), image_registry, &image, root_of_trust).await?;
+
+        if filesystem::is_writable(fs) {
+            self.ctx.filesystems.unmount(target)?;
+        } else {
+            info!("Building {} image of {}", fs, self.name);
+            self.ctx.filesystems.build_image(fs, &path, target)?;
+            remove_dir_all(target).map_err(|e| ApplicationError::StagingCleanupError(target.clone(), e))?;
+        }
+
+        let mut params = dmverity::format(&path, &main, location.data_blocks, location.hash_start_block())?;
+        params.data_start_block = Some(location.start_block);
+
+        Ok((device, params, launcher))
+    }
+
+    // Seals the root hash of the tree with the application key, replacing
+    // the params is what switches to another image
+    fn seal_verity_params(&self, params: &VerityParams) -> Result<(), ApplicationError> {
+        let sealed = self.workdir.join("state").join(VERITY_PARAMS);
+        let content = match &self.verity_key()? {
+            Key::Raw(raw) => params.seal(raw)?,
+            _ => unreachable!("sealing keys are always raw")
+        };
+
+        write_atomic(&sealed, &content).map_err(|e| ApplicationError::VerityParamsWriteError(sealed, e))
+    }
+
+    async fn install_main_storage(&mut self, image_registry: &String, image: Uuid) -> Result<(LinearDevice, VerityParams), ApplicationError> {
+        let target = self.workdir.join("main");
+        let (device, params, launcher) = self.install_image(image_registry, image, &self.root_of_trust, 0, &target).await?;
+
+        self.seal_verity_params(&params)?;
+        self.launcher = Some(launcher);
+
+        Ok((device, params))
+    }
+
+    fn open_main_storage(&self) -> Result<(LinearDevice, VerityParams), ApplicationError> {
+        let main = self.main_storage.as_ref().unwrap().path()?;
+        let sealed = self.workdir.join("state").join(VERITY_PARAMS);
+        let content = read(&sealed).map_err(|e| ApplicationError::VerityParamsReadError(sealed, e))?;
+
+        let params = match &self.verity_key()? {
+            Key::Raw(raw) => VerityParams::unseal(&content, raw)?,
+            _ => unreachable!("sealing keys are always raw")
+        };
+
+        let (slot, location) = self.image_slot_of(&params)?;
+        let device = self.map_linear(&main, self.image_device_name("image", slot), location.start_block * BLOCK_SIZE / 512, location.data_blocks * BLOCK_SIZE / 512)?;
+
+        Ok((device, params))
+    }
+
+    /// The image is installed once and served read-only through dm-verity
+    /// afterwards, blocks the host modified fail to read
     pub async fn provision_app_image(&mut self, image_registry: &String) -> Result<(), ApplicationError> {
         if self.main_storage.is_none() {
             return Err(ApplicationError::MainStorageNotDecrypted());
         }
 
-        self.mount_storage(
-            self.main_storage.as_ref().unwrap(),
-            "main",
-            "Main storage"
-        )?;
+        let install = self.info.provision_info.as_ref()
+            .map(|info| info.uuid)
+            .filter(|_| self.fresh.contains(&self.info.main_partition_uuid));
 
-        if let Some(info) = self.info.provision_info.as_ref() {
-            let uuid = info.uuid;
-            self.launcher = Some(self.install_app_from_registry(image_registry, &uuid).await?);
-        } else {
+        let (image, params) = match install {
+            Some(uuid) => self.install_main_storage(image_registry, uuid).await?,
+            None => self.open_main_storage()?
+        };
+
+        let (slot, _) = self.image_slot_of(&params)?;
+        let verity = self.open_verity(&image, &self.main_storage.as_ref().unwrap().path()?, &params, slot)?;
+
+        let target = self.workdir.join("main");
+        if !target.exists() {
+            create_dir(&target).map_err(|e| ApplicationError::MkdirError(target.clone(), e))?;
+        }
+
+        self.mount_existing(&verity.path()?, &target, "Main storage", &self.info.image_fs, true)?;
+
+        if self.launcher.is_none() {
             self.launcher = Some(self.installer.validate().await?);
         }
 
+        self.image_storage = Some(image);
+        self.verity = Some(verity);
+        self.image_params = Some(params);
         Ok(())
     }
 
-    pub fn decrypt_secure_storage(&mut self, params: &CryptoParams, key: &Key) -> Result<(), ApplicationError> {
+    pub fn decrypt_secure_storage(&mut self, params: &CryptoParams) -> Result<(), ApplicationError> {
         info!("Decrypting secure memory partition");
-        self.secure_storage = Some(self.decrypt_partition(self.info.secure_partition_uuid, params, key)?);
+        let rotation = self.key_rotation();
+        let device = self.decrypt_partition(self.info.secure_partition_uuid, params, KeyPurpose::SecureStorage, rotation.generation()?)?;
+        self.secure_storage = Some(device);
+
+        if let Some(target) = rotation.pending()? {
+            info!("Resuming interrupted key rotation to generation {}", target);
+            self.reencrypt_secure_storage(target)?;
+        }
+
         Ok(())
     }
 
+    // Maps the partition a second time under the key of the `target`
+    // generation, moves the data over and switches the secure storage device
+    // to the new key. The slot of the old generation is dropped once the
+    // rotation committed.
+    fn reencrypt_secure_storage(&mut self, target: u32) -> Result<(), ApplicationError> {
+        let uuid = self.info.secure_partition_uuid;
+        let device = self.secure_storage.as_ref().ok_or(ApplicationError::SecureStorageNotDecrypted())?;
+        let key = self.data_key(uuid, KeyPurpose::SecureStorage, target)?;
+        let new = self.map_partition(uuid, format!("{}-rotate", uuid), &key)?;
+
+        let moved = device.path()
+            .and_then(|path| Ok((path, new.path()?)))
+            .map_err(ApplicationError::from)
+            .and_then(|(old, new)| self.key_rotation().run(&old, &new, &key).map_err(|e| self.integrity_failure(e.into())))
+            .and_then(|_| self.load_crypt_table(device, uuid, &key))
+            .and_then(|_| Ok(device.resume()?));
+        self.remove_device(new.0);
+        moved?;
+
+        self.headers.get_mut(&uuid).expect("header is opened before mapping").retain_key_slot(target);
+        self.write_header(uuid)
+    }
+
+    /// Re-encrypts the secure storage under a fresh key, the application is
+    /// stopped for the time and relaunched if it was running, also when the
+    /// rotation failed and the storage was brought back
+    pub async fn rotate_secure_key(&mut self, grace: Duration) -> (Option<AppTask>, Result<(), ApplicationError>) {
+        if self.secure_storage.is_none() {
+            return (None, Err(ApplicationError::SecureStorageNotDecrypted()));
+        }
+
+        let target = match self.key_rotation().generation() {
+            Ok(generation) => generation + 1,
+            Err(e) => return (None, Err(e.into()))
+        };
+
+        let running = self.pause("key rotation", grace).await;
+
+        let mut result = self.swap_secure_key(target);
+        if let Err(e) = &result {
+            error!("Key rotation of {} failed: {}", self.name, e);
+            if let Err(e) = self.recover_secure_storage(target) {
+                error!("Cannot bring back the secure storage of {}: {}", self.name, e);
+                return (None, result);
+            }
+        }
+
+        if running {
+            match self.launch() {
+                Ok(handle) => return (Some(handle), result),
+                Err(e) => result = result.and(Err(e))
+            }
+        }
+        (None, result)
+    }
+
+    fn swap_secure_key(&mut self, target: u32) -> Result<(), ApplicationError> {
+        for target in ["root", "secure"] {
+            self.unmount_if_mounted(&self.workdir.join(target))?;
+        }
+
+        let uuid = self.info.secure_partition_uuid;
+        self.add_data_key(uuid, KeyPurpose::SecureStorage, target)?;
+        self.key_rotation().begin(target, self.header(uuid).crypto.sector_size())?;
+        self.reencrypt_secure_storage(target)?;
+
+        self.mount_secure_storage()
+    }
+
+    // Until the first chunk moved the previous key still maps all of the
+    // data, once the rotation committed only the new one does. In between
+    // the storage stays unmounted and the next boot finishes the rotation.
+    fn recover_secure_storage(&mut self, target: u32) -> Result<(), ApplicationError> {
+        let rotation = self.key_rotation();
+        let uuid = self.info.secure_partition_uuid;
+
+        if rotation.generation()? >= target {
+            let key = self.data_key(uuid, KeyPurpose::SecureStorage, target)?;
+            let device = self.secure_storage.as_ref().ok_or(ApplicationError::SecureStorageNotDecrypted())?;
+            self.load_crypt_table(device, uuid, &key)?;
+            device.resume()?;
+        } else if rotation.started()? {
+            return Err(ApplicationError::RotationInterrupted());
+        }
+
+        for target in ["root", "secure"] {
+            self.unmount_if_mounted(&self.workdir.join(target))?;
+        }
+        self.mount_secure_storage()
+    }
+
+    fn mount_secure_storage(&self) -> Result<(), ApplicationError> {
+        let device = self.secure_storage.as_ref().ok_or(ApplicationError::SecureStorageNotDecrypted())?;
+        self.mount_existing(&device.path()?, &self.workdir.join("secure"), "Secure storage", &self.info.secure_fs, false)?;
+        self.mount_overlay()
+    }
+
     pub fn provision_secure_memory(&self) -> Result<(), ApplicationError> {
         if self.secure_storage.is_none() {
             return Err(ApplicationError::SecureStorageNotDecrypted());
         }
 
         self.mount_storage(
+            self.info.secure_partition_uuid,
             self.secure_storage.as_ref().unwrap(),
             "secure",
-            "Secure storage"
+            "Secure storage",
+            &self.info.secure_fs
         )?;
 
         Ok(())
@@ -191,33 +911,405 @@ impl Application {
         }
 
         debug!("Mounting overlay lower={:?}, upper={:?}, work={:?}, target={:?}", lower, upper, work, target);
-        mount_overlay(&lower, &upper, &work, &target)?;
+        self.ctx.filesystems.mount_overlay(&lower, &upper, &work, &target)?;
 
         Ok(())
     }
 
-    pub fn launch(&mut self) -> Result<JoinHandle<handler::Result<()>>, ApplicationError> {
-        if let Some(launcher) = self.launcher.as_mut() {
-            let target = self.workdir.join("root");
-            Ok(launcher.launch(&target)?)
-        } else {
-            Err(ApplicationError::ApplicationNotInstalled())
+    /// Takes the application from encrypted partitions to a mounted root,
+    /// the device mapper and mkfs work runs outside of the async workers so
+    /// several applications can be provisioned side by side
+    pub async fn provision(&mut self, params: &CryptoParams, image_registry: &String) -> Result<(), ApplicationError> {
+        self.failure = None;
+
+        self.enter(ProvisionStage::MainStorage);
+        block_in_place(|| self.decrypt_main_storage(params))?;
+
+        self.enter(ProvisionStage::Image);
+        self.provision_app_image(image_registry).await?;
+
+        self.enter(ProvisionStage::SecureStorage);
+        block_in_place(|| self.decrypt_secure_storage(params))?;
+        block_in_place(|| self.provision_secure_memory())?;
+
+        self.enter(ProvisionStage::Overlay);
+        self.mount_overlay()?;
+
+        // A failed provisioning starts over on fresh partitions, so the
+        // released image is kept until now
+        if let ImageSource::Verifier(image) = &mut self.image_source {
+            if let Some(path) = image.take() {
+                if let Err(e) = remove_file(&path) {
+                    warn!("Cannot remove released image {:?}: {}", path, e);
+                }
+            }
         }
+
+        Ok(())
     }
 
-    pub async fn terminate(&mut self) -> Result<ExitStatus, ApplicationError> {
-        if let Some(launcher) = self.launcher.as_mut() {
-            Ok(launcher.stop().await?)
-        } else {
-            Err(ApplicationError::ApplicationNotInstalled())
+    /// The returned task has to be awaited by the caller, its result goes
+    /// back to `exited` along with the launch it belongs to
+    pub fn launch(&mut self) -> Result<AppTask, ApplicationError> {
+        if self.running {
+            return Err(ApplicationError::AlreadyRunning());
+        }
+
+        self.check_integrity()?;
+
+        let launcher = self.launcher.as_mut().ok_or(ApplicationError::ApplicationNotInstalled())?;
+        let handle = launcher.launch(&self.workdir.join("root"))?;
+
+        self.launches += 1;
+        self.running = true;
+        self.exit_status = None;
+
+        let (name, launch) = (self.name.clone(), self.launches);
+        Ok(async move { (name, launch, handle.await) }.boxed())
+    }
+
+    /// Records how a launch ended, tasks of earlier launches are ignored
+    pub fn exited(&mut self, launch: u64, result: Result<handler::Result<ExitStatus>, JoinError>) {
+        if launch != self.launches {
+            return;
+        }
+
+        self.running = false;
+        match result {
+            Ok(Ok(status)) => {
+                info!("{} exited with {}", self.name, status);
+                self.exit_status = Some(status);
+            },
+            Ok(Err(e)) => error!("Lost track of {}: {}", self.name, e),
+            Err(e) => error!("Supervisor of {} panicked: {}", self.name, e)
         }
     }
 
+    /// Stops the application with the stop signal of its image and kills it
+    /// once `grace` runs out, one that already exited reports how it ended
+    pub async fn terminate(&mut self, grace: Duration) -> Result<(ExitStatus, StopPath), ApplicationError> {
+        if !self.running {
+            let status = self.exit_status.ok_or(ApplicationError::NotRunning())?;
+            return Ok((status, StopPath::AlreadyExited));
+        }
+
+        let launcher = self.launcher.as_mut().ok_or(ApplicationError::ApplicationNotInstalled())?;
+        let stop = launcher.stop(grace).await?;
+        self.stopped(stop.status);
+
+        let path = match (stop.exited_before, stop.killed) {
+            (true, _) => StopPath::AlreadyExited,
+            (false, true) => StopPath::Killed,
+            (false, false) => StopPath::Graceful
+        };
+        info!("{} {}, exit status: {}", self.name, path, stop.status);
+
+        Ok((stop.status, path))
+    }
+
     pub async fn kill(&mut self) -> Result<ExitStatus, ApplicationError> {
-        if let Some(launcher) = self.launcher.as_mut() {
-            Ok(launcher.kill().await?)
-        } else {
-            Err(ApplicationError::ApplicationNotInstalled())
+        if !self.running {
+            return self.exit_status.ok_or(ApplicationError::NotRunning());
+        }
+
+        let launcher = self.launcher.as_mut().ok_or(ApplicationError::ApplicationNotInstalled())?;
+        let status = launcher.kill().await?;
+        self.stopped(status);
+        Ok(status)
+    }
+
+    fn stopped(&mut self, status: ExitStatus) {
+        self.running = false;
+        self.exit_status = Some(status);
+    }
+
+    // Stops the application for maintenance, tells whether it has to be relaunched
+    async fn pause(&mut self, reason: &str, grace: Duration) -> bool {
+        if !self.running {
+            return false;
+        }
+
+        match self.terminate(grace).await {
+            Ok((status, _)) => {
+                info!("Stopped {} for {}, exit status: {}", self.name, reason, status);
+                true
+            },
+            Err(e) => {
+                warn!("Cannot stop {} for {}: {}", self.name, reason, e);
+                false
+            }
         }
     }
+
+    /// Installs `image` in the free image slot and moves the application over
+    /// to it while it keeps its secure storage. The application only stops
+    /// once the new image is staged and is relaunched if it was running, also
+    /// when the update failed and the previous image was brought back.
+    pub async fn update(&mut self, image_registry: &String, image: Uuid, grace: Duration) -> (Option<AppTask>, Result<(), ApplicationError>) {
+        let staged = match self.stage_update(image_registry, image).await {
+            Ok(staged) => staged,
+            Err(e) => return (None, Err(e))
+        };
+
+        let running = self.pause("update", grace).await;
+
+        let result = self.swap_image(staged);
+
+        match running {
+            true => match self.launch() {
+                Ok(handle) => (Some(handle), result),
+                Err(e) => (None, result.and(Err(e)))
+            },
+            false => (None, result)
+        }
+    }
+
+    // Everything that can be done while the application keeps running
+    async fn stage_update(&mut self, image_registry: &String, image: Uuid) -> Result<StagedImage, ApplicationError> {
+        let main = self.info.main_partition_uuid;
+        let secure = self.info.secure_partition_uuid;
+
+        if [main, secure].iter().any(|uuid| matches!(self.header(*uuid).crypto.integrity, Some(Integrity::Hmac(_)))) {
+            return Err(ApplicationError::UpdateWithHmacIntegrity());
+        }
+
+        let current = self.image_params.as_ref().ok_or(ApplicationError::ApplicationNotInstalled())?;
+        let (current_slot, current_location) = self.image_slot_of(current)?;
+        let slot = 1 - current_slot;
+        let location = self.image_slots()?[slot];
+
+        if location.start_block < current_location.end_block() && current_location.start_block < location.end_block() {
+            return Err(ApplicationError::NoUpdateSlot());
+        }
+
+        // Read up front, the swap fails only in ways it can roll back
+        let main_path = self.main_storage.as_ref().ok_or(ApplicationError::ApplicationNotInstalled())?.path()?;
+        let sealed = self.workdir.join("state").join(VERITY_PARAMS);
+        let previous_params = read(&sealed).map_err(|e| ApplicationError::VerityParamsReadError(sealed, e))?;
+        let secure_generation = self.key_rotation().generation()?;
+
+        let client = Client::new(image_registry.to_string());
+        let root_of_trust: Box<[u8]> = client.get_manifest(image).await?.root_of_trust.into();
+
+        // Keys derive from the root of trust, the storage would stay bound to it
+        if root_of_trust == self.root_of_trust {
+            return Err(ApplicationError::SameRootOfTrust(image));
+        }
+
+        info!("Staging image {} for {} in slot {}", image, self.name, slot);
+        let staging = self.workdir.join("staging");
+        let installed = self.install_image(image_registry, image, &root_of_trust, slot, &staging).await;
+        if staging.exists() {
+            let _ = remove_dir_all(&staging);
+        }
+
+        let name = self.image_device_name("image", slot);
+        let (device, params, launcher) = installed.map_err(|e| {
+            self.remove_device_by_name(&name);
+            e
+        })?;
+
+        let verity = match self.open_verity(&device, &main_path, &params, slot) {
+            Ok(verity) => verity,
+            Err(e) => {
+                self.remove_device_by_name(&self.image_device_name("verity", slot));
+                self.remove_device(device.0);
+                return Err(e);
+            }
+        };
+
+        Ok(StagedImage { image, root_of_trust, device, verity, params, launcher, previous_params, secure_generation })
+    }
+
+    // Data keys are bound to the image, so they get wrapped with the keys of
+    // the new one first. Rewriting the sealed verity params is what switches
+    // images, anything failing until the new image is mounted brings the
+    // previous one back.
+    fn swap_image(&mut self, staged: StagedImage) -> Result<(), ApplicationError> {
+        let keys = [
+            (self.info.main_partition_uuid, KeyPurpose::MainStorage, 0),
+            (self.info.secure_partition_uuid, KeyPurpose::SecureStorage, staged.secure_generation)
+        ];
+        let previous_root_of_trust = std::mem::replace(&mut self.root_of_trust, staged.root_of_trust.clone());
+
+        if let Err(e) = self.switch_image(&keys, &previous_root_of_trust, &staged) {
+            error!("Update of {} failed, rolling back: {}", self.name, e);
+            self.rollback_image(&keys, previous_root_of_trust, staged);
+            return Err(e);
+        }
+
+        // The previous image can't unlock the storage anymore
+        for (uuid, purpose, generation) in keys {
+            if let Err(e) = self.drop_data_key(uuid, &previous_root_of_trust, &staged.root_of_trust, purpose, generation) {
+                warn!("Cannot drop key slot of the previous image from {}: {}", uuid, e);
+            }
+        }
+
+        let devices = [self.verity.take().map(|d| d.0), self.image_storage.take().map(|d| d.0)];
+        for device in devices.into_iter().flatten() {
+            self.remove_device(device);
+        }
+
+        info!("{} switched to image {}", self.name, staged.image);
+        self.info.image_uuid = Some(staged.image);
+        self.image_storage = Some(staged.device);
+        self.verity = Some(staged.verity);
+        self.image_params = Some(staged.params);
+        self.launcher = Some(staged.launcher);
+
+        Ok(())
+    }
+
+    fn switch_image(&mut self, keys: &[(Uuid, KeyPurpose, u32)], previous_root_of_trust: &[u8], staged: &StagedImage) -> Result<(), ApplicationError> {
+        for target in ["root", "main"] {
+            self.unmount_if_mounted(&self.workdir.join(target))?;
+        }
+
+        for (uuid, purpose, generation) in keys.iter().copied() {
+            let kek = self.image_sealing_key(previous_root_of_trust, purpose, generation)?;
+            let new_kek = self.sealing_key(purpose, generation)?;
+
+            match (&kek, &new_kek) {
+                (Key::Raw(kek), Key::Raw(new_kek)) => self.headers.get_mut(&uuid)
+                    .expect("header is opened before mapping")
+                    .rewrap_key_slot(generation, kek, new_kek)?,
+                _ => unreachable!("sealing keys are always raw")
+            }
+            self.write_header(uuid)?;
+        }
+
+        self.seal_verity_params(&staged.params)?;
+
+        let target = self.workdir.join("main");
+        self.mount_existing(&staged.verity.path()?, &target, "Main storage", &self.info.image_fs, true)?;
+        self.mount_overlay()
+    }
+
+    // Best effort, the previous image stays mapped until the update succeeded
+    fn rollback_image(&mut self, keys: &[(Uuid, KeyPurpose, u32)], previous_root_of_trust: Box<[u8]>, staged: StagedImage) {
+        for target in ["root", "main"] {
+            if let Err(e) = self.unmount_if_mounted(&self.workdir.join(target)) {
+                error!("Cannot unmount {}: {}", target, e);
+            }
+        }
+
+        let root_of_trust = self.root_of_trust.clone();
+        for (uuid, purpose, generation) in keys.iter().copied() {
+            if let Err(e) = self.drop_data_key(uuid, &root_of_trust, &previous_root_of_trust, purpose, generation) {
+                error!("Cannot drop key slot of the new image from {}: {}", uuid, e);
+            }
+        }
+
+        let sealed = self.workdir.join("state").join(VERITY_PARAMS);
+        if let Err(e) = write_atomic(&sealed, &staged.previous_params) {
+            error!("Cannot restore {:?}: {}", sealed, e);
+        }
+        self.root_of_trust = previous_root_of_trust;
+
+        let remount = self.verity.as_ref()
+            .ok_or(ApplicationError::ApplicationNotInstalled())
+            .and_then(|verity| Ok(verity.path()?))
+            .and_then(|path| self.mount_existing(&path, &self.workdir.join("main"), "Main storage", &self.info.image_fs, true))
+            .and_then(|_| self.mount_overlay());
+        if let Err(e) = remount {
+            error!("Cannot remount the previous image of {}: {}", self.name, e);
+        }
+
+        self.remove_device(staged.verity.0);
+        self.remove_device(staged.device.0);
+    }
+
+    // Removes the slot the image with `root_of_trust` unlocks, unless the
+    // image with `keep` unlocks it as well
+    fn drop_data_key(&mut self, uuid: Uuid, root_of_trust: &[u8], keep: &[u8], purpose: KeyPurpose, generation: u32) -> Result<(), ApplicationError> {
+        let kek = self.image_sealing_key(root_of_trust, purpose, generation)?;
+        let keep = self.image_sealing_key(keep, purpose, generation)?;
+
+        match (&kek, &keep) {
+            (Key::Raw(kek), Key::Raw(keep)) => self.headers.get_mut(&uuid).expect("header is opened before mapping").drop_key_slot(generation, kek, keep),
+            _ => unreachable!("sealing keys are always raw")
+        }
+
+        self.write_header(uuid)
+    }
+
+    fn remove_device(&self, device: DeviceHandle) {
+        let name = device.name();
+        debug!("Removing device {}", name);
+
+        if let Err(e) = self.ctx.devicemapper.remove(device) {
+            error!("Cannot remove device {}: {}", name, e);
+        }
+    }
+
+    fn remove_device_by_name(&self, name: &String) {
+        match self.ctx.devicemapper.open(name) {
+            Ok(Some(device)) => self.remove_device(device),
+            Ok(None) => {},
+            Err(e) => error!("Cannot open device {}: {}", name, e)
+        }
+    }
+
+    /// Stops the application and releases its storage in the reverse order
+    /// it was set up. Every step is attempted, the first failure is returned.
+    pub async fn teardown(&mut self, grace: Duration) -> Result<(), ApplicationError> {
+        if self.running {
+            if let Err(e) = self.terminate(grace).await {
+                warn!("Cannot stop {}: {}", self.name, e);
+            }
+        }
+
+        unsafe { sync() };
+        let mut result = Ok(());
+
+        for target in ["root", "secure", "main", "state"] {
+            let target = self.workdir.join(target);
+            if let Err(e) = self.unmount_if_mounted(&target) {
+                error!("Cannot unmount {:?}: {}", target, e);
+                result = result.and(Err(e.into()));
+            }
+        }
+
+        let devices = [
+            self.secure_storage.take().map(|d| d.0),
+            self.verity.take().map(|d| d.0),
+            self.image_storage.take().map(|d| d.0),
+            self.state_storage.take().map(|d| d.0),
+            self.main_storage.take().map(|d| d.0)
+        ];
+        let integrity = self.integrity.drain().map(|(_, (d, _))| d.0);
+
+        for device in devices.into_iter().flatten().chain(integrity) {
+            let name = device.name();
+            debug!("Removing device {}", name);
+
+            if let Err(e) = self.ctx.devicemapper.remove(device) {
+                error!("Cannot remove device {}: {}", name, e);
+                result = result.and(Err(e.into()));
+            }
+        }
+
+        result
+    }
+
+    // Teardown also runs for applications that were only partly set up
+    fn unmount_if_mounted(&self, target: &Path) -> Result<(), UtilitiesError> {
+        match self.ctx.filesystems.unmount(target) {
+            Err(UtilitiesError::UnmountError(_, Errno::EINVAL | Errno::ENOENT)) => Ok(()),
+            res => res
+        }
+    }
+}
+
+/// Gives out installers unpacking images into a directory
+pub trait InstallerFactory: Send + Sync {
+    fn installer(&self, target: PathBuf) -> Box<dyn InstallerTrait>;
+}
+
+pub struct DockerInstallerFactory {}
+
+impl InstallerFactory for DockerInstallerFactory {
+    fn installer(&self, target: PathBuf) -> Box<dyn InstallerTrait> {
+        Box::new(Installer::target(target))
+    }
 }
@@ -0,0 +1,227 @@
+use std::{fmt::Display, fs::{create_dir, read, read_to_string, remove_dir, write}, path::{Path, PathBuf}, str::FromStr};
+
+use log::{debug, warn};
+use protocol::attestation::{pad_challenge, MockToken, CHALLENGE_LEN};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::rsi::{Rsi, RsiError};
+
+const TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+
+#[derive(Error, Debug)]
+pub enum AttestationError {
+    #[error("Invalid attestation backend `{0}`, expected rsi, tsm or mock")]
+    InvalidBackend(String),
+
+    #[error("Invalid image release `{0}`, expected registry or verifier")]
+    InvalidImageRelease(String),
+
+    #[error("Challenge is {0} bytes, at most {CHALLENGE_LEN} are allowed")]
+    ChallengeTooLong(usize),
+
+    #[error("RSI error")]
+    RsiError(#[from] RsiError),
+
+    #[error("TSM report error on {0:?}")]
+    TsmError(PathBuf, #[source] std::io::Error),
+
+    #[error("TSM report was generated by `{0}`, not the arm_cca_guest provider")]
+    UnexpectedTsmProvider(String),
+
+    #[error("Cannot measure {0:?} for the mock token")]
+    MeasurementError(PathBuf, #[source] std::io::Error),
+
+    #[error("Mock token serialization error")]
+    MockTokenError(#[from] serde_json::Error)
+}
+
+/// Where attestation tokens come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttestationBackend {
+    /// RSIIO_ATTESTATION_TOKEN of the islet rsi module
+    Rsi,
+
+    /// configfs-tsm report interface of upstream kernels
+    Tsm,
+
+    /// Unsigned token for testing without CCA
+    Mock
+}
+
+/// Who hands out the roots of trust of application images
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageRelease {
+    /// Read straight from the image manifest in the registry
+    Registry,
+
+    /// Released by the host side verifier in exchange for an attestation token
+    Verifier
+}
+
+pub trait TokenSource: Send + Sync {
+    fn token(&self, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, AttestationError>;
+}
+
+struct RsiTokenSource {
+    rsi: Rsi
+}
+
+impl TokenSource for RsiTokenSource {
+    fn token(&self, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, AttestationError> {
+        Ok(self.rsi.attestation_token(challenge)?)
+    }
+}
+
+struct TsmTokenSource {}
+
+impl TokenSource for TsmTokenSource {
+    // Every report gets its own configfs entry, removed once the token is read
+    fn token(&self, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, AttestationError> {
+        let report = Path::new(TSM_REPORT_DIR).join(format!("app-manager-{}", std::process::id()));
+        let err = |e| AttestationError::TsmError(report.clone(), e);
+
+        create_dir(&report).map_err(err)?;
+        let token = Self::report(&report, challenge);
+
+        if let Err(e) = remove_dir(&report) {
+            warn!("Cannot remove TSM report {:?}: {}", report, e);
+        }
+
+        token
+    }
+}
+
+impl TsmTokenSource {
+    fn report(report: &Path, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, AttestationError> {
+        let err = |e| AttestationError::TsmError(report.to_owned(), e);
+
+        write(report.join("inblob"), challenge).map_err(err)?;
+        let token = read(report.join("outblob")).map_err(err)?;
+
+        let provider = read_to_string(report.join("provider")).map_err(err)?;
+        if provider.trim() != "arm_cca_guest" {
+            return Err(AttestationError::UnexpectedTsmProvider(provider.trim().to_owned()));
+        }
+
+        Ok(token)
+    }
+}
+
+struct MockTokenSource {
+    measurement: Vec<u8>
+}
+
+impl TokenSource for MockTokenSource {
+    fn token(&self, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, AttestationError> {
+        let token = MockToken {
+            challenge: challenge.to_vec(),
+            measurement: self.measurement.clone()
+        };
+
+        Ok(serde_json::to_vec(&token)?)
+    }
+}
+
+/// Produces attestation tokens bound to caller supplied challenges
+pub struct Attester {
+    source: Box<dyn TokenSource>
+}
+
+impl Attester {
+    pub fn new(backend: &AttestationBackend) -> Result<Self, AttestationError> {
+        let source: Box<dyn TokenSource> = match backend {
+            AttestationBackend::Rsi => Box::new(RsiTokenSource { rsi: Rsi::open()? }),
+
+            AttestationBackend::Tsm => Box::new(TsmTokenSource {}),
+
+            AttestationBackend::Mock => {
+                warn!("Using mock attestation, tokens are not signed");
+                let exe = PathBuf::from("/proc/self/exe");
+                let binary = read(&exe).map_err(|e| AttestationError::MeasurementError(exe, e))?;
+                Box::new(MockTokenSource { measurement: Sha256::digest(&binary).to_vec() })
+            }
+        };
+
+        Ok(Self { source })
+    }
+
+    pub fn token(&self, challenge: &[u8]) -> Result<Vec<u8>, AttestationError> {
+        let challenge = pad_challenge(challenge)
+            .ok_or(AttestationError::ChallengeTooLong(challenge.len()))?;
+
+        debug!("Requesting attestation token for challenge {}", hex::encode(challenge));
+        self.source.token(&challenge)
+    }
+}
+
+impl Display for AttestationBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttestationBackend::Rsi => write!(f, "rsi"),
+            AttestationBackend::Tsm => write!(f, "tsm"),
+            AttestationBackend::Mock => write!(f, "mock")
+        }
+    }
+}
+
+impl FromStr for AttestationBackend {
+    type Err = AttestationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rsi" => Ok(AttestationBackend::Rsi),
+            "tsm" => Ok(AttestationBackend::Tsm),
+            "mock" => Ok(AttestationBackend::Mock),
+            _ => Err(AttestationError::InvalidBackend(s.to_owned()))
+        }
+    }
+}
+
+impl Serialize for AttestationBackend {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for AttestationBackend {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(deserializer)?;
+        v.parse().map_err(D::Error::custom)
+    }
+}
+
+impl Display for ImageRelease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageRelease::Registry => write!(f, "registry"),
+            ImageRelease::Verifier => write!(f, "verifier")
+        }
+    }
+}
+
+impl FromStr for ImageRelease {
+    type Err = AttestationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "registry" => Ok(ImageRelease::Registry),
+            "verifier" => Ok(ImageRelease::Verifier),
+            _ => Err(AttestationError::InvalidImageRelease(s.to_owned()))
+        }
+    }
+}
+
+impl Serialize for ImageRelease {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageRelease {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(deserializer)?;
+        v.parse().map_err(D::Error::custom)
+    }
+}
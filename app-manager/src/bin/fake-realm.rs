@@ -0,0 +1,486 @@
+use std::{collections::HashMap, fs::{self, create_dir, create_dir_all, remove_dir, remove_dir_all, remove_file, File}, io::{Seek, SeekFrom, Write}, os::unix::{fs::symlink, process::ExitStatusExt}, path::{Path, PathBuf}, process::ExitStatus, sync::{Arc, Mutex}, time::Duration};
+
+use app_manager::{app::InstallerFactory, attestation::{AttestationBackend, Attester}, config::Config, diskmanager::{DiskManager, DiskManagerError}, dm::{DeviceMapper, DeviceMapperBackend, DeviceMapperError, MappedDevice}, filesystem::{self, FilesystemError, Filesystems}, keyring::{KernelKey, KeyringError}, keys::{Key, KeyManager, KeyManagerError, RootKeySource}, manager::{AppManager, AppManagerCtx}, utils::{random_bytes, UtilitiesError}};
+use async_trait::async_trait;
+use clap::Parser;
+use devicemapper::DmOptions;
+use gpt::GptConfig;
+use handler::{ImageError, InstallerTrait, Launcher, StopStatus};
+use log::{debug, info};
+use nix::errno::Errno;
+use protocol::{transport::Transport, Filesystem};
+use tokio::{io::{sink, copy, AsyncRead}, sync::watch, task::JoinHandle};
+use uuid::Uuid;
+
+// Runs the app-manager on the host in place of a realm. The kernel, the
+// hardware and the image handler are replaced with plain files and
+// directories, so no hypervisor, root privileges or image registry are
+// needed. Storage is kept in the workdir:
+//
+//   partitions/<uuid>  contents of the GPT partitions of the attached disks
+//   devices/<name>     data of a mapped device, the mapping itself is not applied
+//   devices/<name>.d   files of the filesystem on the device
+//   workdir/           the app-manager workdir
+//
+// Mounting links the target to the files of the device, images are not
+// unpacked and applications only wait to be stopped.
+
+const FAKE_IMAGE: &str = "fake-image";
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Kernel command line the realm would have been booted with
+    #[clap(short, long, default_value = "")]
+    cmdline: String,
+
+    /// Unix socket standing in for the virtio-serial port
+    #[clap(short, long)]
+    serial_socket: Option<PathBuf>,
+
+    /// Directory keeping the storage of the realm
+    #[clap(short, long)]
+    workdir: PathBuf,
+
+    /// Disk images attached to the realm
+    #[clap(short, long)]
+    disk: Vec<PathBuf>
+}
+
+/// Partitions of the attached disk images, each kept in a file of its own
+struct FakeDiskManager {
+    partitions: HashMap<Uuid, (PathBuf, u64)>
+}
+
+impl FakeDiskManager {
+    fn available(disks: &[PathBuf], dir: &Path) -> std::io::Result<Self> {
+        create_dir_all(dir)?;
+        let mut partitions = HashMap::new();
+
+        for disk in disks.iter() {
+            let gpt = GptConfig::new()
+                .writable(false)
+                .initialized(true)
+                .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+                .open(disk);
+
+            if let Ok(gpt) = gpt {
+                for (id, partition) in gpt.partitions().iter() {
+                    let path = dir.join(partition.part_guid.to_string());
+                    let sectors = partition.last_lba - partition.first_lba + 1;
+
+                    // Follows resizes of the disk by the host
+                    File::options().create(true).truncate(false).write(true).open(&path)?.set_len(sectors * 512)?;
+
+                    info!("Adding new partition {:?}#{}, uuid: {}", disk, id, partition.part_guid);
+                    partitions.insert(partition.part_guid, (path, sectors));
+                }
+            }
+        }
+
+        Ok(Self { partitions })
+    }
+}
+
+impl DiskManager for FakeDiskManager {
+    fn partition_path(&self, uuid: &Uuid) -> Option<PathBuf> {
+        self.partitions.get(uuid).map(|(path, _)| path.clone())
+    }
+
+    fn partition_size(&self, uuid: &Uuid) -> Result<u64, DiskManagerError> {
+        self.partitions.get(uuid)
+            .map(|(_, sectors)| *sectors)
+            .ok_or(DiskManagerError::PartitionNotFound(*uuid))
+    }
+}
+
+#[derive(Clone, Default)]
+struct FakeTable {
+    targets: Vec<(u64, u64, String, String)>,
+    active: bool
+}
+
+type FakeDevices = Arc<Mutex<HashMap<String, FakeTable>>>;
+
+/// Devices live as long as the process, like mappings do until the realm
+/// powers off. Their data is kept across boots, keyed by the device name.
+struct FakeDeviceMapper {
+    dir: PathBuf,
+    devices: FakeDevices
+}
+
+impl FakeDeviceMapper {
+    fn new(dir: PathBuf) -> std::io::Result<Self> {
+        create_dir_all(&dir)?;
+        Ok(Self { dir, devices: FakeDevices::default() })
+    }
+
+    fn device(&self, name: &str) -> Box<dyn MappedDevice> {
+        Box::new(FakeDevice { devices: self.devices.clone(), path: self.dir.join(name), name: name.to_owned() })
+    }
+}
+
+impl DeviceMapperBackend for FakeDeviceMapper {
+    fn list(&self) -> Result<Vec<String>, DeviceMapperError> {
+        Ok(self.devices.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+        Ok(self.device(name))
+    }
+
+    fn create(&self, name: &str, _options: DmOptions) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+        debug!("Creating fake device {}", name);
+        self.devices.lock().unwrap().insert(name.to_owned(), FakeTable::default());
+        Ok(self.device(name))
+    }
+
+    // There is no keyring, the key goes into the table
+    fn add_key(&self, _desc: &str, _payload: &[u8]) -> Result<Option<KernelKey>, KeyringError> {
+        Ok(None)
+    }
+}
+
+struct FakeDevice {
+    devices: FakeDevices,
+    path: PathBuf,
+    name: String
+}
+
+impl FakeDevice {
+    fn with_table<T>(&self, f: impl FnOnce(&mut FakeTable) -> T) -> Result<T, DeviceMapperError> {
+        let mut devices = self.devices.lock().unwrap();
+        let table = devices.get_mut(&self.name)
+            .ok_or(DeviceMapperError::DeviceNodeMissing(self.name.clone()))?;
+        Ok(f(table))
+    }
+}
+
+impl MappedDevice for FakeDevice {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn is_active(&self) -> bool {
+        self.with_table(|table| table.active).unwrap_or(false)
+    }
+
+    fn resume(&self) -> Result<(), DeviceMapperError> {
+        self.with_table(|table| table.active = true)
+    }
+
+    fn suspend(&self) -> Result<(), DeviceMapperError> {
+        Ok(())
+    }
+
+    fn remove(&self) -> Result<(), DeviceMapperError> {
+        debug!("Removing fake device {}", self.name);
+        self.devices.lock().unwrap().remove(&self.name);
+        Ok(())
+    }
+
+    fn status(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        self.table()
+    }
+
+    fn table(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        self.with_table(|table| table.targets.clone())
+    }
+
+    // The data file gets the size of the mapping, verity shows its data device
+    fn table_load(&self, targets: &[(u64, u64, String, String)], _options: DmOptions) -> Result<(), DeviceMapperError> {
+        if !targets.iter().any(|(_, _, target, _)| target == "verity") {
+            let sectors = targets.iter().map(|(start, len, _, _)| start + len).max().unwrap_or(0);
+            File::options().create(true).truncate(false).write(true).open(&self.path)
+                .and_then(|file| file.set_len(sectors * 512))
+                .map_err(|e| DeviceMapperError::StorageError(self.name.clone(), e))?;
+        }
+
+        self.with_table(|table| table.targets = targets.to_vec())
+    }
+
+    fn path(&self) -> Result<PathBuf, DeviceMapperError> {
+        let targets = self.with_table(|table| table.active.then(|| table.targets.clone()))?
+            .ok_or(DeviceMapperError::DeviceNodeMissing(self.name.clone()))?;
+
+        match targets.first() {
+            Some((_, _, target, params)) if target == "verity" => params.split_whitespace().nth(1)
+                .map(PathBuf::from)
+                .ok_or(DeviceMapperError::TableMismatch(self.name.clone())),
+            _ => Ok(self.path.clone())
+        }
+    }
+}
+
+/// Filesystems are directories next to the device, mounts are symlinks to them
+struct FakeFilesystems {}
+
+impl FakeFilesystems {
+    fn files(devpath: &Path) -> PathBuf {
+        let mut files = devpath.as_os_str().to_owned();
+        files.push(".d");
+        PathBuf::from(files)
+    }
+
+    // Probing goes by the superblock magic, so the device gets it as well
+    fn write_signature(fs: Filesystem, devpath: &Path) -> std::io::Result<()> {
+        let (offset, magic) = filesystem::signature(fs);
+        let mut dev = File::options().write(true).open(devpath)?;
+        dev.seek(SeekFrom::Start(offset))?;
+        dev.write_all(magic)
+    }
+
+    fn create(fs: Filesystem, devpath: &Path, source: Option<&Path>) -> std::io::Result<()> {
+        let files = Self::files(devpath);
+        if files.exists() {
+            remove_dir_all(&files)?;
+        }
+
+        match source {
+            Some(source) => copy_dir(source, &files)?,
+            None => create_dir(&files)?
+        }
+
+        Self::write_signature(fs, devpath)
+    }
+
+    fn link(source: &Path, target: &Path) -> Result<(), Errno> {
+        if target.is_symlink() {
+            return Err(Errno::EBUSY);
+        }
+
+        remove_dir(target).and_then(|_| symlink(source, target))
+            .map_err(|e| Errno::from_raw(e.raw_os_error().unwrap_or(Errno::EIO as i32)))
+    }
+}
+
+impl Filesystems for FakeFilesystems {
+    fn format(&self, fs: Filesystem, devpath: &Path, _label: &str) -> Result<(), FilesystemError> {
+        if !filesystem::is_writable(fs) {
+            return Err(FilesystemError::ReadOnly(fs));
+        }
+
+        Self::create(fs, devpath, None).map_err(|e| FilesystemError::ProbeError(devpath.to_owned(), e))
+    }
+
+    fn build_image(&self, fs: Filesystem, devpath: &Path, source: &Path) -> Result<(), FilesystemError> {
+        Self::create(fs, devpath, Some(source)).map_err(|e| FilesystemError::ProbeError(devpath.to_owned(), e))
+    }
+
+    fn grow_unmounted(&self, _fs: Filesystem, _devpath: &Path) -> Result<(), FilesystemError> {
+        Ok(())
+    }
+
+    fn grow_mounted(&self, _fs: Filesystem, _devpath: &Path, _target: &Path) -> Result<(), FilesystemError> {
+        Ok(())
+    }
+
+    fn mount(&self, fs: Filesystem, devpath: &Path, target: &Path, _readonly: bool, _options: Option<&str>) -> Result<(), FilesystemError> {
+        let files = Self::files(devpath);
+        create_dir_all(&files).map_err(|e| FilesystemError::ProbeError(devpath.to_owned(), e))?;
+        Self::link(&files, target).map_err(|e| FilesystemError::MountError(devpath.to_owned(), fs, e))
+    }
+
+    // Only the upper layer shows up, nothing reads the root
+    fn mount_overlay(&self, _lower: &Path, upper: &Path, _work: &Path, target: &Path) -> Result<(), UtilitiesError> {
+        Self::link(upper, target).map_err(UtilitiesError::MountError)
+    }
+
+    fn unmount(&self, target: &Path) -> Result<(), UtilitiesError> {
+        let err = |e| UtilitiesError::UnmountError(target.to_owned(), e);
+
+        match target.symlink_metadata() {
+            Ok(meta) if meta.is_symlink() => {},
+            Ok(_) => return Err(err(Errno::EINVAL)),
+            Err(_) => return Err(err(Errno::ENOENT))
+        }
+
+        remove_file(target).and_then(|_| create_dir(target))
+            .map_err(|e| err(Errno::from_raw(e.raw_os_error().unwrap_or(Errno::EIO as i32))))
+    }
+}
+
+fn copy_dir(source: &Path, target: &Path) -> std::io::Result<()> {
+    create_dir(target)?;
+
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        match entry.file_type()?.is_dir() {
+            true => copy_dir(&entry.path(), &target.join(entry.file_name()))?,
+            false => { fs::copy(entry.path(), target.join(entry.file_name()))?; }
+        }
+    }
+
+    Ok(())
+}
+
+/// The image is only consumed, a marker stands in for its contents
+struct FakeInstaller {
+    target: PathBuf
+}
+
+#[async_trait]
+impl InstallerTrait for FakeInstaller {
+    async fn install(&self, rot: Box<[u8]>, mut image: Box<dyn AsyncRead + Unpin + Send>) -> handler::Result<Box<dyn Launcher>> {
+        let size = copy(&mut image, &mut sink()).await.map_err(ImageError::FileReadError)?;
+        info!("Installing {} byte image to {:?}", size, self.target);
+
+        fs::write(self.target.join(FAKE_IMAGE), hex::encode(rot)).map_err(ImageError::FileOpenError)?;
+        Ok(Box::new(FakeLauncher::default()))
+    }
+
+    async fn validate(&self) -> handler::Result<Box<dyn Launcher>> {
+        fs::read(self.target.join(FAKE_IMAGE)).map_err(ImageError::FileOpenError)?;
+        Ok(Box::new(FakeLauncher::default()))
+    }
+}
+
+struct FakeInstallerFactory {}
+
+impl InstallerFactory for FakeInstallerFactory {
+    fn installer(&self, target: PathBuf) -> Box<dyn InstallerTrait> {
+        Box::new(FakeInstaller { target })
+    }
+}
+
+/// Application that runs until it is stopped, a zero grace period stands in
+/// for one ignoring its stop signal
+#[derive(Default)]
+struct FakeLauncher {
+    exit: Option<watch::Sender<Option<ExitStatus>>>
+}
+
+impl FakeLauncher {
+    fn exit(&mut self, status: ExitStatus) -> Option<ExitStatus> {
+        let exit = self.exit.as_ref()?;
+        let status = *exit.borrow().as_ref().unwrap_or(&status);
+        exit.send_replace(Some(status));
+        Some(status)
+    }
+}
+
+#[async_trait]
+impl Launcher for FakeLauncher {
+    fn launch(&mut self, disk_path: &PathBuf) -> handler::Result<JoinHandle<handler::Result<ExitStatus>>> {
+        info!("Launching fake application in {:?}", disk_path);
+        let (exit, mut exited) = watch::channel(None);
+        self.exit = Some(exit);
+
+        Ok(tokio::spawn(async move {
+            let status = exited.wait_for(Option::is_some).await
+                .map(|status| status.unwrap())
+                .unwrap_or(ExitStatus::from_raw(0));
+            Ok(status)
+        }))
+    }
+
+    async fn stop(&mut self, grace: Duration) -> handler::Result<StopStatus> {
+        let running = self.exit.as_ref().is_some_and(|exit| exit.borrow().is_none());
+        let killed = running && grace.is_zero();
+        let signal = if killed { 9 } else { 15 };
+
+        Ok(StopStatus {
+            status: self.exit(ExitStatus::from_raw(signal)).unwrap_or(ExitStatus::from_raw(0)),
+            killed,
+            exited_before: !running
+        })
+    }
+
+    async fn kill(&mut self) -> handler::Result<ExitStatus> {
+        Ok(self.exit(ExitStatus::from_raw(9)).unwrap_or(ExitStatus::from_raw(0)))
+    }
+
+    async fn wait(&mut self) -> handler::Result<ExitStatus> {
+        let mut exited = match self.exit.as_ref() {
+            Some(exit) => exit.subscribe(),
+            None => return Ok(ExitStatus::from_raw(0))
+        };
+
+        let status = exited.wait_for(Option::is_some).await.map(|status| status.unwrap());
+        Ok(status.unwrap_or(ExitStatus::from_raw(0)))
+    }
+}
+
+/// Random root key kept in the workdir, so storage survives restarts
+struct FakeKeySource {
+    key: Key
+}
+
+impl FakeKeySource {
+    fn load(path: &Path) -> Result<Self, KeyManagerError> {
+        if !path.exists() {
+            let key = random_bytes(32).map_err(|e| KeyManagerError::KeyFileReadError(path.to_owned(), e))?;
+            fs::write(path, key).map_err(|e| KeyManagerError::KeyFileReadError(path.to_owned(), e))?;
+        }
+
+        let key = fs::read(path).map_err(|e| KeyManagerError::KeyFileReadError(path.to_owned(), e))?;
+        let len = key.len();
+        let key = key.try_into().map_err(|_| KeyManagerError::KeyFileSizeError(path.to_owned(), 32, len))?;
+
+        Ok(Self { key })
+    }
+}
+
+impl RootKeySource for FakeKeySource {
+    fn root_key(&self) -> Result<Key, KeyManagerError> {
+        Ok(self.key)
+    }
+}
+
+// The serial port of a realm is a unix socket here
+fn config(args: &Args) -> anyhow::Result<Config> {
+    let mut config = Config::defaults()?;
+    config.apply_cmdline(&args.cmdline);
+
+    config.workdir = args.workdir.join("workdir");
+    config.attestation = AttestationBackend::Mock;
+
+    if let Transport::Serial(_) = config.transport {
+        let socket = args.serial_socket.clone()
+            .ok_or(anyhow::anyhow!("Serial transport requested but no serial socket given"))?;
+        config.transport = Transport::Unix(socket);
+    }
+
+    Ok(config)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let args = Args::parse();
+    create_dir_all(&args.workdir)?;
+    let config = config(&args)?;
+    debug!("Using config: {:#?}", config);
+
+    info!("Starting fake realm");
+
+    // Mock attestation measures this binary, like the app-manager measures itself
+    let ctx = AppManagerCtx {
+        disks: Box::new(FakeDiskManager::available(&args.disk, &args.workdir.join("partitions"))?),
+        devicemapper: DeviceMapper::with_backend(Box::new(FakeDeviceMapper::new(args.workdir.join("devices"))?)),
+        keymanager: KeyManager::with_source(Box::new(FakeKeySource::load(&args.workdir.join("root-key"))?)),
+        attester: Attester::new(&config.attestation)?,
+        filesystems: Box::new(FakeFilesystems {}),
+        installers: Box::new(FakeInstallerFactory {})
+    };
+
+    let mut manager = AppManager::with_ctx(config, ctx).await?;
+
+    info!("Loading realm info from host");
+    manager.read_provision_info().await?;
+
+    info!("Finishing provisioning with host");
+    manager.finish_provisioning().await?;
+
+    info!("Provisioning...");
+    manager.provision_applications().await?;
+
+    info!("Launcing applications");
+    manager.launch_applications()?;
+
+    info!("Starting event loop");
+    manager.event_loop().await?;
+
+    Ok(())
+}
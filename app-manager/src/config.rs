@@ -1,13 +1,236 @@
-use std::path::PathBuf;
-use serde::Deserialize;
+use std::{collections::HashMap, fs::read_to_string, path::{Path, PathBuf}, str::FromStr};
+use log::{info, warn};
+use protocol::transport::Transport;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use thiserror::Error;
 
-use crate::dmcrypt::CryptoParams;
+use crate::{attestation::{AttestationBackend, ImageRelease}, dmcrypt::CryptoParams, keys::KeyBackend};
 
+const CONFIG_FILE: &str = "/etc/app-manager.yaml";
 
-#[derive(Deserialize, Debug)]
+static DEFAULTS: &str = r"
+workdir: /workdir
+transport: vsock:1337
+image_registry: http://192.168.100.1:8888
+image_release: registry
+provisioning_jobs: 4
+stop_grace: 10
+keys: cca
+attestation: rsi
+crypto:
+  cipher: Aes
+  iv_mode: Plain
+  block_mode: Cbc
+  iv_offset: 0
+";
+
+// Everything else is in use by the time the host is reached
+const HOST_SETTINGS: [&str; 4] = ["image_registry", "image_release", "provisioning_jobs", "stop_grace"];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Cannot read config file {0:?}")]
+    ReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid config")]
+    YamlError(#[from] serde_yaml::Error)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub workdir: PathBuf,
-    pub vsock_port: u32,
+    pub transport: Transport,
     pub crypto: CryptoParams,
-    pub image_registry: String
+    pub keys: KeyBackend,
+    pub attestation: AttestationBackend,
+    pub image_registry: String,
+    pub image_release: ImageRelease,
+    pub provisioning_jobs: usize,
+
+    /// Seconds an application gets to exit after its stop signal
+    pub stop_grace: u64
+}
+
+impl Config {
+    /// Built-in defaults, overridden by the config file of the initramfs and
+    /// then by the kernel command line
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config: Value = serde_yaml::from_str(DEFAULTS)?;
+
+        let path = Path::new(CONFIG_FILE);
+        if path.exists() {
+            info!("Loading config from {:?}", path);
+            let content = read_to_string(path).map_err(|e| ConfigError::ReadError(path.to_owned(), e))?;
+            let overrides: Value = serde_yaml::from_str(&content)?;
+
+            if !overrides.is_null() {
+                merge(&mut config, overrides);
+            }
+        }
+
+        let mut config: Self = serde_yaml::from_value(config)?;
+
+        match read_to_string("/proc/cmdline") {
+            Ok(cmdline) => config.apply_cmdline(&cmdline),
+            Err(e) => warn!("Cannot read kernel command line: {}", e)
+        }
+
+        Ok(config)
+    }
+
+    /// Built-in defaults alone
+    pub fn defaults() -> Result<Self, ConfigError> {
+        Ok(serde_yaml::from_str(DEFAULTS)?)
+    }
+
+    /// The host passes per realm settings as `app_manager.<name>=` on the kernel command line
+    pub fn apply_cmdline(&mut self, cmdline: &str) {
+        let params: HashMap<&str, &str> = cmdline.split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("app_manager."))
+            .filter_map(|arg| arg.split_once('='))
+            .collect();
+
+        for (name, value) in params {
+            self.apply(name, value, "kernel command line");
+        }
+    }
+
+    /// Settings delivered in `RealmInfo`, only those still unused when the
+    /// host is reached can be changed and `image_release` only tightened
+    pub fn apply_realm_info(&mut self, params: &HashMap<String, String>) {
+        for (name, value) in params {
+            if name == "image_release" && self.image_release == ImageRelease::Verifier {
+                warn!("Ignoring image_release from host, images are already released by the verifier");
+            } else if HOST_SETTINGS.contains(&name.as_str()) {
+                self.apply(name, value, "host");
+            } else {
+                warn!("Ignoring {} from host, it can only be set on the kernel command line", name);
+            }
+        }
+    }
+
+    fn apply(&mut self, name: &str, value: &str, source: &str) {
+        let res = match name {
+            "workdir" => {
+                self.workdir = PathBuf::from(value);
+                Ok(())
+            },
+            "image_registry" => {
+                self.image_registry = value.to_owned();
+                Ok(())
+            },
+            "stop_grace" => u64::from_str(value).map(|v| self.stop_grace = v).map_err(|e| e.to_string()),
+            "provisioning_jobs" => usize::from_str(value).map(|v| self.provisioning_jobs = v).map_err(|e| e.to_string()),
+            "transport" => Transport::from_str(value).map(|v| self.transport = v).map_err(|e| e.to_string()),
+            "keys" => KeyBackend::from_str(value).map(|v| self.keys = v).map_err(|e| e.to_string()),
+            "attestation" => AttestationBackend::from_str(value).map(|v| self.attestation = v).map_err(|e| e.to_string()),
+            "image_release" => ImageRelease::from_str(value).map(|v| self.image_release = v).map_err(|e| e.to_string()),
+            _ => Err("unknown setting".to_owned())
+        };
+
+        if let Err(e) = res {
+            warn!("Ignoring {} from {}: {}", name, source, e);
+        }
+    }
+}
+
+// Mappings are merged key by key, anything else is replaced
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Mapping(base), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(v) => merge(v, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        },
+        (base, overrides) => *base = overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dmcrypt::BlockMode;
+
+    use super::*;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_replaces_leaves_and_keeps_siblings() {
+        let mut config = yaml(DEFAULTS);
+        merge(&mut config, yaml("stop_grace: 30\ncrypto:\n  block_mode: Xts\n  iv_mode: Plain64\n"));
+
+        let config: Config = serde_yaml::from_value(config).unwrap();
+        assert_eq!(config.stop_grace, 30);
+        assert!(matches!(config.crypto.block_mode, BlockMode::Xts));
+        assert_eq!(config.crypto.iv_offset, 0);
+        assert_eq!(config.workdir, PathBuf::from("/workdir"));
+    }
+
+    #[test]
+    fn merge_replaces_sequences_whole() {
+        let mut value = yaml("options: [a, b]\n");
+        merge(&mut value, yaml("options: [c]\n"));
+        assert_eq!(value, yaml("options: [c]\n"));
+    }
+
+    #[test]
+    fn merge_adds_missing_keys() {
+        let mut value = yaml("crypto:\n  cipher: Aes\n");
+        merge(&mut value, yaml("crypto:\n  additional_options: [allow_discards]\n"));
+        assert_eq!(value, yaml("crypto:\n  cipher: Aes\n  additional_options: [allow_discards]\n"));
+    }
+
+    #[test]
+    fn cmdline_overrides_defaults() {
+        let mut config = Config::defaults().unwrap();
+        config.apply_cmdline("console=ttyS0 app_manager.transport=serial:app-manager app_manager.keys=file:/root.key \
+            app_manager.stop_grace=5 app_manager.image_release=verifier app_manager.workdir=/data");
+
+        assert_eq!(config.transport, Transport::Serial("app-manager".to_owned()));
+        assert_eq!(config.keys, KeyBackend::File(PathBuf::from("/root.key")));
+        assert_eq!(config.stop_grace, 5);
+        assert_eq!(config.image_release, ImageRelease::Verifier);
+        assert_eq!(config.workdir, PathBuf::from("/data"));
+    }
+
+    #[test]
+    fn invalid_values_are_ignored() {
+        let mut config = Config::defaults().unwrap();
+        config.apply_cmdline("app_manager.stop_grace=soon app_manager.keys=tpm app_manager.unknown=1 app_manager.provisioning_jobs");
+
+        assert_eq!(config.stop_grace, 10);
+        assert_eq!(config.keys, KeyBackend::Cca);
+        assert_eq!(config.provisioning_jobs, 4);
+    }
+
+    #[test]
+    fn host_changes_only_host_settings() {
+        let mut config = Config::defaults().unwrap();
+        config.apply_realm_info(&HashMap::from([
+            ("image_release".to_owned(), "verifier".to_owned()),
+            ("provisioning_jobs".to_owned(), "2".to_owned()),
+            ("attestation".to_owned(), "mock".to_owned()),
+            ("workdir".to_owned(), "/tmp".to_owned())
+        ]));
+
+        assert_eq!(config.image_release, ImageRelease::Verifier);
+        assert_eq!(config.provisioning_jobs, 2);
+        assert_eq!(config.attestation, AttestationBackend::Rsi);
+        assert_eq!(config.workdir, PathBuf::from("/workdir"));
+    }
+
+    #[test]
+    fn host_cannot_loosen_image_release() {
+        let mut config = Config::defaults().unwrap();
+        config.apply_cmdline("app_manager.image_release=verifier");
+        config.apply_realm_info(&HashMap::from([("image_release".to_owned(), "registry".to_owned())]));
+
+        assert_eq!(config.image_release, ImageRelease::Verifier);
+    }
 }
@@ -16,7 +16,19 @@ pub enum DiskManagerError {
     SizeReadError(#[source] std::io::Error),
 
     #[error("{0:?} is not a valid size")]
-    InvalidSize(String)
+    InvalidSize(String),
+
+    #[error("Partition of uuid: {0} not found")]
+    PartitionNotFound(Uuid)
+}
+
+/// Partitions of the disks attached to the realm, by GPT partition guid
+pub trait DiskManager: Send + Sync {
+    /// Block device of the partition
+    fn partition_path(&self, uuid: &Uuid) -> Option<PathBuf>;
+
+    /// Size of the partition in 512 byte sectors
+    fn partition_size(&self, uuid: &Uuid) -> Result<u64, DiskManagerError>;
 }
 
 pub struct Partition {
@@ -50,11 +62,11 @@ impl Partition {
     }
 }
 
-pub struct DiskManager {
+pub struct GptDiskManager {
     partitions: HashMap<Uuid, Partition>
 }
 
-impl DiskManager {
+impl GptDiskManager {
     pub fn available() -> Result<Self, DiskManagerError> {
         let partitions = File::open("/proc/partitions")
             .map_err(DiskManagerError::ProcPartitions)?;
@@ -94,8 +106,16 @@ impl DiskManager {
 
         Ok(manager)
     }
+}
+
+impl DiskManager for GptDiskManager {
+    fn partition_path(&self, uuid: &Uuid) -> Option<PathBuf> {
+        self.partitions.get(uuid).map(Partition::path)
+    }
 
-    pub fn partition_path_by_uuid(&self, uuid: &Uuid) -> Option<&Partition> {
+    fn partition_size(&self, uuid: &Uuid) -> Result<u64, DiskManagerError> {
         self.partitions.get(uuid)
+            .ok_or(DiskManagerError::PartitionNotFound(*uuid))?
+            .sz()
     }
 }
@@ -1,9 +1,10 @@
-use std::fs::read_dir;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
 
 use devicemapper::DeviceInfo;
 use devicemapper::DM;
@@ -11,8 +12,20 @@ use devicemapper::DmOptions;
 use devicemapper::DmName;
 use devicemapper::DevId;
 use devicemapper::DmFlags;
+use log::{debug, info};
+use nix::libc::{major, minor};
 use thiserror::Error;
-use uuid::Uuid;
+use tokio::task::block_in_place;
+
+use crate::{dmcrypt::Key, keyring::{KernelKey, KeyringError}};
+
+// udev may still hold a device that was just closed
+const REMOVE_RETRIES: usize = 5;
+const REMOVE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// devtmpfs creates the node of a new device asynchronously
+const NODE_RETRIES: usize = 50;
+const NODE_RETRY_DELAY: Duration = Duration::from_millis(20);
 
 #[derive(Error, Debug)]
 pub enum DeviceMapperError {
@@ -34,14 +47,26 @@ pub enum DeviceMapperError {
     #[error("Suspend Error")]
     SuspendError(#[source] devicemapper::DmError),
 
-    #[error("Block device enumeration error")]
-    EnumerationError(#[source] std::io::Error),
+    #[error("Cannot read status of device `{0}`")]
+    StatusError(String, #[source] devicemapper::DmError),
+
+    #[error("Cannot remove device `{0}`")]
+    RemoveError(String, #[source] devicemapper::DmError),
+
+    #[error("Cannot read table of device `{0}`")]
+    TableError(String, #[source] devicemapper::DmError),
+
+    #[error("Cannot list devices")]
+    ListError(#[source] devicemapper::DmError),
 
-    #[error("Device `{0}` doesn't show as a block device, resume?")]
-    DeviceNotShownInSysFs(String),
+    #[error("Existing device `{0}` is mapped differently than expected")]
+    TableMismatch(String),
 
-    #[error("Failed to read name from sysfs")]
-    SysFsNameReadError(#[source] std::io::Error)
+    #[error("Device node of `{0}` didn't show up, resume?")]
+    DeviceNodeMissing(String),
+
+    #[error("Storage of device `{0}` is not accessible")]
+    StorageError(String, #[source] std::io::Error)
 }
 
 pub trait DeviceHandleWrapper {
@@ -50,19 +75,119 @@ pub trait DeviceHandleWrapper {
     fn resume(&self) -> Result<(), DeviceMapperError> { self.dm_handle().resume() }
     fn suspend(&self) -> Result<(), DeviceMapperError> { self.dm_handle().suspend() }
     fn path(&self) -> Result<PathBuf, DeviceMapperError> { self.dm_handle().path() }
+    fn name(&self) -> String { self.dm_handle().name() }
+}
+
+/// A mapped device as the backend sees it
+pub trait MappedDevice: Send + Sync {
+    fn name(&self) -> String;
+
+    /// Whether a table is active, i.e. the device was resumed before
+    fn is_active(&self) -> bool;
+
+    fn resume(&self) -> Result<(), DeviceMapperError>;
+    fn suspend(&self) -> Result<(), DeviceMapperError>;
+    fn remove(&self) -> Result<(), DeviceMapperError>;
+    fn status(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError>;
+    fn table(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError>;
+    fn table_load(&self, targets: &[(u64, u64, String, String)], options: DmOptions) -> Result<(), DeviceMapperError>;
+
+    /// Block device the mapping is available at
+    fn path(&self) -> Result<PathBuf, DeviceMapperError>;
+}
+
+/// Creates and looks up mapped devices, the kernel device mapper or a stand-in
+pub trait DeviceMapperBackend: Send + Sync {
+    fn list(&self) -> Result<Vec<String>, DeviceMapperError>;
+    fn open(&self, name: &str) -> Result<Box<dyn MappedDevice>, DeviceMapperError>;
+    fn create(&self, name: &str, options: DmOptions) -> Result<Box<dyn MappedDevice>, DeviceMapperError>;
+
+    /// Hands a crypt key to the targets under `desc`, without a keyring the
+    /// key has to go into the table itself
+    fn add_key(&self, desc: &str, payload: &[u8]) -> Result<Option<KernelKey>, KeyringError>;
 }
 
 pub struct DeviceHandle {
-    dm: Arc<DM>,
-    info: DeviceInfo
+    device: Box<dyn MappedDevice>,
+
+    // Opened rather than created, the first table load only checks the active table
+    reused: AtomicBool
 }
 
 impl DeviceHandle {
-    pub fn new(dm: Arc<DM>, info: DeviceInfo) -> Self {
-        Self { dm, info }
+    pub fn new(device: Box<dyn MappedDevice>) -> Self {
+        Self { device, reused: AtomicBool::new(false) }
     }
 
     pub fn resume(&self) -> Result<(), DeviceMapperError> {
+        self.device.resume()
+    }
+
+    pub fn suspend(&self) -> Result<(), DeviceMapperError> {
+        self.device.suspend()
+    }
+
+    pub fn name(&self) -> String {
+        self.device.name()
+    }
+
+    pub fn remove(self) -> Result<(), DeviceMapperError> {
+        self.device.remove()
+    }
+
+    pub fn status(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        self.device.status()
+    }
+
+    /// Active table as reported by the kernel
+    pub fn table(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        self.device.table()
+    }
+
+    pub fn table_load(&self, targets: &[(u64, u64, String, String)], options: Option<DmOptions>) -> Result<(), DeviceMapperError> {
+        if self.reused.swap(false, Ordering::SeqCst) {
+            return self.verify_table(targets);
+        }
+
+        self.device.table_load(targets, options.unwrap_or_default())
+    }
+
+    // Keys, ciphers and every other argument have to match, only the
+    // optional arguments may have been extended by the kernel. The tables
+    // hold key material so they are not logged.
+    fn verify_table(&self, expected: &[(u64, u64, String, String)]) -> Result<(), DeviceMapperError> {
+        let active = self.table()?;
+
+        if !tables_match(&active, expected) {
+            debug!("Active table of {} has targets {:?}, expected {:?}", self.name(),
+                active.iter().map(|t| &t.2).collect::<Vec<_>>(),
+                expected.iter().map(|t| &t.2).collect::<Vec<_>>());
+            return Err(DeviceMapperError::TableMismatch(self.name()));
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> Result<PathBuf, DeviceMapperError> {
+        self.device.path()
+    }
+}
+
+struct KernelDevice {
+    dm: Arc<DM>,
+    info: DeviceInfo
+}
+
+impl MappedDevice for KernelDevice {
+    fn name(&self) -> String {
+        self.info.name().unwrap().to_string()
+    }
+
+    fn is_active(&self) -> bool {
+        self.info.flags().contains(DmFlags::DM_ACTIVE_PRESENT)
+    }
+
+    fn resume(&self) -> Result<(), DeviceMapperError> {
         let id = DevId::Name(self.info.name().unwrap());
 
         let _ = self.dm.device_suspend(&id, DmOptions::default())
@@ -71,7 +196,7 @@ impl DeviceHandle {
         Ok(())
     }
 
-    pub fn suspend(&self) -> Result<(), DeviceMapperError> {
+    fn suspend(&self) -> Result<(), DeviceMapperError> {
         let id = DevId::Name(self.info.name().unwrap());
 
         let _ = self.dm.device_suspend(&id, DmOptions::default().set_flags(DmFlags::DM_SUSPEND))
@@ -80,52 +205,360 @@ impl DeviceHandle {
         Ok(())
     }
 
-    pub fn table_load(&self, targets: &[(u64, u64, String, String)], options: Option<DmOptions>) -> Result<(), DeviceMapperError> {
+    fn remove(&self) -> Result<(), DeviceMapperError> {
+        let name = self.info.name().unwrap();
+
+        let _ = self.dm.device_remove(&DevId::Name(name), DmOptions::default())
+            .map_err(|e| DeviceMapperError::RemoveError(name.to_string(), e))?;
+
+        Ok(())
+    }
+
+    fn status(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        let name = self.info.name().unwrap();
+
+        let (_, status) = self.dm.table_status(&DevId::Name(name), DmOptions::default())
+            .map_err(|e| DeviceMapperError::StatusError(name.to_string(), e))?;
+
+        Ok(status)
+    }
+
+    fn table(&self) -> Result<Vec<(u64, u64, String, String)>, DeviceMapperError> {
+        let name = self.info.name().unwrap();
+        let options = DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE);
+
+        let (_, table) = self.dm.table_status(&DevId::Name(name), options)
+            .map_err(|e| DeviceMapperError::TableError(name.to_string(), e))?;
+
+        Ok(table)
+    }
+
+    fn table_load(&self, targets: &[(u64, u64, String, String)], options: DmOptions) -> Result<(), DeviceMapperError> {
         let id = DevId::Name(self.info.name().unwrap());
 
-        let _ = self.dm.table_load(&id, targets, options.unwrap_or(DmOptions::default()))
+        let _ = self.dm.table_load(&id, targets, options)
             .map_err(DeviceMapperError::TableLoad)?;
 
         Ok(())
     }
 
-    pub fn path(&self) -> Result<PathBuf, DeviceMapperError> {
-        for dir in read_dir("/sys/class/block/").map_err(DeviceMapperError::EnumerationError)? {
-            let dir = dir.map_err(DeviceMapperError::EnumerationError)?;
-            if let Ok(mut file) = File::open(dir.path().join("dm/name")) {
-                let mut name = String::new();
-                let _ = file.read_to_string(&mut name)
-                    .map_err(DeviceMapperError::SysFsNameReadError)?;
-
-                if name.trim() == self.info.name().unwrap().to_string() {
-                    return Ok(PathBuf::from_str("/dev").unwrap().join(dir.file_name()));
-                }
-            }
+    /// Waits for the `/dev/dm-N` node of the device
+    fn path(&self) -> Result<PathBuf, DeviceMapperError> {
+        let device = self.info.device();
+        let path = PathBuf::from(format!("/dev/dm-{}", device.minor));
+
+        let present = || device_number(&path) == Some((device.major, device.minor));
+        if present() {
+            return Ok(path);
         }
 
-        Err(DeviceMapperError::DeviceNotShownInSysFs(self.info.name().unwrap().to_string()))
+        // Other tasks keep running on the runtime while the node shows up
+        let found = block_in_place(|| (0..NODE_RETRIES).any(|_| {
+            sleep(NODE_RETRY_DELAY);
+            present()
+        }));
+
+        match found {
+            true => Ok(path),
+            false => Err(DeviceMapperError::DeviceNodeMissing(self.name()))
+        }
     }
 }
 
+struct KernelDeviceMapper {
+    dm: Arc<DM>
+}
+
+impl DeviceMapperBackend for KernelDeviceMapper {
+    fn list(&self) -> Result<Vec<String>, DeviceMapperError> {
+        let devices = self.dm.list_devices().map_err(DeviceMapperError::ListError)?;
+        Ok(devices.iter().map(|(name, _, _)| name.to_string()).collect())
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+        let dm_name = DmName::new(name)
+            .map_err(|e| DeviceMapperError::InvalidName(name.to_owned(), e))?;
+        let info = self.dm.device_info(&DevId::Name(dm_name))
+            .map_err(|e| DeviceMapperError::StatusError(name.to_owned(), e))?;
+
+        Ok(Box::new(KernelDevice { dm: self.dm.clone(), info }))
+    }
+
+    fn create(&self, name: &str, options: DmOptions) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+        let dm_name = DmName::new(name)
+            .map_err(|e| DeviceMapperError::InvalidName(name.to_owned(), e))?;
+
+        let info = self.dm.device_create(dm_name, None, options)
+            .map_err(|e| DeviceMapperError::CreateError(name.to_owned(), e))?;
+
+        Ok(Box::new(KernelDevice { dm: self.dm.clone(), info }))
+    }
+
+    // Logon keys can't be read back from userspace
+    fn add_key(&self, desc: &str, payload: &[u8]) -> Result<Option<KernelKey>, KeyringError> {
+        Ok(Some(KernelKey::add_logon(desc, payload)?))
+    }
+}
+
+fn device_number(path: impl AsRef<Path>) -> Option<(u32, u32)> {
+    let rdev = metadata(path).ok()?.rdev();
+    Some(unsafe { (major(rdev), minor(rdev)) })
+}
+
+// Target arguments with devices as major:minor, the way the kernel reports them
+fn normalize_params(params: &str) -> Vec<String> {
+    params.split_whitespace()
+        .map(|arg| match arg.starts_with("/dev/") {
+            true => device_number(arg).map_or(arg.to_owned(), |(major, minor)| format!("{}:{}", major, minor)),
+            false => arg.to_owned()
+        })
+        .collect()
+}
+
+// Arguments before the optional ones, which come as a count followed by the options
+fn positional_args(target: &str) -> Option<usize> {
+    match target {
+        "linear" => Some(2),
+        "crypt" => Some(5),
+        "integrity" => Some(4),
+        "verity" => Some(10),
+        _ => None
+    }
+}
+
+fn tables_match(active: &[(u64, u64, String, String)], expected: &[(u64, u64, String, String)]) -> bool {
+    active.len() == expected.len() && active.iter().zip(expected).all(|(a, e)| {
+        (a.0, a.1, &a.2) == (e.0, e.1, &e.2) && params_match(&a.2, &a.3, &e.3)
+    })
+}
+
+// The kernel reorders optional arguments and adds the defaults it picked, so
+// those only have to include the expected ones
+fn params_match(target: &str, active: &str, expected: &str) -> bool {
+    let (active, expected) = (normalize_params(active), normalize_params(expected));
+
+    let Some(n) = positional_args(target) else {
+        return active == expected;
+    };
+
+    if active.len() < n || expected.len() < n || active[..n] != expected[..n] {
+        return false;
+    }
+
+    let active_opts = active.get(n + 1..).unwrap_or_default();
+    expected.get(n + 1..).unwrap_or_default().iter().all(|opt| active_opts.contains(opt))
+}
+
 pub struct DeviceMapper {
-    dm: Arc<DM>,
+    backend: Box<dyn DeviceMapperBackend>
 }
 
 impl DeviceMapper {
     pub fn init() -> Result<Self, DeviceMapperError> {
-        Ok(Self {
-            dm: Arc::new(DM::new().map_err(DeviceMapperError::OpenError)?),
-        })
+        let dm = DM::new().map_err(DeviceMapperError::OpenError)?;
+        Ok(Self::with_backend(Box::new(KernelDeviceMapper { dm: Arc::new(dm) })))
+    }
+
+    pub fn with_backend(backend: Box<dyn DeviceMapperBackend>) -> Self {
+        Self { backend }
+    }
+
+    /// Names of all mapped devices
+    pub fn list(&self) -> Result<Vec<String>, DeviceMapperError> {
+        self.backend.list()
+    }
+
+    /// Handle of an already mapped device
+    pub fn open(&self, name: &String) -> Result<Option<DeviceHandle>, DeviceMapperError> {
+        if !self.list()?.contains(name) {
+            return Ok(None);
+        }
+
+        Ok(Some(DeviceHandle::new(self.backend.open(name)?)))
+    }
+
+    /// Reuses a device left behind by a previous run, e.g. after an app-manager
+    /// restart. Its first table load checks the active table instead of replacing it.
+    pub fn open_or_create(&self, name: &String, options: Option<DmOptions>) -> Result<DeviceHandle, DeviceMapperError> {
+        match self.open(name)? {
+            Some(device) => {
+                info!("Reusing existing device {}", name);
+                let active = device.device.is_active();
+                device.reused.store(active, Ordering::SeqCst);
+                Ok(device)
+            },
+            None => self.create(name, options)
+        }
     }
 
     pub fn create(&self, name: &String, options: Option<DmOptions>) -> Result<DeviceHandle, DeviceMapperError> {
-        let name = DmName::new(&name)
-            .map_err(|e| DeviceMapperError::InvalidName(name.clone(), e))?;
+        Ok(DeviceHandle::new(self.backend.create(name, options.unwrap_or_default())?))
+    }
+
+    /// Removes a device nothing holds open anymore, retrying while it is busy
+    pub fn remove(&self, device: DeviceHandle) -> Result<(), DeviceMapperError> {
+        let name = device.name();
+
+        let mut retries = REMOVE_RETRIES;
+        loop {
+            match device.device.remove() {
+                Ok(()) => break Ok(()),
+                Err(e) if retries == 0 => break Err(e),
+                Err(e) => {
+                    debug!("Cannot remove {} yet: {}", name, e);
+                    retries -= 1;
+                    block_in_place(|| sleep(REMOVE_RETRY_DELAY));
+                }
+            }
+        }
+    }
+
+    /// Key for a crypt table, valid as long as the returned handle is kept
+    pub fn crypt_key(&self, desc: &str, key: &Key) -> Result<(Key, Option<KernelKey>), KeyringError> {
+        let raw = match key {
+            Key::Raw(raw) => raw,
+            _ => unreachable!("sealing keys are always raw")
+        };
 
-        let info = self.dm.device_create(name, None, options.unwrap_or(DmOptions::default()))
-            .map_err(|e| DeviceMapperError::CreateError(name.to_string(), e))?;
+        Ok(match self.backend.add_key(desc, raw)? {
+            Some(kernel_key) => (kernel_key.dm_key(), Some(kernel_key)),
+            None => (Key::Raw(raw.clone()), None)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    type Table = Vec<(u64, u64, String, String)>;
 
-        Ok(DeviceHandle::new(self.dm.clone(), info))
+    const CRYPT_KEY: &str = "aes-xts-plain64 :64:logon:app-manager:a0 0";
+
+    fn target(kind: &str, params: &str) -> (u64, u64, String, String) {
+        (0, 2048, kind.to_owned(), params.to_owned())
+    }
+
+    // Device left behind with `table` active, loads are recorded
+    struct StubDevice {
+        table: Table,
+        loaded: Arc<Mutex<Option<Table>>>
     }
 
+    impl MappedDevice for StubDevice {
+        fn name(&self) -> String { "stub".to_owned() }
+        fn is_active(&self) -> bool { !self.table.is_empty() }
+        fn resume(&self) -> Result<(), DeviceMapperError> { Ok(()) }
+        fn suspend(&self) -> Result<(), DeviceMapperError> { Ok(()) }
+        fn remove(&self) -> Result<(), DeviceMapperError> { Ok(()) }
+        fn status(&self) -> Result<Table, DeviceMapperError> { Ok(self.table.clone()) }
+        fn table(&self) -> Result<Table, DeviceMapperError> { Ok(self.table.clone()) }
+        fn path(&self) -> Result<PathBuf, DeviceMapperError> { Ok(PathBuf::from("/dev/null")) }
+
+        fn table_load(&self, targets: &[(u64, u64, String, String)], _options: DmOptions) -> Result<(), DeviceMapperError> {
+            *self.loaded.lock().unwrap() = Some(targets.to_vec());
+            Ok(())
+        }
+    }
+
+    struct StubBackend {
+        table: Table,
+        loaded: Arc<Mutex<Option<Table>>>
+    }
+
+    impl DeviceMapperBackend for StubBackend {
+        fn list(&self) -> Result<Vec<String>, DeviceMapperError> { Ok(vec!["stub".to_owned()]) }
+        fn add_key(&self, _desc: &str, _payload: &[u8]) -> Result<Option<KernelKey>, KeyringError> { Ok(None) }
+
+        fn open(&self, _name: &str) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+            Ok(Box::new(StubDevice { table: self.table.clone(), loaded: self.loaded.clone() }))
+        }
+
+        fn create(&self, name: &str, _options: DmOptions) -> Result<Box<dyn MappedDevice>, DeviceMapperError> {
+            self.open(name)
+        }
+    }
+
+    fn reopen(active: Table) -> (DeviceHandle, Arc<Mutex<Option<Table>>>) {
+        let loaded = Arc::new(Mutex::new(None));
+        let dm = DeviceMapper::with_backend(Box::new(StubBackend { table: active, loaded: loaded.clone() }));
+        (dm.open_or_create(&"stub".to_owned(), None).unwrap(), loaded)
+    }
+
+    #[test]
+    fn device_paths_become_numbers() {
+        assert_eq!(normalize_params("/dev/null 0 /dev/does-not-exist"), ["1:3", "0", "/dev/does-not-exist"]);
+        assert_eq!(normalize_params("  sha256   253:1 "), ["sha256", "253:1"]);
+    }
+
+    #[test]
+    fn known_targets_have_positional_args() {
+        assert_eq!(positional_args("crypt"), Some(5));
+        assert_eq!(positional_args("verity"), Some(10));
+        assert_eq!(positional_args("zero"), None);
+    }
+
+    #[test]
+    fn kernel_may_reorder_and_add_options() {
+        let expected = format!("{} /dev/null 2048 2 allow_discards sector_size:4096", CRYPT_KEY);
+        let active = format!("{} 1:3 2048 3 sector_size:4096 iv_large_sectors allow_discards", CRYPT_KEY);
+        assert!(params_match("crypt", &active, &expected));
+        assert!(params_match("crypt", &format!("{} 1:3 2048", CRYPT_KEY), &format!("{} /dev/null 2048", CRYPT_KEY)));
+    }
+
+    #[test]
+    fn positional_args_and_expected_options_must_match() {
+        let expected = format!("{} 1:3 2048 1 allow_discards", CRYPT_KEY);
+        assert!(!params_match("crypt", &format!("{} 1:3 2048", CRYPT_KEY), &expected));
+        assert!(!params_match("crypt", &format!("{} 1:3 4096 1 allow_discards", CRYPT_KEY), &expected));
+        assert!(!params_match("crypt", "aes-xts-plain64 :64:logon:app-manager:a1 0 1:3 2048 1 allow_discards", &expected));
+        assert!(!params_match("crypt", "aes-xts-plain64", &expected));
+    }
+
+    #[test]
+    fn unknown_targets_match_exactly() {
+        assert!(params_match("zero", "", ""));
+        assert!(!params_match("striped", "2 256 1:3 0 1:4 0", "2 256 1:4 0 1:3 0"));
+    }
+
+    #[test]
+    fn tables_match_target_by_target() {
+        let table = vec![target("linear", "1:3 0"), (2048, 8, "linear".to_owned(), "1:3 4096".to_owned())];
+        assert!(tables_match(&table, &table));
+        assert!(!tables_match(&table, &table[..1]));
+        assert!(!tables_match(&[target("linear", "1:3 0")], &[target("crypt", "1:3 0")]));
+        assert!(!tables_match(&[target("linear", "1:3 0")], &[(0, 4096, "linear".to_owned(), "1:3 0".to_owned())]));
+    }
+
+    #[test]
+    fn reused_device_checks_the_active_table() {
+        let (device, loaded) = reopen(vec![target("linear", "1:3 0")]);
+        assert!(matches!(device.table_load(&[target("linear", "1:3 2048")], None), Err(DeviceMapperError::TableMismatch(_))));
+        assert!(loaded.lock().unwrap().is_none());
+
+        let (device, loaded) = reopen(vec![target("linear", "1:3 0")]);
+        device.table_load(&[target("linear", "/dev/null 0")], None).unwrap();
+        assert!(loaded.lock().unwrap().is_none());
+
+        // Only the first load is checked
+        device.table_load(&[target("linear", "1:3 2048")], None).unwrap();
+        assert_eq!(loaded.lock().unwrap().as_deref(), Some(&[target("linear", "1:3 2048")][..]));
+    }
+
+    #[test]
+    fn inactive_device_is_loaded() {
+        let (device, loaded) = reopen(Vec::new());
+        device.table_load(&[target("linear", "1:3 0")], None).unwrap();
+        assert!(loaded.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn key_goes_inline_without_keyring() {
+        let dm = DeviceMapper::with_backend(Box::new(StubBackend { table: Vec::new(), loaded: Arc::default() }));
+        let (key, kernel_key) = dm.crypt_key("app-manager:a0", &Key::Raw(vec![0xab; 4])).unwrap();
+        assert!(kernel_key.is_none());
+        assert_eq!(key.to_string(), "abababab");
+    }
 }
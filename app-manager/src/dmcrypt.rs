@@ -1,10 +1,10 @@
 use std::{fmt::{Debug, Display}, path::PathBuf};
 
 use devicemapper::{DevId, DeviceInfo, DmError, DmFlags, DmOptions, DM};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::dm::{DeviceHandle, DeviceHandleWrapper, DeviceMapperError};
+use crate::{dm::{DeviceHandle, DeviceHandleWrapper, DeviceMapperError}, dmintegrity::Integrity, keys::wipe};
 
 #[derive(Error, Debug)]
 pub enum DmCryptError {
@@ -12,10 +12,30 @@ pub enum DmCryptError {
     PathConversion(PathBuf),
 
     #[error("Device mapper error")]
-    DeviceMapperError(#[from] DeviceMapperError)
+    DeviceMapperError(#[from] DeviceMapperError),
+
+    #[error("Gcm block mode goes together with Aead integrity")]
+    AeadModeMismatch(),
+
+    #[error("Gcm block mode is only available with the Aes cipher, not {0:?}")]
+    UnsupportedAeadCipher(Cipher),
+
+    #[error("Essiv IVs need the Cbc block mode, not {0:?}")]
+    EssivModeMismatch(BlockMode),
+
+    #[error("Invalid dm-crypt option `{0}`")]
+    InvalidOption(String),
+
+    #[error("Parameters need a {0} byte key, got {1}")]
+    KeySizeMismatch(usize, usize)
 }
 
-#[derive(Deserialize, Debug)]
+// All ciphers are used with 256 bit keys
+const CIPHER_KEY_SIZE: usize = 32;
+
+const DEFAULT_SECTOR_SIZE: u32 = 512;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Cipher {
     Aes,
     Twofish,
@@ -32,7 +52,7 @@ impl Display for Cipher {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum HashAlgo {
     Sha256
 }
@@ -45,7 +65,7 @@ impl Display for HashAlgo {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum IvMode {
     Plain,
     Plain64,
@@ -62,17 +82,19 @@ impl Display for IvMode {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum BlockMode {
     Cbc,
-    Xts
+    Xts,
+    Gcm
 }
 
 impl Display for BlockMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             BlockMode::Cbc => write!(f, "cbc"),
-            BlockMode::Xts => write!(f, "xts")
+            BlockMode::Xts => write!(f, "xts"),
+            BlockMode::Gcm => write!(f, "gcm")
         }
     }
 }
@@ -103,6 +125,28 @@ pub enum Key {
     }
 }
 
+// Don't leave key material behind in freed memory
+impl Drop for Key {
+    fn drop(&mut self) {
+        match self {
+            Key::Raw(v) => wipe(v),
+            Key::Hex(h) => wipe(unsafe { h.as_bytes_mut() }),
+            Key::Keyring { .. } => {}
+        }
+    }
+}
+
+impl Key {
+    /// Size of the key material in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Key::Raw(v) => v.len(),
+            Key::Hex(h) => h.len() / 2,
+            Key::Keyring { key_size, .. } => *key_size
+        }
+    }
+}
+
 impl Display for Key {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -114,13 +158,58 @@ impl Display for Key {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CryptoParams {
     pub cipher: Cipher,
     pub iv_mode: IvMode,
     pub block_mode: BlockMode,
     pub iv_offset: usize,
-    pub additional_options: Option<Vec<String>>
+    pub additional_options: Option<Vec<String>>,
+
+    /// Authenticate sectors through dm-integrity, IVs are random when set
+    pub integrity: Option<Integrity>
+}
+
+impl CryptoParams {
+    /// Rejects combinations dm-crypt would refuse or silently weaken
+    pub fn validate(&self) -> Result<(), DmCryptError> {
+        let aead = matches!(self.integrity, Some(Integrity::Aead));
+        if aead != matches!(self.block_mode, BlockMode::Gcm) {
+            return Err(DmCryptError::AeadModeMismatch());
+        }
+
+        if aead && !matches!(self.cipher, Cipher::Aes) {
+            return Err(DmCryptError::UnsupportedAeadCipher(self.cipher.clone()));
+        }
+
+        if matches!(self.iv_mode, IvMode::Essiv(_)) && !matches!(self.block_mode, BlockMode::Cbc) {
+            return Err(DmCryptError::EssivModeMismatch(self.block_mode.clone()));
+        }
+
+        for option in self.additional_options.iter().flatten() {
+            if option.is_empty() || option.contains(char::is_whitespace) {
+                return Err(DmCryptError::InvalidOption(option.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encryption unit of the mapping, set through the `sector_size` option
+    pub fn sector_size(&self) -> u32 {
+        self.additional_options.iter().flatten()
+            .find_map(|option| option.strip_prefix("sector_size:")?.parse().ok())
+            .unwrap_or(DEFAULT_SECTOR_SIZE)
+    }
+
+    /// Bytes of key material the mapping takes, xts splits the key in a data
+    /// and a tweak half so it needs twice the cipher key
+    pub fn key_size(&self) -> usize {
+        match self.block_mode {
+            BlockMode::Xts => 2 * CIPHER_KEY_SIZE,
+            BlockMode::Cbc | BlockMode::Gcm => CIPHER_KEY_SIZE
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -135,18 +224,29 @@ pub struct CryptDevice(pub DeviceHandle);
 
 impl CryptDevice {
     pub fn load(&self, entry: DmCryptTable, devpath: &PathBuf, key: &Key, options: Option<DmOptions>) -> Result<(), DmCryptError> {
-        let mut params = format!("{}-{}-{} {} {} {} {}",
-            entry.params.cipher,
-            entry.params.block_mode,
-            entry.params.iv_mode,
+        entry.params.validate()?;
+        if key.size() != entry.params.key_size() {
+            return Err(DmCryptError::KeySizeMismatch(entry.params.key_size(), key.size()));
+        }
+
+        let cipher = match entry.params.integrity {
+            Some(Integrity::Aead) => format!("capi:{}({})-random", entry.params.block_mode, entry.params.cipher),
+            _ => format!("{}-{}-{}", entry.params.cipher, entry.params.block_mode, entry.params.iv_mode)
+        };
+
+        let mut params = format!("{} {} {} {} {}",
+            cipher,
             key,
             entry.params.iv_offset,
             devpath.to_str().ok_or(DmCryptError::PathConversion(devpath.clone()))?,
             entry.offset
         );
 
-        if let Some(opts) = &entry.params.additional_options {
-            params.push_str(format!("{} {}", opts.len(), opts.join(" ")).as_str());
+        let mut opts = entry.params.additional_options.clone().unwrap_or_default();
+        opts.extend(entry.params.integrity.as_ref().and_then(Integrity::crypt_option));
+
+        if !opts.is_empty() {
+            params.push_str(format!(" {} {}", opts.len(), opts.join(" ")).as_str());
         }
 
         let table = vec![(
@@ -167,3 +267,79 @@ impl DeviceHandleWrapper for CryptDevice {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(block_mode: BlockMode, iv_mode: IvMode, integrity: Option<Integrity>) -> CryptoParams {
+        CryptoParams {
+            cipher: Cipher::Aes,
+            iv_mode,
+            block_mode,
+            iv_offset: 0,
+            additional_options: None,
+            integrity
+        }
+    }
+
+    #[test]
+    fn valid_combinations() {
+        assert!(params(BlockMode::Cbc, IvMode::Plain, None).validate().is_ok());
+        assert!(params(BlockMode::Xts, IvMode::Plain64, None).validate().is_ok());
+        assert!(params(BlockMode::Cbc, IvMode::Essiv(HashAlgo::Sha256), None).validate().is_ok());
+        assert!(params(BlockMode::Gcm, IvMode::Plain64, Some(Integrity::Aead)).validate().is_ok());
+        assert!(params(BlockMode::Xts, IvMode::Plain64, Some(Integrity::Hmac(HashAlgo::Sha256))).validate().is_ok());
+    }
+
+    #[test]
+    fn gcm_goes_with_aead() {
+        assert!(matches!(params(BlockMode::Gcm, IvMode::Plain64, None).validate(), Err(DmCryptError::AeadModeMismatch())));
+        assert!(matches!(params(BlockMode::Xts, IvMode::Plain64, Some(Integrity::Aead)).validate(), Err(DmCryptError::AeadModeMismatch())));
+
+        let mut twofish = params(BlockMode::Gcm, IvMode::Plain64, Some(Integrity::Aead));
+        twofish.cipher = Cipher::Twofish;
+        assert!(matches!(twofish.validate(), Err(DmCryptError::UnsupportedAeadCipher(Cipher::Twofish))));
+    }
+
+    #[test]
+    fn essiv_needs_cbc() {
+        let essiv = params(BlockMode::Xts, IvMode::Essiv(HashAlgo::Sha256), None);
+        assert!(matches!(essiv.validate(), Err(DmCryptError::EssivModeMismatch(BlockMode::Xts))));
+    }
+
+    #[test]
+    fn options_are_single_words() {
+        let mut with_options = params(BlockMode::Xts, IvMode::Plain64, None);
+        with_options.additional_options = Some(vec!["allow_discards".to_owned(), "sector_size:4096".to_owned()]);
+        assert!(with_options.validate().is_ok());
+        assert_eq!(with_options.sector_size(), 4096);
+
+        for option in ["", "sector_size:4096 allow_discards"] {
+            with_options.additional_options = Some(vec![option.to_owned()]);
+            assert!(matches!(with_options.validate(), Err(DmCryptError::InvalidOption(o)) if o == option));
+        }
+    }
+
+    #[test]
+    fn default_sector_size() {
+        assert_eq!(params(BlockMode::Xts, IvMode::Plain64, None).sector_size(), DEFAULT_SECTOR_SIZE);
+    }
+
+    #[test]
+    fn xts_takes_a_double_key() {
+        assert_eq!(params(BlockMode::Xts, IvMode::Plain64, None).key_size(), 64);
+        assert_eq!(params(BlockMode::Cbc, IvMode::Plain, None).key_size(), 32);
+        assert_eq!(params(BlockMode::Gcm, IvMode::Plain64, Some(Integrity::Aead)).key_size(), 32);
+    }
+
+    #[test]
+    fn key_formats() {
+        assert_eq!(Key::Raw(vec![0xab; 4]).to_string(), "abababab");
+        assert_eq!(Key::Hex("abababab".to_owned()).size(), 4);
+
+        let keyring = Key::Keyring { key_size: 64, key_type: KeyType::Logon, key_desc: "app-manager:a0".to_owned() };
+        assert_eq!(keyring.to_string(), ":64:logon:app-manager:a0");
+        assert_eq!(keyring.size(), 64);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, os::fd::AsRawFd, path::PathBuf};
+
+use devicemapper::DmOptions;
+use nix::libc::{posix_fadvise, POSIX_FADV_DONTNEED};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{dm::{DeviceHandle, DeviceHandleWrapper, DeviceMapperError}, dmcrypt::{HashAlgo, Key}, keys::wipe};
+
+// Superblock of the kernel target, see drivers/md/dm-integrity.c
+const SB_MAGIC: &[u8; 8] = b"integrt\0";
+const SB_SIZE: usize = 4096;
+const SB_PROVIDED_DATA_SECTORS: usize = 16;
+
+// Authentication tag and random IV of aes-gcm
+const AEAD_TAG_SIZE: usize = 16 + 12;
+
+#[derive(Error, Debug)]
+pub enum IntegrityDeviceError {
+    #[error("Cannot convert path `{0:?}` to string")]
+    PathConversion(PathBuf),
+
+    #[error("Device mapper error")]
+    DeviceMapperError(#[from] DeviceMapperError),
+
+    #[error("Cannot read integrity superblock of {0:?}")]
+    SuperblockReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot clear integrity superblock of {0:?}")]
+    SuperblockWriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Unexpected status `{0}` of the integrity target")]
+    InvalidStatus(String)
+}
+
+/// How sectors are authenticated
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Integrity {
+    /// dm-crypt authenticated encryption (`Gcm` block mode) with random IVs,
+    /// dm-integrity only stores the tags
+    Aead,
+
+    /// dm-integrity keeps a keyed hash of every sector below the cipher
+    Hmac(HashAlgo)
+}
+
+impl Integrity {
+    pub fn tag_size(&self) -> usize {
+        match self {
+            Integrity::Aead => AEAD_TAG_SIZE,
+            Integrity::Hmac(HashAlgo::Sha256) => 32
+        }
+    }
+
+    /// Options for the dm-crypt table on top
+    pub fn crypt_option(&self) -> Option<String> {
+        match self {
+            Integrity::Aead => Some(format!("integrity:{}:aead", self.tag_size())),
+            Integrity::Hmac(_) => None
+        }
+    }
+}
+
+/// Sectors the formatted device provides, none if there is no superblock yet.
+/// `offset` is where the superblock starts, in sectors.
+pub fn provided_sectors(devpath: &PathBuf, offset: u64) -> Result<Option<u64>, IntegrityDeviceError> {
+    let err = |e| IntegrityDeviceError::SuperblockReadError(devpath.clone(), e);
+    let mut dev = File::open(devpath).map_err(err)?;
+    let mut sb = [0u8; SB_SIZE];
+
+    // The kernel writes the superblock past the page cache
+    unsafe { posix_fadvise(dev.as_raw_fd(), (offset * 512) as i64, SB_SIZE as i64, POSIX_FADV_DONTNEED) };
+    dev.seek(SeekFrom::Start(offset * 512)).map_err(err)?;
+    dev.read_exact(&mut sb).map_err(err)?;
+
+    if &sb[..SB_MAGIC.len()] != SB_MAGIC {
+        return Ok(None);
+    }
+
+    let sectors = &sb[SB_PROVIDED_DATA_SECTORS..SB_PROVIDED_DATA_SECTORS + 8];
+    Ok(Some(u64::from_le_bytes(sectors.try_into().unwrap())))
+}
+
+/// The kernel formats a device with a zeroed superblock when the table is loaded
+pub fn clear_superblock(devpath: &PathBuf, offset: u64) -> Result<(), IntegrityDeviceError> {
+    let err = |e| IntegrityDeviceError::SuperblockWriteError(devpath.clone(), e);
+    let mut dev = File::options().write(true).open(devpath).map_err(err)?;
+    dev.seek(SeekFrom::Start(offset * 512)).map_err(err)?;
+
+    dev.write_all(&[0u8; SB_SIZE]).map_err(err)?;
+    dev.sync_all().map_err(err)
+}
+
+pub struct IntegrityDevice(pub DeviceHandle);
+
+impl IntegrityDevice {
+    /// `key` is only used by the hmac mode, `offset` skips that many sectors of `devpath`
+    pub fn load(&self, devpath: &PathBuf, offset: u64, len: u64, integrity: &Integrity, key: Option<&Key>, options: Option<DmOptions>) -> Result<(), IntegrityDeviceError> {
+        let mut params = format!("{} {} {} J",
+            devpath.to_str().ok_or(IntegrityDeviceError::PathConversion(devpath.clone()))?,
+            offset,
+            integrity.tag_size()
+        );
+
+        match (integrity, key) {
+            (Integrity::Hmac(algo), Some(key)) => params.push_str(&format!(" 1 internal_hash:hmac({}):{}", algo, key)),
+            _ => params.push_str(" 0")
+        }
+
+        let mut table = vec![(0, len, "integrity".into(), params)];
+        let res = self.0.table_load(&table, options);
+
+        // The hmac key is part of the table
+        wipe(unsafe { table[0].3.as_bytes_mut() });
+        res?;
+
+        Ok(())
+    }
+
+    /// Sectors whose keyed hash didn't match since the device was created,
+    /// always zero in the aead mode where dm-crypt checks the tags
+    pub fn mismatches(&self) -> Result<u64, IntegrityDeviceError> {
+        let status = self.0.status()?;
+        let params = status.first().map(|(_, _, _, params)| params.as_str()).unwrap_or_default();
+
+        params.split_whitespace()
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or(IntegrityDeviceError::InvalidStatus(params.to_owned()))
+    }
+}
+
+impl DeviceHandleWrapper for IntegrityDevice {
+    fn dm_handle(&self) -> &DeviceHandle {
+        &self.0
+    }
+}
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use devicemapper::DmOptions;
+use thiserror::Error;
+
+use crate::dm::{DeviceHandle, DeviceHandleWrapper, DeviceMapperError};
+
+#[derive(Error, Debug)]
+pub enum LinearDeviceError {
+    #[error("Cannot convert path `{0:?}` to string")]
+    PathConversion(PathBuf),
+
+    #[error("Device mapper error")]
+    DeviceMapperError(#[from] DeviceMapperError)
+}
+
+/// Window of `len` sectors into another block device starting at `offset`
+pub struct LinearDevice(pub DeviceHandle);
+
+impl LinearDevice {
+    pub fn load(&self, devpath: &PathBuf, offset: u64, len: u64, options: Option<DmOptions>) -> Result<(), LinearDeviceError> {
+        let params = format!("{} {}",
+            devpath.to_str().ok_or(LinearDeviceError::PathConversion(devpath.clone()))?,
+            offset
+        );
+
+        let table = vec![(0, len, "linear".into(), params)];
+        let _ = self.0.table_load(&table, options)?;
+
+        Ok(())
+    }
+}
+
+impl DeviceHandleWrapper for LinearDevice {
+    fn dm_handle(&self) -> &DeviceHandle {
+        &self.0
+    }
+}
@@ -1,15 +1,196 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use devicemapper::DmOptions;
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use crate::dm::{DeviceHandle, DeviceHandleWrapper};
+use crate::{dm::{DeviceHandle, DeviceHandleWrapper, DeviceMapperError}, utils::random_bytes};
+
+// Format version 1 of the kernel target: sha256 over salt || block, data and
+// hash blocks of one page, digests packed without padding
+pub const BLOCK_SIZE: u64 = 4096;
+const DIGEST_SIZE: usize = 32;
+const HASHES_PER_BLOCK: u64 = BLOCK_SIZE / DIGEST_SIZE as u64;
+const SALT_SIZE: usize = 32;
 
 #[derive(Error, Debug)]
 pub enum VerityDeviceError {
+    #[error("Cannot convert path `{0:?}` to string")]
+    PathConversion(PathBuf),
+
+    #[error("Device mapper error")]
+    DeviceMapperError(#[from] DeviceMapperError),
+
+    #[error("Volume of {0} blocks is too small for a hash tree")]
+    VolumeTooSmall(u64),
+
+    #[error("Cannot read from {0:?}")]
+    ReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write to {0:?}")]
+    WriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot generate salt")]
+    SaltGenerationError(#[source] std::io::Error),
+
+    #[error("Malformed sealed verity parameters")]
+    MalformedParams(#[source] serde_json::Error),
+
+    #[error("Sealed verity parameters don't match the application key")]
+    InvalidSeal()
+}
+
+/// Everything the kernel needs to check the data device, the root hash is
+/// only trusted when unsealed with the application key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerityParams {
+    pub data_blocks: u64,
+    pub hash_start_block: u64,
+    pub salt: Vec<u8>,
+    pub root_hash: Vec<u8>,
+
+    /// Where the image starts on the hash device, set by the user of the
+    /// tree. Kept out of the seal when unset so older params still verify.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_start_block: Option<u64>
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedParams {
+    params: VerityParams,
+    mac: Vec<u8>
+}
+
+impl VerityParams {
+    pub fn seal(&self, key: &[u8]) -> Result<Vec<u8>, VerityDeviceError> {
+        let mac = Self::mac(key)
+            .chain_update(serde_json::to_vec(&self).map_err(VerityDeviceError::MalformedParams)?)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        serde_json::to_vec(&SealedParams { params: self.clone(), mac }).map_err(VerityDeviceError::MalformedParams)
+    }
+
+    pub fn unseal(sealed: &[u8], key: &[u8]) -> Result<Self, VerityDeviceError> {
+        let sealed: SealedParams = serde_json::from_slice(sealed).map_err(VerityDeviceError::MalformedParams)?;
+
+        Self::mac(key)
+            .chain_update(serde_json::to_vec(&sealed.params).map_err(VerityDeviceError::MalformedParams)?)
+            .verify_slice(&sealed.mac)
+            .map_err(|_| VerityDeviceError::InvalidSeal())?;
+
+        Ok(sealed.params)
+    }
+
+    fn mac(key: &[u8]) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size")
+    }
+}
 
+/// Number of hash blocks the tree over `data_blocks` takes
+pub fn hash_blocks(data_blocks: u64) -> u64 {
+    let mut total = 0;
+    let mut level = data_blocks;
+
+    loop {
+        level = level.div_ceil(HASHES_PER_BLOCK);
+        total += level;
+
+        if level <= 1 {
+            break total;
+        }
+    }
+}
+
+/// Builds the hash tree of the first `data_blocks` of `data` and writes it to
+/// `hash` at `hash_start_block`, top level first as the kernel expects
+pub fn format(data: &Path, hash: &Path, data_blocks: u64, hash_start_block: u64) -> Result<VerityParams, VerityDeviceError> {
+    // A single block would hash straight to the root without a tree
+    if data_blocks < 2 {
+        return Err(VerityDeviceError::VolumeTooSmall(data_blocks));
+    }
+
+    let salt = random_bytes(SALT_SIZE).map_err(VerityDeviceError::SaltGenerationError)?;
+
+    info!("Hashing {} blocks of {:?}", data_blocks, data);
+    let err = |e| VerityDeviceError::ReadError(data.to_owned(), e);
+    let mut dev = File::open(data).map_err(err)?;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    let mut digests = Vec::with_capacity(data_blocks as usize * DIGEST_SIZE);
+
+    for _ in 0..data_blocks {
+        dev.read_exact(&mut block).map_err(err)?;
+        digests.extend_from_slice(&hash_block(&salt, &block));
+    }
+
+    let mut levels = Vec::new();
+    loop {
+        let blocks = (digests.len() as u64).div_ceil(BLOCK_SIZE);
+        let mut level = vec![0u8; (blocks * BLOCK_SIZE) as usize];
+        level[..digests.len()].copy_from_slice(&digests);
+
+        digests = level.chunks(BLOCK_SIZE as usize)
+            .flat_map(|block| hash_block(&salt, block))
+            .collect();
+        levels.push(level);
+
+        if blocks == 1 {
+            break;
+        }
+    }
+
+    let err = |e| VerityDeviceError::WriteError(hash.to_owned(), e);
+    let mut dev = File::options().write(true).open(hash).map_err(err)?;
+    dev.seek(SeekFrom::Start(hash_start_block * BLOCK_SIZE)).map_err(err)?;
+
+    for level in levels.iter().rev() {
+        dev.write_all(level).map_err(err)?;
+    }
+    dev.sync_all().map_err(err)?;
+
+    debug!("Hash tree of {} levels written to {:?}, root hash {}", levels.len(), hash, hex::encode(&digests));
+
+    Ok(VerityParams { data_blocks, hash_start_block, salt, root_hash: digests, data_start_block: None })
+}
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(block)
+        .finalize()
+        .into()
 }
 
 pub struct VerityDevice(pub DeviceHandle);
 
 impl VerityDevice {
+    pub fn load(&self, data: &PathBuf, hash: &PathBuf, params: &VerityParams, options: Option<DmOptions>) -> Result<(), VerityDeviceError> {
+        let table_params = format!("1 {} {} {} {} {} {} sha256 {} {}",
+            data.to_str().ok_or(VerityDeviceError::PathConversion(data.clone()))?,
+            hash.to_str().ok_or(VerityDeviceError::PathConversion(hash.clone()))?,
+            BLOCK_SIZE,
+            BLOCK_SIZE,
+            params.data_blocks,
+            params.hash_start_block,
+            hex::encode(&params.root_hash),
+            hex::encode(&params.salt)
+        );
+
+        let table = vec![(
+            0,
+            params.data_blocks * BLOCK_SIZE / 512,
+            "verity".into(),
+            table_params
+        )];
+
+        let _ = self.0.table_load(&table, options)?;
+
+        Ok(())
+    }
 }
 
 impl DeviceHandleWrapper for VerityDevice {
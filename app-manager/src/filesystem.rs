@@ -0,0 +1,347 @@
+use std::{ffi::{c_void, CString, NulError, OsStr}, fs::File, io::{Read, Seek, SeekFrom}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process::{Command, ExitStatus}};
+
+use log::debug;
+use nix::{errno::Errno, libc::{c_char, c_ulong, mount, MS_RDONLY}};
+use protocol::Filesystem;
+use thiserror::Error;
+
+use crate::utils::{self, UtilitiesError};
+
+// Where each filesystem keeps its superblock magic, ext2 and ext3 share the
+// one of ext4 and are mounted by the same driver
+const SIGNATURES: [(Filesystem, u64, &[u8]); 5] = [
+    (Filesystem::Ext4, 1024 + 56, &[0x53, 0xEF]),
+    (Filesystem::Xfs, 0, b"XFSB"),
+    (Filesystem::Btrfs, 0x10040, b"_BHRfS_M"),
+    (Filesystem::Erofs, 1024, &[0xE2, 0xE1, 0xF5, 0xE0]),
+    (Filesystem::Squashfs, 0, b"hsqs")
+];
+
+const XFS_LABEL_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum FilesystemError {
+    #[error("Error running {0}")]
+    SpawnError(&'static str, #[source] std::io::Error),
+
+    #[error("{0} failed on {1:?} with {2}")]
+    ToolError(&'static str, PathBuf, ExitStatus),
+
+    #[error("Cannot probe filesystem on {0:?}")]
+    ProbeError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot read filesystem size of {0:?}")]
+    SizeReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid ext4 superblock on {0:?}")]
+    InvalidSuperblock(PathBuf),
+
+    #[error("{0} is read-only and can only hold an image")]
+    ReadOnly(Filesystem),
+
+    #[error("Cannot mount {0:?} as {1}")]
+    MountError(PathBuf, Filesystem, #[source] Errno),
+
+    #[error("CString conversion error in {0:?}")]
+    CStringConvError(PathBuf, #[source] NulError)
+}
+
+/// Creates and mounts filesystems, with the mkfs tools and the kernel or a stand-in
+pub trait Filesystems: Send + Sync {
+    fn format(&self, fs: Filesystem, devpath: &Path, label: &str) -> Result<(), FilesystemError>;
+    fn build_image(&self, fs: Filesystem, devpath: &Path, source: &Path) -> Result<(), FilesystemError>;
+    fn grow_unmounted(&self, fs: Filesystem, devpath: &Path) -> Result<(), FilesystemError>;
+    fn grow_mounted(&self, fs: Filesystem, devpath: &Path, target: &Path) -> Result<(), FilesystemError>;
+    fn mount(&self, fs: Filesystem, devpath: &Path, target: &Path, readonly: bool, options: Option<&str>) -> Result<(), FilesystemError>;
+    fn mount_overlay(&self, lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<(), UtilitiesError>;
+    fn unmount(&self, target: &Path) -> Result<(), UtilitiesError>;
+}
+
+pub struct KernelFilesystems {}
+
+impl Filesystems for KernelFilesystems {
+    fn format(&self, fs: Filesystem, devpath: &Path, label: &str) -> Result<(), FilesystemError> {
+        format(fs, devpath, label)
+    }
+
+    fn build_image(&self, fs: Filesystem, devpath: &Path, source: &Path) -> Result<(), FilesystemError> {
+        build_image(fs, devpath, source)
+    }
+
+    fn grow_unmounted(&self, fs: Filesystem, devpath: &Path) -> Result<(), FilesystemError> {
+        grow_unmounted(fs, devpath)
+    }
+
+    fn grow_mounted(&self, fs: Filesystem, devpath: &Path, target: &Path) -> Result<(), FilesystemError> {
+        grow_mounted(fs, devpath, target)
+    }
+
+    fn mount(&self, fs: Filesystem, devpath: &Path, target: &Path, readonly: bool, options: Option<&str>) -> Result<(), FilesystemError> {
+        mount_fs(fs, devpath, target, readonly, options)
+    }
+
+    fn mount_overlay(&self, lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<(), UtilitiesError> {
+        utils::mount_overlay(lower, upper, work, target)
+    }
+
+    fn unmount(&self, target: &Path) -> Result<(), UtilitiesError> {
+        utils::unmount(target)
+    }
+}
+
+/// Whether the filesystem can be written after it was created
+pub fn is_writable(fs: Filesystem) -> bool {
+    !matches!(fs, Filesystem::Erofs | Filesystem::Squashfs)
+}
+
+/// Offset and value of the superblock magic `probe` looks for
+pub fn signature(fs: Filesystem) -> (u64, &'static [u8]) {
+    SIGNATURES.iter()
+        .find(|(sig, _, _)| *sig == fs)
+        .map(|(_, offset, magic)| (*offset, *magic))
+        .expect("every filesystem has a signature")
+}
+
+/// Filesystem the device holds, going by the superblock magic
+pub fn probe(devpath: &Path) -> Result<Option<Filesystem>, FilesystemError> {
+    let err = |e| FilesystemError::ProbeError(devpath.to_owned(), e);
+    let mut dev = File::open(devpath).map_err(err)?;
+    let size = dev.seek(SeekFrom::End(0)).map_err(err)?;
+
+    for (fs, offset, magic) in SIGNATURES {
+        if offset + magic.len() as u64 > size {
+            continue;
+        }
+
+        let mut buf = vec![0u8; magic.len()];
+        dev.seek(SeekFrom::Start(offset)).map_err(err)?;
+        dev.read_exact(&mut buf).map_err(err)?;
+
+        if buf == magic {
+            return Ok(Some(fs));
+        }
+    }
+
+    Ok(None)
+}
+
+fn run(tool: &'static str, args: &[&OsStr]) -> Result<ExitStatus, FilesystemError> {
+    let res = Command::new(format!("/bin/{}", tool)).args(args)
+        .status()
+        .map_err(|e| FilesystemError::SpawnError(tool, e))?;
+    debug!("{} exited with code: {}", tool, res);
+
+    Ok(res)
+}
+
+fn run_checked(tool: &'static str, args: &[&OsStr], devpath: &Path) -> Result<(), FilesystemError> {
+    let res = run(tool, args)?;
+
+    if !res.success() {
+        return Err(FilesystemError::ToolError(tool, devpath.to_owned(), res));
+    }
+
+    Ok(())
+}
+
+/// Creates an empty filesystem, erofs and squashfs are built from a
+/// directory with `build_image` instead
+pub fn format(fs: Filesystem, devpath: &Path, label: &str) -> Result<(), FilesystemError> {
+    let dev = devpath.as_os_str();
+
+    match fs {
+        Filesystem::Ext4 => run_checked("mkfs.ext4", &["-q".as_ref(), "-F".as_ref(), "-L".as_ref(), label.as_ref(), dev], devpath),
+        Filesystem::Xfs => {
+            let label: String = label.chars().take(XFS_LABEL_LEN).collect();
+            run_checked("mkfs.xfs", &["-q".as_ref(), "-f".as_ref(), "-L".as_ref(), label.as_ref(), dev], devpath)
+        },
+        Filesystem::Btrfs => run_checked("mkfs.btrfs", &["-q".as_ref(), "-f".as_ref(), "-L".as_ref(), label.as_ref(), dev], devpath),
+        Filesystem::Erofs | Filesystem::Squashfs => Err(FilesystemError::ReadOnly(fs))
+    }
+}
+
+/// Writes a read-only filesystem holding the contents of `source`
+pub fn build_image(fs: Filesystem, devpath: &Path, source: &Path) -> Result<(), FilesystemError> {
+    let (dev, src) = (devpath.as_os_str(), source.as_os_str());
+
+    match fs {
+        Filesystem::Erofs => run_checked("mkfs.erofs", &["--quiet".as_ref(), dev, src], devpath),
+        Filesystem::Squashfs => run_checked("mksquashfs", &[src, dev, "-noappend".as_ref(), "-quiet".as_ref()], devpath),
+        _ => unreachable!("writable filesystems are formatted and filled while mounted")
+    }
+}
+
+const EXT4_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_SUPERBLOCK_SIZE: usize = 1024;
+
+// 64KiB blocks, the largest ext4 supports
+const EXT4_MAX_LOG_BLOCK_SIZE: u32 = 6;
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+
+fn le32(superblock: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap())
+}
+
+// Superblock fields are little endian, block size is 1024 << s_log_block_size
+// and the block count has a high half on 64-bit filesystems
+fn ext4_superblock_size(superblock: &[u8; EXT4_SUPERBLOCK_SIZE]) -> Option<u64> {
+    let log_block_size = le32(superblock, 0x18);
+    if log_block_size > EXT4_MAX_LOG_BLOCK_SIZE {
+        return None;
+    }
+    let block_size = 1024u64.checked_shl(log_block_size)?;
+
+    let mut blocks = le32(superblock, 0x04) as u64;
+    if le32(superblock, 0x60) & EXT4_FEATURE_INCOMPAT_64BIT != 0 {
+        blocks |= (le32(superblock, 0x150) as u64) << 32;
+    }
+
+    blocks.checked_mul(block_size)
+}
+
+fn ext4_size(devpath: &Path) -> Result<(u64, u64), FilesystemError> {
+    let err = |e| FilesystemError::SizeReadError(devpath.to_owned(), e);
+    let mut dev = File::open(devpath).map_err(err)?;
+    let mut superblock = [0u8; EXT4_SUPERBLOCK_SIZE];
+
+    dev.seek(SeekFrom::Start(EXT4_SUPERBLOCK_OFFSET)).map_err(err)?;
+    dev.read_exact(&mut superblock).map_err(err)?;
+
+    let fs_size = ext4_superblock_size(&superblock)
+        .ok_or(FilesystemError::InvalidSuperblock(devpath.to_owned()))?;
+    let device_size = dev.seek(SeekFrom::End(0)).map_err(err)?;
+
+    Ok((fs_size, device_size))
+}
+
+/// Grows the filesystem to fill the device after the host resized the disk,
+/// ext4 is grown before it is mounted
+pub fn grow_unmounted(fs: Filesystem, devpath: &Path) -> Result<(), FilesystemError> {
+    if fs != Filesystem::Ext4 {
+        return Ok(());
+    }
+
+    let (fs_size, device_size) = ext4_size(devpath)?;
+    if fs_size >= device_size {
+        return Ok(());
+    }
+
+    debug!("Growing filesystem on {:?} from {} to {} bytes", devpath, fs_size, device_size);
+
+    // resize2fs refuses to touch a filesystem that wasn't checked
+    let res = run("e2fsck", &["-f".as_ref(), "-p".as_ref(), devpath.as_os_str()])?;
+
+    // 1 means errors were fixed
+    if !matches!(res.code(), Some(0) | Some(1)) {
+        return Err(FilesystemError::ToolError("e2fsck", devpath.to_owned(), res));
+    }
+
+    run_checked("resize2fs", &[devpath.as_os_str()], devpath)
+}
+
+/// xfs and btrfs only grow while mounted, both do nothing if the device didn't change
+pub fn grow_mounted(fs: Filesystem, devpath: &Path, target: &Path) -> Result<(), FilesystemError> {
+    match fs {
+        Filesystem::Xfs => run_checked("xfs_growfs", &["-d".as_ref(), target.as_os_str()], devpath),
+        Filesystem::Btrfs => run_checked("btrfs", &["filesystem".as_ref(), "resize".as_ref(), "max".as_ref(), target.as_os_str()], devpath),
+        _ => Ok(())
+    }
+}
+
+/// Mounts with the filesystem specific `options`, read-only filesystems always are
+pub fn mount_fs(fs: Filesystem, devpath: &Path, target: &Path, readonly: bool, options: Option<&str>) -> Result<(), FilesystemError> {
+    let src = CString::new(devpath.as_os_str().as_bytes())
+        .map_err(|e| FilesystemError::CStringConvError(devpath.to_owned(), e))?;
+    let dst = CString::new(target.as_os_str().as_bytes())
+        .map_err(|e| FilesystemError::CStringConvError(target.to_owned(), e))?;
+    let ty = CString::new(fs.to_string()).unwrap();
+    let data = options.map(CString::new).transpose()
+        .map_err(|e| FilesystemError::CStringConvError(target.to_owned(), e))?;
+
+    let flags: c_ulong = match readonly || !is_writable(fs) {
+        true => MS_RDONLY,
+        false => 0
+    };
+
+    let ret = unsafe {
+        mount(
+            src.as_ptr() as *const c_char,
+            dst.as_ptr() as *const c_char,
+            ty.as_ptr() as *const c_char,
+            flags,
+            data.as_ref().map_or(std::ptr::null(), |d| d.as_ptr() as *const c_void)
+        )
+    };
+
+    if ret != 0 {
+        Err(FilesystemError::MountError(devpath.to_owned(), fs, Errno::last()))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::device;
+
+    fn superblock(log_block_size: u32, blocks_lo: u32, blocks_hi: u32, incompat: u32) -> [u8; EXT4_SUPERBLOCK_SIZE] {
+        let mut sb = [0u8; EXT4_SUPERBLOCK_SIZE];
+        sb[0x04..0x08].copy_from_slice(&blocks_lo.to_le_bytes());
+        sb[0x18..0x1c].copy_from_slice(&log_block_size.to_le_bytes());
+        sb[0x60..0x64].copy_from_slice(&incompat.to_le_bytes());
+        sb[0x150..0x154].copy_from_slice(&blocks_hi.to_le_bytes());
+        sb
+    }
+
+    #[test]
+    fn probe_finds_every_signature() {
+        for (fs, offset, magic) in SIGNATURES {
+            let file = device(1 << 20, offset, magic);
+            assert_eq!(probe(file.path()).unwrap(), Some(fs));
+        }
+    }
+
+    #[test]
+    fn probe_blank_and_small_devices() {
+        let blank = device(1 << 20, 0, &[]);
+        assert_eq!(probe(blank.path()).unwrap(), None);
+
+        // Too small for the btrfs superblock, the others are still checked
+        let small = device(4096, 1024, &[0xE2, 0xE1, 0xF5, 0xE0]);
+        assert_eq!(probe(small.path()).unwrap(), Some(Filesystem::Erofs));
+
+        let missing = std::env::temp_dir().join(format!("filesystem-{}-missing", std::process::id()));
+        assert!(matches!(probe(&missing), Err(FilesystemError::ProbeError(..))));
+    }
+
+    #[test]
+    fn ext4_size_from_block_size_and_count() {
+        assert_eq!(ext4_superblock_size(&superblock(0, 1000, 0, 0)), Some(1024 * 1000));
+        assert_eq!(ext4_superblock_size(&superblock(2, 1000, 0, 0)), Some(4096 * 1000));
+        assert_eq!(ext4_superblock_size(&superblock(EXT4_MAX_LOG_BLOCK_SIZE, 1, 0, 0)), Some(64 * 1024));
+    }
+
+    #[test]
+    fn ext4_high_block_count_needs_64bit() {
+        assert_eq!(ext4_superblock_size(&superblock(2, 0, 1, EXT4_FEATURE_INCOMPAT_64BIT)), Some(4096 << 32));
+        assert_eq!(ext4_superblock_size(&superblock(2, 0, 1, 0)), Some(0));
+    }
+
+    #[test]
+    fn ext4_invalid_superblock() {
+        assert_eq!(ext4_superblock_size(&superblock(EXT4_MAX_LOG_BLOCK_SIZE + 1, 1000, 0, 0)), None);
+        assert_eq!(ext4_superblock_size(&superblock(31, 1000, 0, 0)), None);
+        assert_eq!(ext4_superblock_size(&superblock(6, u32::MAX, u32::MAX, EXT4_FEATURE_INCOMPAT_64BIT)), None);
+    }
+
+    #[test]
+    fn ext4_size_reads_the_device() {
+        let sb = superblock(2, 256, 0, 0);
+        let file = device(2 << 20, EXT4_SUPERBLOCK_OFFSET, &sb);
+        assert_eq!(ext4_size(file.path()).unwrap(), (1 << 20, 2 << 20));
+
+        let file = device(2 << 20, EXT4_SUPERBLOCK_OFFSET, &superblock(9, 256, 0, 0));
+        assert!(matches!(ext4_size(file.path()), Err(FilesystemError::InvalidSuperblock(_))));
+    }
+}
@@ -0,0 +1,502 @@
+use std::{fs::File, io::{Read, Seek, SeekFrom, Write}, path::PathBuf};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{dmcrypt::{CryptoParams, Key}, keys::wipe, utils::random_bytes};
+
+/// Sectors reserved for the header at the start of every partition, data
+/// mappings start right after
+pub const HEADER_SECTORS: u64 = 2048;
+
+// Two copies so a torn write leaves the previous one intact, each is
+// [magic][version u16][seqid u64][length u32][sha256 of json][json]. The
+// digest only catches torn writes, the crypto parameters are authenticated
+// by every key slot with a key derived from its data key.
+const MAGIC: &[u8; 8] = b"APPMHDR\0";
+const VERSION: u16 = 2;
+const COPY_SIZE: usize = 16 * 1024;
+const COPY_OFFSETS: [u64; 2] = [0, COPY_SIZE as u64];
+const FIXED_SIZE: usize = MAGIC.len() + 2 + 8 + 4 + 32;
+
+const MAC_KEY_SIZE: usize = 32;
+const SLOT_SALT_SIZE: usize = 32;
+const SLOT_INFO: &[u8] = b"app-manager key slot";
+const KEY_CHECK_INFO: &[u8] = b"app-manager key check";
+const PARAMS_MAC_INFO: &[u8] = b"app-manager header params";
+
+#[derive(Error, Debug)]
+pub enum HeaderError {
+    #[error("Cannot read header from {0:?}")]
+    ReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write header to {0:?}")]
+    WriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("No valid header copy on {0:?}")]
+    Corrupted(PathBuf),
+
+    #[error("Header of {0} bytes doesn't fit in the header area")]
+    TooLarge(usize),
+
+    #[error("Cannot (de)serialize header")]
+    SerializationError(#[from] serde_json::Error),
+
+    #[error("No key slot for generation {0}")]
+    NoKeySlot(u32),
+
+    #[error("Key slot of generation {0} doesn't match the sealing key")]
+    WrongKey(u32),
+
+    #[error("Unwrapped key of generation {0} fails the key check")]
+    KeyCheckFailed(u32),
+
+    #[error("Crypto parameters were changed after the key slot of generation {0} was sealed")]
+    ParamsMismatch(u32),
+
+    #[error("Cannot generate key material")]
+    KeyGenerationError(#[source] std::io::Error)
+}
+
+/// Data key wrapped with a sealing key of one generation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeySlot {
+    pub generation: u32,
+    salt: Vec<u8>,
+    wrapped_key: Vec<u8>,
+    mac: Vec<u8>,
+    key_check: Vec<u8>,
+    params_mac: Vec<u8>
+}
+
+/// Crypto metadata of a partition, the parameters recorded here take
+/// precedence over the configuration once the partition is provisioned
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PartitionHeader {
+    pub crypto: CryptoParams,
+    key_slots: Vec<KeySlot>,
+
+    #[serde(skip)]
+    seqid: u64
+}
+
+impl PartitionHeader {
+    pub fn new(crypto: CryptoParams) -> Self {
+        Self { crypto, key_slots: Vec::new(), seqid: 0 }
+    }
+
+    /// None if the partition was never given a header
+    pub fn read(devpath: &PathBuf) -> Result<Option<Self>, HeaderError> {
+        let err = |e| HeaderError::ReadError(devpath.clone(), e);
+        let mut dev = File::open(devpath).map_err(err)?;
+        let mut found = false;
+        let mut best: Option<Self> = None;
+
+        for offset in COPY_OFFSETS {
+            let mut copy = vec![0u8; COPY_SIZE];
+            dev.seek(SeekFrom::Start(offset)).map_err(err)?;
+            dev.read_exact(&mut copy).map_err(err)?;
+
+            if &copy[..MAGIC.len()] != MAGIC {
+                continue;
+            }
+            found = true;
+
+            match Self::parse(&copy) {
+                Some(header) if best.as_ref().is_none_or(|b| header.seqid > b.seqid) => best = Some(header),
+                Some(_) => {},
+                None => warn!("Invalid header copy at offset {} of {:?}", offset, devpath)
+            }
+        }
+
+        match (found, best) {
+            (_, Some(header)) => Ok(Some(header)),
+            (true, None) => Err(HeaderError::Corrupted(devpath.clone())),
+            (false, None) => Ok(None)
+        }
+    }
+
+    fn parse(copy: &[u8]) -> Option<Self> {
+        let mut pos = MAGIC.len();
+        let mut field = |len: usize| {
+            let v = &copy[pos..pos + len];
+            pos += len;
+            v
+        };
+
+        let version = u16::from_le_bytes(field(2).try_into().unwrap());
+        let seqid = u64::from_le_bytes(field(8).try_into().unwrap());
+        let len = u32::from_le_bytes(field(4).try_into().unwrap()) as usize;
+        let digest = field(32).to_vec();
+
+        if version != VERSION || len > COPY_SIZE - FIXED_SIZE {
+            return None;
+        }
+
+        let json = &copy[FIXED_SIZE..FIXED_SIZE + len];
+        if Sha256::digest(json).as_slice() != digest {
+            return None;
+        }
+
+        let mut header: Self = serde_json::from_slice(json).ok()?;
+        header.seqid = seqid;
+        Some(header)
+    }
+
+    /// Writes both copies under a new sequence number
+    pub fn write(&mut self, devpath: &PathBuf) -> Result<(), HeaderError> {
+        let json = serde_json::to_vec(&self)?;
+        if json.len() > COPY_SIZE - FIXED_SIZE {
+            return Err(HeaderError::TooLarge(json.len()));
+        }
+
+        let seqid = self.seqid + 1;
+        let mut copy = Vec::with_capacity(COPY_SIZE);
+        copy.extend_from_slice(MAGIC);
+        copy.extend_from_slice(&VERSION.to_le_bytes());
+        copy.extend_from_slice(&seqid.to_le_bytes());
+        copy.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        copy.extend_from_slice(&Sha256::digest(&json));
+        copy.extend_from_slice(&json);
+        copy.resize(COPY_SIZE, 0);
+
+        let err = |e| HeaderError::WriteError(devpath.clone(), e);
+        let mut dev = File::options().write(true).open(devpath).map_err(err)?;
+
+        // One copy at a time, a crash leaves the other readable
+        for offset in COPY_OFFSETS {
+            dev.seek(SeekFrom::Start(offset)).map_err(err)?;
+            dev.write_all(&copy).map_err(err)?;
+            dev.sync_all().map_err(err)?;
+        }
+
+        debug!("Header {} written to {:?}", seqid, devpath);
+        self.seqid = seqid;
+        Ok(())
+    }
+
+    /// Generates a data key of the size the parameters need and wraps it with
+    /// `kek`, a slot of the same generation is replaced
+    pub fn add_key_slot(&mut self, generation: u32, kek: &[u8]) -> Result<Key, HeaderError> {
+        let mut data_key = random_bytes(self.crypto.key_size()).map_err(HeaderError::KeyGenerationError)?;
+        let slot = self.wrap(generation, &data_key, kek)?;
+
+        self.key_slots.retain(|s| s.generation != generation);
+        self.key_slots.push(slot);
+
+        let key = Key::Raw(data_key.clone());
+        wipe(&mut data_key);
+        Ok(key)
+    }
+
+    /// Wraps the data key of `generation` with `new_kek` as well, both
+    /// sealing keys unlock it until one of them is dropped
+    pub fn rewrap_key_slot(&mut self, generation: u32, kek: &[u8], new_kek: &[u8]) -> Result<(), HeaderError> {
+        let slot = match &self.unlock(generation, kek)? {
+            Key::Raw(_) if kek == new_kek => return Ok(()),
+            Key::Raw(data_key) => self.wrap(generation, data_key, new_kek)?,
+            _ => unreachable!("data keys are always raw")
+        };
+
+        self.key_slots.retain(|s| s.generation != generation || Self::open_slot(s, new_kek).is_none());
+        self.key_slots.push(slot);
+        Ok(())
+    }
+
+    fn wrap(&self, generation: u32, data_key: &[u8], kek: &[u8]) -> Result<KeySlot, HeaderError> {
+        let salt = random_bytes(SLOT_SALT_SIZE).map_err(HeaderError::KeyGenerationError)?;
+
+        let (mut stream, mac_key) = Self::slot_keys(kek, &salt, data_key.len());
+        let wrapped_key: Vec<u8> = data_key.iter().zip(stream.iter()).map(|(k, s)| k ^ s).collect();
+        wipe(&mut stream);
+
+        Ok(KeySlot {
+            generation,
+            mac: Self::mac(&mac_key, &wrapped_key),
+            key_check: Self::mac(data_key, KEY_CHECK_INFO),
+            params_mac: self.params_mac(data_key)?,
+            salt,
+            wrapped_key
+        })
+    }
+
+    /// Unwraps the data key of `generation`, fails before anything is mapped
+    /// if `kek` isn't the key the slot was sealed with or the crypto
+    /// parameters were changed since
+    pub fn unlock(&self, generation: u32, kek: &[u8]) -> Result<Key, HeaderError> {
+        let mut slots = self.key_slots.iter().filter(|s| s.generation == generation).peekable();
+        if slots.peek().is_none() {
+            return Err(HeaderError::NoKeySlot(generation));
+        }
+
+        let (slot, mut stream) = slots
+            .find_map(|slot| Self::open_slot(slot, kek).map(|stream| (slot, stream)))
+            .ok_or(HeaderError::WrongKey(generation))?;
+
+        let data_key: Vec<u8> = slot.wrapped_key.iter().zip(stream.iter()).map(|(k, s)| k ^ s).collect();
+        wipe(&mut stream);
+        let key = Key::Raw(data_key);
+
+        let raw = match &key {
+            Key::Raw(raw) if Self::mac(raw, KEY_CHECK_INFO) == slot.key_check => raw,
+            _ => return Err(HeaderError::KeyCheckFailed(generation))
+        };
+
+        match self.params_mac(raw)? == slot.params_mac {
+            true => Ok(key),
+            false => Err(HeaderError::ParamsMismatch(generation))
+        }
+    }
+
+    // Binds the crypto parameters to a data key, whoever can rewrite the
+    // header can't produce it without the key
+    fn params_mac(&self, data_key: &[u8]) -> Result<Vec<u8>, HeaderError> {
+        let mut mac_key = [0u8; MAC_KEY_SIZE];
+        Hkdf::<Sha256>::new(None, data_key)
+            .expand(PARAMS_MAC_INFO, &mut mac_key)
+            .expect("output fits in HKDF-SHA256");
+
+        let mac = Self::mac(&mac_key, &serde_json::to_vec(&self.crypto)?);
+        wipe(&mut mac_key);
+        Ok(mac)
+    }
+
+    // Keystream of a slot sealed with `kek`
+    fn open_slot(slot: &KeySlot, kek: &[u8]) -> Option<Vec<u8>> {
+        let (mut stream, mac_key) = Self::slot_keys(kek, &slot.salt, slot.wrapped_key.len());
+        let valid = Hmac::<Sha256>::new_from_slice(&mac_key)
+            .expect("HMAC accepts keys of any size")
+            .chain_update(&slot.wrapped_key)
+            .verify_slice(&slot.mac)
+            .is_ok();
+
+        if !valid {
+            wipe(&mut stream);
+            return None;
+        }
+        Some(stream)
+    }
+
+    /// Drops the slots of every other generation
+    pub fn retain_key_slot(&mut self, generation: u32) {
+        self.key_slots.retain(|s| s.generation == generation);
+    }
+
+    /// Drops the slot of `generation` sealed with `kek`, a slot `keep` opens
+    /// as well stays so the data key can't be lost when both are the same
+    pub fn drop_key_slot(&mut self, generation: u32, kek: &[u8], keep: &[u8]) {
+        self.key_slots.retain(|s| s.generation != generation
+            || Self::open_slot(s, kek).is_none()
+            || Self::open_slot(s, keep).is_some());
+    }
+
+    // Keystream to wrap the data key with and a key for the slot mac
+    fn slot_keys(kek: &[u8], salt: &[u8], len: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut okm = vec![0u8; len + MAC_KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(salt), kek)
+            .expand(SLOT_INFO, &mut okm)
+            .expect("output fits in HKDF-SHA256");
+
+        let mac_key = okm.split_off(len);
+        (okm, mac_key)
+    }
+
+    fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        Hmac::<Sha256>::new_from_slice(key)
+            .expect("HMAC accepts keys of any size")
+            .chain_update(data)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use crate::{dmcrypt::{BlockMode, Cipher, IvMode}, test_utils};
+
+    use super::*;
+
+    const KEK: [u8; 32] = [0x11; 32];
+    const OTHER_KEK: [u8; 32] = [0x22; 32];
+
+    fn params() -> CryptoParams {
+        CryptoParams {
+            cipher: Cipher::Aes,
+            iv_mode: IvMode::Plain64,
+            block_mode: BlockMode::Xts,
+            iv_offset: 0,
+            additional_options: None,
+            integrity: None
+        }
+    }
+
+    // Zeroed file the size of both header copies
+    fn device() -> NamedTempFile {
+        test_utils::device(2 * COPY_SIZE as u64, 0, &[])
+    }
+
+    fn raw(key: &Key) -> Vec<u8> {
+        match key {
+            Key::Raw(raw) => raw.clone(),
+            _ => panic!("data keys are raw")
+        }
+    }
+
+    fn patch(path: &PathBuf, offset: u64, data: &[u8]) {
+        let mut dev = File::options().write(true).open(path).unwrap();
+        dev.seek(SeekFrom::Start(offset)).unwrap();
+        dev.write_all(data).unwrap();
+    }
+
+    #[test]
+    fn blank_device_has_no_header() {
+        let file = device();
+        let path = file.path().to_path_buf();
+        assert!(PartitionHeader::read(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let file = device();
+        let path = file.path().to_path_buf();
+        let mut header = PartitionHeader::new(params());
+        let key = header.add_key_slot(1, &KEK).unwrap();
+        header.write(&path).unwrap();
+        header.write(&path).unwrap();
+
+        let read = PartitionHeader::read(&path).unwrap().unwrap();
+        assert_eq!(read.seqid, 2);
+        assert!(matches!(read.crypto.block_mode, BlockMode::Xts));
+        assert_eq!(raw(&read.unlock(1, &KEK).unwrap()), raw(&key));
+    }
+
+    #[test]
+    fn newest_valid_copy_wins() {
+        let file = device();
+        let path = file.path().to_path_buf();
+        let mut header = PartitionHeader::new(params());
+        header.write(&path).unwrap();
+        let first = std::fs::read(&path).unwrap();
+        header.write(&path).unwrap();
+
+        // Torn write of the second copy, the first one is newer
+        patch(&path, COPY_OFFSETS[1], &first[..COPY_SIZE]);
+        assert_eq!(PartitionHeader::read(&path).unwrap().unwrap().seqid, 2);
+
+        // Damaged first copy, the older second one is used
+        patch(&path, (FIXED_SIZE + 1) as u64, b"#");
+        assert_eq!(PartitionHeader::read(&path).unwrap().unwrap().seqid, 1);
+
+        patch(&path, COPY_OFFSETS[1] + FIXED_SIZE as u64 + 1, b"#");
+        assert!(matches!(PartitionHeader::read(&path), Err(HeaderError::Corrupted(_))));
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let file = device();
+        let path = file.path().to_path_buf();
+        PartitionHeader::new(params()).write(&path).unwrap();
+        patch(&path, MAGIC.len() as u64, &(VERSION + 1).to_le_bytes());
+        assert_eq!(PartitionHeader::read(&path).unwrap().unwrap().seqid, 1);
+
+        patch(&path, COPY_OFFSETS[1] + MAGIC.len() as u64, &(VERSION + 1).to_le_bytes());
+        assert!(matches!(PartitionHeader::read(&path), Err(HeaderError::Corrupted(_))));
+    }
+
+    #[test]
+    fn data_key_fits_the_parameters() {
+        let mut header = PartitionHeader::new(params());
+        assert_eq!(header.add_key_slot(1, &KEK).unwrap().size(), 64);
+
+        header.crypto.block_mode = BlockMode::Cbc;
+        assert_eq!(header.add_key_slot(2, &KEK).unwrap().size(), 32);
+    }
+
+    #[test]
+    fn unlock_checks_generation_and_kek() {
+        let mut header = PartitionHeader::new(params());
+        header.add_key_slot(1, &KEK).unwrap();
+
+        assert!(matches!(header.unlock(2, &KEK), Err(HeaderError::NoKeySlot(2))));
+        assert!(matches!(header.unlock(1, &OTHER_KEK), Err(HeaderError::WrongKey(1))));
+    }
+
+    #[test]
+    fn changed_params_are_detected() {
+        let mut header = PartitionHeader::new(params());
+        header.add_key_slot(1, &KEK).unwrap();
+
+        header.crypto.iv_mode = IvMode::Plain;
+        assert!(matches!(header.unlock(1, &KEK), Err(HeaderError::ParamsMismatch(1))));
+    }
+
+    #[test]
+    fn tampered_slot_fails_the_key_check() {
+        let mut header = PartitionHeader::new(params());
+        header.add_key_slot(1, &KEK).unwrap();
+
+        header.key_slots[0].key_check[0] ^= 1;
+        assert!(matches!(header.unlock(1, &KEK), Err(HeaderError::KeyCheckFailed(1))));
+    }
+
+    #[test]
+    fn rewrapped_slot_opens_with_both_keks() {
+        let mut header = PartitionHeader::new(params());
+        let key = header.add_key_slot(1, &KEK).unwrap();
+
+        header.rewrap_key_slot(1, &KEK, &OTHER_KEK).unwrap();
+        assert_eq!(raw(&header.unlock(1, &KEK).unwrap()), raw(&key));
+        assert_eq!(raw(&header.unlock(1, &OTHER_KEK).unwrap()), raw(&key));
+
+        header.drop_key_slot(1, &KEK, &OTHER_KEK);
+        assert!(matches!(header.unlock(1, &KEK), Err(HeaderError::WrongKey(1))));
+        assert_eq!(raw(&header.unlock(1, &OTHER_KEK).unwrap()), raw(&key));
+    }
+
+    #[test]
+    fn rolled_back_rewrap_keeps_the_previous_slot() {
+        let mut header = PartitionHeader::new(params());
+        let key = header.add_key_slot(1, &KEK).unwrap();
+
+        header.rewrap_key_slot(1, &KEK, &OTHER_KEK).unwrap();
+        header.drop_key_slot(1, &OTHER_KEK, &KEK);
+        assert!(matches!(header.unlock(1, &OTHER_KEK), Err(HeaderError::WrongKey(1))));
+        assert_eq!(raw(&header.unlock(1, &KEK).unwrap()), raw(&key));
+    }
+
+    // An update to an image with the same root of trust seals with the same key
+    #[test]
+    fn update_to_the_same_kek_keeps_the_slot() {
+        let file = device();
+        let path = file.path().to_path_buf();
+        let mut header = PartitionHeader::new(params());
+        let key = header.add_key_slot(1, &KEK).unwrap();
+        header.write(&path).unwrap();
+
+        header.rewrap_key_slot(1, &KEK, &KEK).unwrap();
+        header.drop_key_slot(1, &KEK, &KEK);
+        header.write(&path).unwrap();
+
+        let reopened = PartitionHeader::read(&path).unwrap().unwrap();
+        assert_eq!(reopened.key_slots.len(), 1);
+        assert_eq!(raw(&reopened.unlock(1, &KEK).unwrap()), raw(&key));
+    }
+
+    #[test]
+    fn retain_drops_other_generations() {
+        let mut header = PartitionHeader::new(params());
+        header.add_key_slot(1, &KEK).unwrap();
+        header.add_key_slot(2, &OTHER_KEK).unwrap();
+
+        header.retain_key_slot(2);
+        assert!(matches!(header.unlock(1, &KEK), Err(HeaderError::NoKeySlot(1))));
+        assert!(header.unlock(2, &OTHER_KEK).is_ok());
+    }
+}
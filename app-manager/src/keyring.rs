@@ -0,0 +1,74 @@
+use std::ffi::{CString, NulError};
+
+use log::warn;
+use nix::{errno::Errno, libc::{c_long, syscall, SYS_add_key, SYS_keyctl}};
+use thiserror::Error;
+
+use crate::dmcrypt::{Key, KeyType};
+
+// Interface of the kernel key retention service, see linux/keyctl.h
+
+const KEY_SPEC_SESSION_KEYRING: c_long = -3;
+const KEYCTL_INVALIDATE: c_long = 21;
+
+#[derive(Error, Debug)]
+pub enum KeyringError {
+    #[error("Invalid key description `{0}`")]
+    InvalidDescription(String, #[source] NulError),
+
+    #[error("Cannot add key `{0}` to the session keyring")]
+    AddKeyError(String, #[source] Errno)
+}
+
+/// Logon key in the session keyring, the kernel doesn't let userspace read
+/// logon keys back. The key is invalidated when dropped.
+pub struct KernelKey {
+    serial: i32,
+    desc: String,
+    size: usize
+}
+
+impl KernelKey {
+    /// `desc` must be of the form `<prefix>:<name>`, as logon keys require
+    pub fn add_logon(desc: &str, payload: &[u8]) -> Result<Self, KeyringError> {
+        let ty = CString::new("logon").unwrap();
+        let cdesc = CString::new(desc)
+            .map_err(|e| KeyringError::InvalidDescription(desc.to_owned(), e))?;
+
+        let ret = unsafe {
+            syscall(
+                SYS_add_key,
+                ty.as_ptr(),
+                cdesc.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                KEY_SPEC_SESSION_KEYRING
+            )
+        };
+
+        if ret < 0 {
+            return Err(KeyringError::AddKeyError(desc.to_owned(), Errno::last()));
+        }
+
+        Ok(Self { serial: ret as i32, desc: desc.to_owned(), size: payload.len() })
+    }
+
+    /// Reference to the key for a dm-crypt table
+    pub fn dm_key(&self) -> Key {
+        Key::Keyring {
+            key_size: self.size,
+            key_type: KeyType::Logon,
+            key_desc: self.desc.clone()
+        }
+    }
+}
+
+impl Drop for KernelKey {
+    fn drop(&mut self) {
+        let ret = unsafe { syscall(SYS_keyctl, KEYCTL_INVALIDATE, self.serial as c_long) };
+
+        if ret < 0 {
+            warn!("Failed to invalidate key `{}`: {}", self.desc, Errno::last());
+        }
+    }
+}
@@ -1,28 +1,198 @@
+use std::{fmt::Display, fs::read, path::PathBuf, str::FromStr};
+
+use hkdf::Hkdf;
+use log::warn;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
 use thiserror::Error;
+use uuid::Uuid;
 
-// TODO: This is a mock up, implement key sealing later
+use crate::rsi::{Rsi, RsiError, RSI_SEALING_KEY_FLAGS_RIM};
 
 pub type Key = [u8; 32];
 
+// Domain separation for everything derived from the root key
+const KDF_SALT: &[u8] = b"app-manager sealing v1";
+const REALM_SEALING_LABEL: &str = "realm-sealing";
+const APPLICATION_SEALING_LABEL: &str = "application-sealing";
+
 #[derive(Error, Debug)]
 pub enum KeyManagerError {
+    #[error("Invalid key backend `{0}`, expected cca or file:<path>")]
+    InvalidBackend(String),
+
+    #[error("RSI error")]
+    RsiError(#[from] RsiError),
+
+    #[error("Cannot read key file {0:?}")]
+    KeyFileReadError(PathBuf, #[source] std::io::Error),
 
+    #[error("Key file {0:?} must hold exactly {1} bytes, got {2}")]
+    KeyFileSizeError(PathBuf, usize, usize),
+
+    #[error("Key derivation failed")]
+    DerivationError()
 }
 
-pub struct KeyManager {
+/// What an application key is used for, each purpose gets an unrelated key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyPurpose {
+    MainStorage,
+    SecureStorage,
+    MainStorageVerity,
+    MainStorageIntegrity,
+    SecureStorageIntegrity
+}
 
+impl KeyPurpose {
+    fn label(&self) -> &'static str {
+        match self {
+            KeyPurpose::MainStorage => "main-storage",
+            KeyPurpose::SecureStorage => "secure-storage",
+            KeyPurpose::MainStorageVerity => "main-storage-verity",
+            KeyPurpose::MainStorageIntegrity => "main-storage-integrity",
+            KeyPurpose::SecureStorageIntegrity => "secure-storage-integrity"
+        }
+    }
+}
+
+/// Where the realm's root sealing key comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyBackend {
+    /// Realm sealing key from the RMM, bound to the realm initial measurement
+    Cca,
+
+    /// Raw key read from a file, for testing without CCA
+    File(PathBuf)
+}
+
+pub trait RootKeySource: Send + Sync {
+    fn root_key(&self) -> Result<Key, KeyManagerError>;
+}
+
+struct CcaKeySource {
+    rsi: Rsi
+}
+
+impl RootKeySource for CcaKeySource {
+    fn root_key(&self) -> Result<Key, KeyManagerError> {
+        Ok(self.rsi.sealing_key(RSI_SEALING_KEY_FLAGS_RIM, 0)?)
+    }
+}
+
+struct FileKeySource {
+    path: PathBuf
+}
+
+impl RootKeySource for FileKeySource {
+    fn root_key(&self) -> Result<Key, KeyManagerError> {
+        let content = read(&self.path)
+            .map_err(|e| KeyManagerError::KeyFileReadError(self.path.clone(), e))?;
+        let len = content.len();
+
+        content.try_into()
+            .map_err(|_| KeyManagerError::KeyFileSizeError(self.path.clone(), std::mem::size_of::<Key>(), len))
+    }
+}
+
+/// Hands out keys derived from the root key, never the root key itself
+pub struct KeyManager {
+    source: Box<dyn RootKeySource>
 }
 
 impl KeyManager {
-    pub fn new() -> Result<Self, KeyManagerError> {
-        Ok(Self {})
+    pub fn new(backend: &KeyBackend) -> Result<Self, KeyManagerError> {
+        let source: Box<dyn RootKeySource> = match backend {
+            KeyBackend::Cca => Box::new(CcaKeySource { rsi: Rsi::open()? }),
+
+            KeyBackend::File(path) => {
+                warn!("Sealing keys are derived from {:?}, storage is only as safe as that file", path);
+                Box::new(FileKeySource { path: path.clone() })
+            }
+        };
+
+        Ok(Self::with_source(source))
+    }
+
+    pub fn with_source(source: Box<dyn RootKeySource>) -> Self {
+        Self { source }
     }
 
     pub fn realm_sealing_key(&self) -> Result<Key, KeyManagerError> {
-        Ok([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31])
+        self.derive(REALM_SEALING_LABEL, &[])
+    }
+
+    /// Key bound to the partitions of an application and the root of trust of
+    /// its image, so leaking one application's key exposes nothing else. The
+    /// name is left out, an imported application may be renamed.
+    /// Bumping the generation gives an unrelated key for rotation.
+    pub fn application_sealing_key(&self, partitions: &[Uuid], root_of_trust: &[u8], purpose: KeyPurpose, generation: u32) -> Result<Key, KeyManagerError> {
+        let generation = generation.to_le_bytes();
+        let mut context = vec![purpose.label().as_bytes(), &generation, root_of_trust];
+        context.extend(partitions.iter().map(|uuid| uuid.as_bytes().as_slice()));
+
+        self.derive(APPLICATION_SEALING_LABEL, &context)
+    }
+
+    // HKDF-SHA256, the info is the label and context fields each prefixed
+    // with its length so different contexts can't collide
+    fn derive(&self, label: &str, context: &[&[u8]]) -> Result<Key, KeyManagerError> {
+        let mut root = self.source.root_key()?;
+        let hkdf = Hkdf::<Sha256>::new(Some(KDF_SALT), &root);
+        wipe(&mut root);
+
+        let mut info = Vec::new();
+        for field in [label.as_bytes()].iter().chain(context.iter()) {
+            info.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            info.extend_from_slice(field);
+        }
+
+        let mut key = [0u8; 32];
+        hkdf.expand(&info, &mut key)
+            .map_err(|_| KeyManagerError::DerivationError())?;
+
+        Ok(key)
+    }
+}
+
+/// Zeroes key material in a way the compiler can't optimize out
+pub fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) };
     }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+impl Display for KeyBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyBackend::Cca => write!(f, "cca"),
+            KeyBackend::File(path) => write!(f, "file:{}", path.to_string_lossy())
+        }
+    }
+}
+
+impl FromStr for KeyBackend {
+    type Err = KeyManagerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "cca" => Ok(KeyBackend::Cca),
+            Some(("file", path)) => Ok(KeyBackend::File(PathBuf::from(path))),
+            _ => Err(KeyManagerError::InvalidBackend(s.to_owned()))
+        }
+    }
+}
+
+impl Serialize for KeyBackend {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
 
-    pub fn application_sealing_key(&self) -> Result<Key, KeyManagerError> {
-        Ok([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31])
+impl<'de> Deserialize<'de> for KeyBackend {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(deserializer)?;
+        v.parse().map_err(D::Error::custom)
     }
 }
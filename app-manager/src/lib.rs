@@ -0,0 +1,20 @@
+pub mod app;
+pub mod attestation;
+pub mod config;
+pub mod diskmanager;
+pub mod dm;
+pub mod dmcrypt;
+pub mod dmintegrity;
+pub mod dmlinear;
+pub mod dmverity;
+pub mod filesystem;
+pub mod header;
+pub mod keyring;
+pub mod keys;
+pub mod manager;
+pub mod reencrypt;
+pub mod rsi;
+pub mod utils;
+
+#[cfg(test)]
+mod test_utils;
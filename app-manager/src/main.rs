@@ -1,33 +1,12 @@
 use log::{debug, info};
 
-use crate::{config::Config, manager::AppManager};
-
-mod app;
-mod config;
-mod diskmanager;
-mod dm;
-mod dmcrypt;
-mod dmverity;
-mod keys;
-mod manager;
-mod utils;
-
-static CONFIG: &'static str = r"
-workdir: /workdir
-vsock_port: 1337
-image_registry: http://192.168.100.1:8888
-crypto:
-  cipher: Aes
-  iv_mode: Plain
-  block_mode: Cbc
-  iv_offset: 0
-";
+use app_manager::{config::Config, manager::AppManager};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let config: Config = serde_yaml::from_str(CONFIG)?;
+    let config = Config::load()?;
     debug!("Using config: {:#?}", config);
 
     info!("Starting app-manager");
@@ -37,20 +16,11 @@ async fn main() -> anyhow::Result<()> {
     info!("Loading realm info from host");
     manager.read_provision_info().await?;
 
-    info!("Decrypting applications main storage");
-    manager.decrypt_main_storage()?;
+    info!("Finishing provisioning with host");
+    manager.finish_provisioning().await?;
 
     info!("Provisioning...");
-    manager.provision_app_image().await?;
-
-    info!("Decrypting secure storage");
-    manager.decrypt_secure_storage()?;
-
-    info!("Provisioning secure storage");
-    manager.provision_secure_storage()?;
-
-    info!("Mounting overlays");
-    manager.mount_overlay()?;
+    manager.provision_applications().await?;
 
     info!("Launcing applications");
     manager.launch_applications()?;
@@ -1,22 +1,27 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 
-use futures::stream::FuturesUnordered;
+use futures::{stream::{self, FuturesUnordered}, StreamExt, TryStreamExt};
 use thiserror::Error;
-use log::{debug, info};
-use protocol::{Command, RealmInfo, Response};
-use tokio::{fs::create_dir, task::{spawn_blocking, JoinHandle}};
-use tokio_vsock::{VsockAddr, VsockStream, VMADDR_CID_HOST};
+use log::{debug, error, info, warn};
+use protocol::{transport::{Stream, TransportError}, AppStatus, Command, ProvisionRequest, ProvisionResponse, ProvisionStage, RealmInfo, Response};
+use tokio::{fs::{create_dir, File}, io::{empty, split, AsyncWriteExt}, select, task::{spawn, spawn_blocking}};
+use tokio_serde::{formats::SymmetricalJson, SymmetricallyFramed};
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+use uuid::Uuid;
 
-use crate::{app::{Application, ApplicationError}, config::Config, diskmanager::{DiskManager, DiskManagerError}, dm::{DeviceMapper, DeviceMapperError}, dmcrypt::{DmCryptError, Key}, keys::{KeyManager, KeyManagerError}, utils::{serde_read, serde_write, UtilitiesError}};
+use crate::{app::{AppTask, Application, ApplicationError, DockerInstallerFactory, InstallerFactory}, attestation::{AttestationError, Attester, ImageRelease}, config::Config, diskmanager::{DiskManager, DiskManagerError, GptDiskManager}, filesystem::{Filesystems, KernelFilesystems}, dm::{DeviceMapper, DeviceMapperError}, dmcrypt::DmCryptError, keys::{KeyManager, KeyManagerError}, utils::{serde_read, serde_write, UtilitiesError}};
 
 #[derive(Error, Debug)]
 pub enum AppManagerError {
     #[error("Unable to connect to host to get provisioning info")]
-    ConnectionFailed(#[source] std::io::Error),
+    ConnectionFailed(#[from] TransportError),
 
     #[error("Protocol error")]
     ProtocolError(#[from] serde_json::Error),
 
+    #[error("Cannot serialize config")]
+    ConfigError(#[from] serde_yaml::Error),
+
     #[error("ApplicationError")]
     AppError(#[from] ApplicationError),
 
@@ -29,6 +34,9 @@ pub enum AppManagerError {
     #[error("KeyManager Error")]
     KeyManagerError(#[from] KeyManagerError),
 
+    #[error("Attestation error")]
+    AttestationError(#[from] AttestationError),
+
     #[error("Workdir creation error")]
     WorkdirCreation(#[source] std::io::Error),
 
@@ -39,51 +47,89 @@ pub enum AppManagerError {
     UtilitiesError(#[from] UtilitiesError),
 
     #[error("Application does not exists")]
-    ApplicationDoesNotExists()
+    ApplicationDoesNotExists(),
+
+    #[error("Verifier refused to release image {0}: {1}")]
+    ImageReleaseDenied(Uuid, String),
+
+    #[error("Cannot store the image released by the verifier in {0:?}")]
+    ImageDownloadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Unexpected provisioning response from host: {0:?}")]
+    UnexpectedProvisionResponse(ProvisionResponse),
+
+    #[error("Provisioning task panicked")]
+    ProvisioningTaskError(#[source] tokio::task::JoinError)
 }
 
+/// Everything applications need from the realm, the kernel and hardware
+/// backed parts can be swapped out to run without them
 pub struct AppManagerCtx {
-    pub disks: DiskManager,
+    pub disks: Box<dyn DiskManager>,
     pub devicemapper: DeviceMapper,
-    pub keymanager: KeyManager
+    pub keymanager: KeyManager,
+    pub attester: Attester,
+    pub filesystems: Box<dyn Filesystems>,
+    pub installers: Box<dyn InstallerFactory>
+}
+
+impl AppManagerCtx {
+    pub fn new(config: &Config) -> Result<Self, AppManagerError> {
+        debug!("Listing available block devices");
+        let disks = GptDiskManager::available()?;
+
+        debug!("Setting up DmCrypt");
+        let devicemapper = DeviceMapper::init()?;
+
+        debug!("Setting up key manager");
+        let keymanager = KeyManager::new(&config.keys)?;
+
+        debug!("Setting up {} attestation", config.attestation);
+        let attester = Attester::new(&config.attestation)?;
+
+        Ok(Self {
+            disks: Box::new(disks),
+            devicemapper,
+            keymanager,
+            attester,
+            filesystems: Box::new(KernelFilesystems {}),
+            installers: Box::new(DockerInstallerFactory {})
+        })
+    }
 }
 
 pub struct AppManager {
     ctx: Arc<AppManagerCtx>,
     config: Config,
-    stream: VsockStream,
+    stream: Box<dyn Stream>,
     apps: HashMap<String, Application>,
-    thread_handlers: FuturesUnordered<JoinHandle<handler::Result<()>>>
+    app_tasks: FuturesUnordered<AppTask>
 }
 
 impl AppManager {
     pub async fn setup(config: Config) -> Result<Self, AppManagerError> {
+        let ctx = AppManagerCtx::new(&config)?;
+        Self::with_ctx(config, ctx).await
+    }
+
+    /// Manager on top of an already set up context, connects to the host
+    pub async fn with_ctx(config: Config, ctx: AppManagerCtx) -> Result<Self, AppManagerError> {
+        config.crypto.validate()?;
+
         if !config.workdir.exists() {
             create_dir(&config.workdir).await.map_err(AppManagerError::WorkdirCreation)?;
         }
 
-        let stream = VsockStream::connect(
-            VsockAddr::new(VMADDR_CID_HOST, config.vsock_port)
-        ).await.map_err(AppManagerError::ConnectionFailed)?;
-
-        debug!("Listing available block devices");
-        let disks = DiskManager::available()?;
-
-        debug!("Setting up DmCrypt");
-        let devicemapper = DeviceMapper::init()?;
-
-        debug!("Setting up key manager");
-        let keymanager = KeyManager::new()?;
+        debug!("Connecting to host over {}", config.transport);
+        let stream = config.transport.connector().connect().await?;
 
-        let manager = Self {
-            ctx: Arc::new(AppManagerCtx { disks, devicemapper, keymanager }),
+        Ok(Self {
+            ctx: Arc::new(ctx),
             config,
             stream,
             apps: HashMap::new(),
-            thread_handlers: FuturesUnordered::new()
-        };
-
-        Ok(manager)
+            app_tasks: FuturesUnordered::new()
+        })
     }
 
     pub async fn read_provision_info(&mut self) -> Result<(), AppManagerError> {
@@ -91,87 +137,241 @@ impl AppManager {
 
         debug!("Received RealmInfo: {:#?}", info);
 
+        self.config.apply_realm_info(&info.config);
+        let config = serde_yaml::to_string(&self.config)?;
+        info!("Effective config:\n{}", config);
+        serde_write(&mut self.stream, ProvisionRequest::EffectiveConfig(config)).await?;
+
         for (name, info) in info.apps.iter() {
             let workdir = self.config.workdir.join(name);
-            self.apps.insert(name.clone(), Application::new(self.ctx.clone(), workdir, info.clone())?);
+            let mut app = Application::new(self.ctx.clone(), workdir, name.clone(), info.clone())?;
+
+            // Only a broken connection to the host stops the boot, an
+            // application without its root of trust is left out
+            let res = match self.config.image_release {
+                ImageRelease::Registry => app.fetch_root_of_trust(&self.config.image_registry).await.map_err(AppManagerError::from),
+                ImageRelease::Verifier => match app.image_uuid() {
+                    Some(uuid) => self.request_release(&mut app, uuid).await,
+                    None => Ok(())
+                }
+            };
+
+            match res {
+                Ok(()) => {},
+                Err(e @ (AppManagerError::AppError(_) | AppManagerError::ImageReleaseDenied(..) | AppManagerError::ImageDownloadError(..))) => app.fail(ProvisionStage::RootOfTrust, describe(e)),
+                Err(e) => return Err(e)
+            }
+
+            self.apps.insert(name.clone(), app);
             info!("Added application: {}", name);
         }
 
         Ok(())
     }
 
-    pub fn decrypt_main_storage(&mut self) -> Result<(), AppManagerError> {
-        let row_realm_sealing_key = self.ctx.keymanager.realm_sealing_key()?;
-        let key = Key::Raw(row_realm_sealing_key.to_vec());
+    // Attests to the host verifier with a token bound to its nonce
+    async fn request_root_of_trust(&mut self, uuid: Uuid) -> Result<Vec<u8>, AppManagerError> {
+        debug!("Requesting release of image {}", uuid);
+        serde_write(&mut self.stream, ProvisionRequest::ImageNonce(uuid)).await?;
+        let nonce = match serde_read(&mut self.stream).await? {
+            ProvisionResponse::Nonce(nonce) => nonce,
+            resp => return Err(AppManagerError::UnexpectedProvisionResponse(resp))
+        };
 
-        for (name, app) in self.apps.iter_mut() {
-            info!("Decrypting main storage for {}", name);
-            app.decrypt_main_storage(&self.config.crypto, &key)?;
+        let token = self.ctx.attester.token(&nonce)?;
+        serde_write(&mut self.stream, ProvisionRequest::ReleaseImage { uuid, token }).await?;
+
+        match serde_read(&mut self.stream).await? {
+            ProvisionResponse::RootOfTrust(rot) => Ok(rot),
+            ProvisionResponse::Denied(reason) => Err(AppManagerError::ImageReleaseDenied(uuid, reason)),
+            resp => Err(AppManagerError::UnexpectedProvisionResponse(resp))
         }
+    }
 
+    // The image is only handed out to attested realms, so an application
+    // that still has to be installed gets it from the verifier as well
+    async fn request_release(&mut self, app: &mut Application, uuid: Uuid) -> Result<(), AppManagerError> {
+        let root_of_trust = self.request_root_of_trust(uuid).await?;
+        let image = match app.needs_image() {
+            true => Some(self.request_image(uuid, app.released_image_path()).await?),
+            false => None
+        };
+
+        app.release(root_of_trust, image);
         Ok(())
     }
 
-    pub async fn provision_app_image(&mut self) -> Result<(), AppManagerError> {
-        for (name, app) in self.apps.iter_mut() {
-            info!("Provisioning image for {}", name);
-            app.provision_app_image(&self.config.image_registry).await?;
+    async fn request_image(&mut self, uuid: Uuid, path: PathBuf) -> Result<PathBuf, AppManagerError> {
+        info!("Downloading image {} from the verifier", uuid);
+        let err = |e| AppManagerError::ImageDownloadError(path.clone(), e);
+        let mut file = File::create(&path).await.map_err(err)?;
+        let mut offset = 0;
+
+        loop {
+            serde_write(&mut self.stream, ProvisionRequest::ImageChunk { uuid, offset }).await?;
+            let chunk = match serde_read(&mut self.stream).await? {
+                ProvisionResponse::ImageChunk(chunk) => chunk,
+                ProvisionResponse::Denied(reason) => return Err(AppManagerError::ImageReleaseDenied(uuid, reason)),
+                resp => return Err(AppManagerError::UnexpectedProvisionResponse(resp))
+            };
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            file.write_all(&chunk).await.map_err(err)?;
+            offset += chunk.len() as u64;
         }
 
-        Ok(())
+        file.flush().await.map_err(err)?;
+        debug!("Downloaded {} bytes of image {}", offset, uuid);
+        Ok(path)
     }
 
-    pub fn decrypt_secure_storage(&mut self) -> Result<(), AppManagerError> {
-        let row_realm_sealing_key = self.ctx.keymanager.realm_sealing_key()?;
-        let key = Key::Raw(row_realm_sealing_key.to_vec());
-        // TODO: add key sealing here later
+    /// Tells the host no more provisioning requests follow, commands may be sent from now on
+    pub async fn finish_provisioning(&mut self) -> Result<(), AppManagerError> {
+        Ok(serde_write(&mut self.stream, ProvisionRequest::Done()).await?)
+    }
 
-        for (name, app) in self.apps.iter_mut() {
-            info!("Decrypting secure storage {}", name);
-            app.decrypt_secure_storage(&self.config.crypto, &key)?;
+    /// Applications are independent of each other, up to `provisioning_jobs`
+    /// of them are decrypted, installed and mounted at the same time. A
+    /// failed application is marked as such and the others carry on.
+    pub async fn provision_applications(&mut self) -> Result<(), AppManagerError> {
+        let (pending, failed): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(&mut self.apps)
+            .into_iter()
+            .partition(|(_, app)| !app.is_failed());
+        self.apps = failed;
+
+        let total = pending.len();
+        let jobs = self.config.provisioning_jobs.max(1);
+        info!("Provisioning {} applications, {} at a time", total, jobs);
+
+        let crypto = self.config.crypto.clone();
+        let registry = self.config.image_registry.clone();
+
+        let tasks = pending.into_iter().map(|(name, mut app)| {
+            let (crypto, registry) = (crypto.clone(), registry.clone());
+
+            spawn(async move {
+                let res = app.provision(&crypto, &registry).await;
+                (name, app, res)
+            })
+        });
+        let mut results = stream::iter(tasks).buffer_unordered(jobs);
+
+        let mut done = 0;
+
+        while let Some(res) = results.next().await {
+            let (name, mut app, res) = res.map_err(AppManagerError::ProvisioningTaskError)?;
+            done += 1;
+
+            match res {
+                Ok(()) => info!("Provisioned {} ({}/{})", name, done, total),
+                Err(e) => app.fail(app.stage(), describe(e))
+            }
+
+            self.apps.insert(name, app);
+        }
+
+        let failed = self.apps.values().filter(|app| app.is_failed()).count();
+        if failed > 0 {
+            warn!("{} of {} applications failed to provision", failed, self.apps.len());
         }
 
         Ok(())
     }
 
-    pub fn provision_secure_storage(&self) -> Result<(), AppManagerError> {
-        for (name, app) in self.apps.iter() {
-            info!("Provisioning secure memory for {}", name);
-            app.provision_secure_memory()?;
+    pub fn launch_applications(&mut self) -> Result<(), AppManagerError> {
+        for (name, app) in self.apps.iter_mut().filter(|(_, app)| !app.is_failed()) {
+            info!("Launching: {}", name);
+
+            match app.launch() {
+                Ok(handle) => self.app_tasks.push(handle),
+                Err(e) => app.fail(ProvisionStage::Launch, describe(e))
+            }
         }
 
         Ok(())
     }
 
-    pub fn mount_overlay(&self) -> Result<(), AppManagerError> {
-        for (name, app) in self.apps.iter() {
-            info!("Mounting overlay for {}", name);
-            app.mount_overlay()?;
+    // Partial storage of the failed attempt is released first, fresh
+    // partitions are provisioned from scratch again
+    async fn retry_provision(&mut self, id: &String) -> Result<Response, AppManagerError> {
+        let grace = self.stop_grace();
+        let app = self.apps.get_mut(id)
+            .ok_or(AppManagerError::ApplicationDoesNotExists())?;
+
+        let stage = match app.status() {
+            AppStatus::Failed { stage, .. } => stage,
+            _ => return Ok(Response::Error(format!("{} did not fail to provision", id)))
+        };
+
+        info!("Retrying provisioning of {} from {}", id, stage);
+        if let Err(e) = app.teardown(grace).await {
+            return Ok(Response::Error(format!("cannot release storage of the failed attempt: {}", describe(e))));
         }
 
-        Ok(())
+        if stage == ProvisionStage::RootOfTrust {
+            if self.config.image_release != ImageRelease::Registry {
+                return Ok(Response::Error("the verifier only releases images while the realm boots".to_owned()));
+            }
+
+            if let Err(e) = app.fetch_root_of_trust(&self.config.image_registry).await {
+                app.fail(ProvisionStage::RootOfTrust, describe(e));
+                return Ok(Response::AppStatus(app.status()));
+            }
+        }
+
+        if let Err(e) = app.provision(&self.config.crypto, &self.config.image_registry).await {
+            app.fail(app.stage(), describe(e));
+            return Ok(Response::AppStatus(app.status()));
+        }
+
+        match app.launch() {
+            Ok(handle) => self.app_tasks.push(handle),
+            Err(e) => app.fail(ProvisionStage::Launch, describe(e))
+        }
+
+        Ok(Response::AppStatus(app.status()))
     }
 
-    pub fn launch_applications(&mut self) -> Result<(), AppManagerError> {
+    /// Stops the applications and releases their storage so the realm can be
+    /// powered off without leaving filesystems or mappings behind
+    pub async fn teardown(&mut self) -> Result<(), AppManagerError> {
+        let grace = self.stop_grace();
+        let mut result = Ok(());
+
         for (name, app) in self.apps.iter_mut() {
-            info!("Launching: {}", name);
-            let handle = app.launch()?;
-            self.thread_handlers.push(handle);
+            info!("Tearing down {}", name);
+            if let Err(e) = app.teardown(grace).await {
+                error!("Teardown of {} incomplete: {}", name, e);
+                result = result.and(Err(e.into()));
+            }
         }
 
-        Ok(())
+        result
+    }
+
+    fn stop_grace(&self) -> Duration {
+        Duration::from_secs(self.config.stop_grace)
     }
 
     async fn handle_command(&mut self, command: &Command) -> Result<Response, AppManagerError> {
         match command {
+            // The host powers the realm off either way
             Command::Shutdown() => {
+                if let Err(e) = self.teardown().await {
+                    error!("Shutting down with an incomplete teardown: {}", e);
+                }
                 Ok(Response::Ok)
             },
 
-            Command::TerminateApp(id) => {
+            Command::TerminateApp { app: id, grace } => {
+                let grace = grace.map_or(self.stop_grace(), Duration::from_secs);
                 let app = self.apps.get_mut(id)
                     .ok_or(AppManagerError::ApplicationDoesNotExists())?;
-                Ok(Response::ExitStatus(app.terminate().await?))
+                let (status, stop) = app.terminate(grace).await?;
+                Ok(Response::Terminated { status, stop })
             },
 
             Command::KillApp(id) => {
@@ -183,19 +383,102 @@ impl AppManager {
             Command::StartApp(id) => {
                 let app = self.apps.get_mut(id)
                     .ok_or(AppManagerError::ApplicationDoesNotExists())?;
-                self.thread_handlers.push(app.launch()?);
+                if app.is_failed() {
+                    return Ok(Response::Error(format!("{} failed to provision: {}", id, app.status())));
+                }
+                self.app_tasks.push(app.launch()?);
                 Ok(Response::Ok)
             },
+
+            Command::RetryProvision(id) => self.retry_provision(id).await,
+
+            Command::AppStatus(id) => {
+                let app = self.apps.get(id)
+                    .ok_or(AppManagerError::ApplicationDoesNotExists())?;
+                Ok(Response::AppStatus(app.status()))
+            },
+
+            Command::Attest { challenge } => {
+                Ok(Response::AttestationToken(self.ctx.attester.token(challenge)?))
+            },
+
+            // A failed update is rolled back, only the host needs to know
+            Command::UpdateApp { app: id, image_uuid } => {
+                if self.config.image_release != ImageRelease::Registry {
+                    return Ok(Response::Error("updates need the image released by the registry".to_owned()));
+                }
+
+                let grace = self.stop_grace();
+                let app = self.apps.get_mut(id)
+                    .ok_or(AppManagerError::ApplicationDoesNotExists())?;
+                let (handle, result) = app.update(&self.config.image_registry, *image_uuid, grace).await;
+                self.app_tasks.extend(handle);
+
+                match result {
+                    Ok(()) => Ok(Response::Ok),
+                    Err(e) => {
+                        let e = describe(e);
+                        error!("Update of {} failed: {}", id, e);
+                        Ok(Response::Error(e))
+                    }
+                }
+            },
+
+            Command::RotateKey(id) => {
+                let grace = self.stop_grace();
+                let app = self.apps.get_mut(id)
+                    .ok_or(AppManagerError::ApplicationDoesNotExists())?;
+                let (handle, result) = app.rotate_secure_key(grace).await;
+                self.app_tasks.extend(handle);
+
+                match result {
+                    Ok(()) => Ok(Response::Ok),
+                    Err(e) => {
+                        let e = describe(e);
+                        error!("Key rotation of {} failed: {}", id, e);
+                        Ok(Response::Error(e))
+                    }
+                }
+            },
         }
     }
 
+    /// Serves the host and supervises the launched applications until the
+    /// realm shuts down, the connection is owned by the loop from now on
     pub async fn event_loop(&mut self) -> Result<(), AppManagerError> {
+        // A single framed reader keeps a partially received command while
+        // an application exit is being handled
+        let (reader, mut writer) = split(std::mem::replace(&mut self.stream, Box::new(empty())));
+        let mut commands = SymmetricallyFramed::new(
+            FramedRead::new(reader, LengthDelimitedCodec::new()),
+            SymmetricalJson::<Command>::default()
+        );
+
         loop {
-            let req: Command = serde_read(&mut self.stream).await?;
+            let req = select! {
+                req = commands.try_next() => req
+                    .map_err(UtilitiesError::SerdeReadError)?
+                    .ok_or(UtilitiesError::StreamIsClosed())?,
+
+                Some((name, launch, result)) = self.app_tasks.next(), if !self.app_tasks.is_empty() => {
+                    if let Some(app) = self.apps.get_mut(&name) {
+                        app.exited(launch, result);
+                    }
+                    continue;
+                }
+            };
+
             debug!("Received command: {:?}", req);
-            let resp = self.handle_command(&req).await?;
+            let resp = match self.handle_command(&req).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let e = describe(e);
+                    error!("{:?} failed: {}", req, e);
+                    Response::Error(e)
+                }
+            };
             debug!("Genereted response: {:?}", resp);
-            serde_write(&mut self.stream, resp).await?;
+            serde_write(&mut writer, resp).await?;
 
             if let Command::Shutdown() = req {
                 info!("Received shutdown request exiting");
@@ -204,3 +487,8 @@ impl AppManager {
         }
     }
 }
+
+// Errors reported to the host carry their whole chain of causes
+fn describe(e: impl Into<anyhow::Error>) -> String {
+    format!("{:#}", e.into())
+}
@@ -0,0 +1,503 @@
+use std::{fs::{read, remove_file, File}, io::{Read, Seek, SeekFrom, Write}, os::unix::fs::{FileExt, OpenOptionsExt}, path::{Path, PathBuf}};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use nix::libc::O_DIRECT;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{dmcrypt::Key, keys::wipe, utils::write_atomic};
+
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const KEY_STATE: &str = "secure-key.json";
+const MARKER: &str = "secure-rotation";
+const MARKER_MAGIC: &[u8; 8] = b"ROTATE02";
+const MARKER_HEADER: usize = MARKER_MAGIC.len() + 4 + 8 + 4;
+const TAG_SIZE: usize = 32;
+const TAG_KEY_INFO: &[u8] = b"app-manager rotation marker";
+
+// O_DIRECT buffers are aligned for any logical block size
+const DIRECT_ALIGN: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum ReencryptError {
+    #[error("Cannot read {0:?}")]
+    StateReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write {0:?}")]
+    StateWriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Corrupted key state {0:?}")]
+    StateParseError(PathBuf, #[source] serde_json::Error),
+
+    #[error("Corrupted rotation marker {0:?}")]
+    CorruptedMarker(PathBuf),
+
+    #[error("No key rotation in progress")]
+    NoRotationInProgress(),
+
+    #[error("Cannot read from {0:?}")]
+    DeviceReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write to {0:?}")]
+    DeviceWriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Old and new mapping differ in size, {0} and {1} bytes")]
+    SizeMismatch(u64, u64),
+
+    #[error("Mapping size {0} is not a multiple of the {1} byte sector size")]
+    UnalignedSize(u64, u32)
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct KeyState {
+    generation: u32
+}
+
+// Everything before `position` is already under the new key and everything
+// after the chunk at `position` is still under the old one. The chunk itself
+// may be anywhere in between, the marker keeps a MAC of the plaintext of each
+// of its sectors to tell which ones are still under the old key. No plaintext
+// ever reaches the main storage and the MACs are keyed from the new data key.
+// An empty list of tags means nothing of the chunk was written yet.
+struct Marker {
+    target: u32,
+    position: u64,
+    sector_size: u32,
+    tags: Vec<[u8; TAG_SIZE]>
+}
+
+/// Tracks the generation of the secure storage key and the progress of its
+/// rotation, the state lives in `dir` on the main storage
+pub struct KeyRotation {
+    dir: PathBuf
+}
+
+impl KeyRotation {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn generation(&self) -> Result<u32, ReencryptError> {
+        let path = self.dir.join(KEY_STATE);
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let content = read(&path).map_err(|e| ReencryptError::StateReadError(path.clone(), e))?;
+        let state: KeyState = serde_json::from_slice(&content)
+            .map_err(|e| ReencryptError::StateParseError(path, e))?;
+
+        Ok(state.generation)
+    }
+
+    /// Generation an interrupted rotation was moving to
+    pub fn pending(&self) -> Result<Option<u32>, ReencryptError> {
+        let marker = match self.read_marker()? {
+            Some(marker) => marker,
+            None => return Ok(None)
+        };
+
+        // Crashed after committing the new generation
+        if marker.target == self.generation()? {
+            let path = self.dir.join(MARKER);
+            remove_file(&path).map_err(|e| ReencryptError::StateWriteError(path, e))?;
+            return Ok(None);
+        }
+
+        Ok(Some(marker.target))
+    }
+
+    /// Whether a rotation in progress may already have moved data to the
+    /// new key, before that the old key still maps all of it
+    pub fn started(&self) -> Result<bool, ReencryptError> {
+        Ok(self.read_marker()?.is_some_and(|marker| marker.position > 0 || !marker.tags.is_empty()))
+    }
+
+    /// Records the start of a rotation to `target`, the mappings use
+    /// `sector_size` byte sectors
+    pub fn begin(&self, target: u32, sector_size: u32) -> Result<(), ReencryptError> {
+        info!("Starting key rotation to generation {}", target);
+        self.write_marker(&Marker { target, position: 0, sector_size, tags: Vec::new() })
+    }
+
+    /// Copies the data from the `old` mapping to the `new` one chunk by
+    /// chunk, starting from the last marker, and commits the new generation.
+    /// `data_key` is the key of the new generation.
+    pub fn run(&self, old: &Path, new: &Path, data_key: &Key) -> Result<u32, ReencryptError> {
+        let marker = self.read_marker()?.ok_or(ReencryptError::NoRotationInProgress())?;
+        let target = marker.target;
+
+        let mut tag_key = tag_key(data_key);
+        let res = self.copy(marker, old, new, &tag_key);
+        wipe(&mut tag_key);
+        res?;
+
+        let state = serde_json::to_vec(&KeyState { generation: target })
+            .map_err(|e| ReencryptError::StateParseError(self.dir.join(KEY_STATE), e))?;
+        self.write_atomic(KEY_STATE, &state)?;
+
+        let path = self.dir.join(MARKER);
+        remove_file(&path).map_err(|e| ReencryptError::StateWriteError(path, e))?;
+        self.sync_dir()?;
+
+        info!("Key rotation to generation {} finished", target);
+        Ok(target)
+    }
+
+    fn copy(&self, marker: Marker, old: &Path, new: &Path, tag_key: &[u8]) -> Result<(), ReencryptError> {
+        let (target, sector_size) = (marker.target, marker.sector_size);
+
+        let mut old_dev = File::open(old).map_err(|e| ReencryptError::DeviceReadError(old.to_owned(), e))?;
+        let mut new_dev = File::options()
+            .read(true)
+            .write(true)
+            .open(new)
+            .map_err(|e| ReencryptError::DeviceWriteError(new.to_owned(), e))?;
+
+        let size = device_size(&mut old_dev, old)?;
+        let new_size = device_size(&mut new_dev, new)?;
+        if size != new_size {
+            return Err(ReencryptError::SizeMismatch(size, new_size));
+        }
+        if size % sector_size as u64 != 0 {
+            return Err(ReencryptError::UnalignedSize(size, sector_size));
+        }
+
+        debug!("Re-encrypting from offset {}", marker.position);
+        let mut position = recover_chunk(&marker, old, new, tag_key)?;
+
+        while position < size {
+            if position % (CHUNK_SIZE * 64) == 0 {
+                debug!("Re-encrypted {} of {} bytes", position, size);
+            }
+
+            let mut chunk = read_chunk(&mut old_dev, old, position, size)?;
+            let tags = chunk_tags(tag_key, position, &chunk, sector_size);
+            let res = self.write_marker(&Marker { target, position, sector_size, tags })
+                .and_then(|_| new_dev.seek(SeekFrom::Start(position))
+                    .and_then(|_| new_dev.write_all(&chunk))
+                    .and_then(|_| new_dev.sync_data())
+                    .map_err(|e| ReencryptError::DeviceWriteError(new.to_owned(), e)));
+
+            position += chunk.len() as u64;
+            wipe(&mut chunk);
+            res?;
+        }
+
+        Ok(())
+    }
+
+    fn read_marker(&self) -> Result<Option<Marker>, ReencryptError> {
+        let path = self.dir.join(MARKER);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = read(&path).map_err(|e| ReencryptError::StateReadError(path.clone(), e))?;
+        Marker::decode(&content).map(Some).ok_or(ReencryptError::CorruptedMarker(path))
+    }
+
+    fn write_marker(&self, marker: &Marker) -> Result<(), ReencryptError> {
+        self.write_atomic(MARKER, &marker.encode())
+    }
+
+    fn write_atomic(&self, name: &str, content: &[u8]) -> Result<(), ReencryptError> {
+        let path = self.dir.join(name);
+        write_atomic(&path, content).map_err(|e| ReencryptError::StateWriteError(path, e))
+    }
+
+    fn sync_dir(&self) -> Result<(), ReencryptError> {
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| ReencryptError::StateWriteError(self.dir.clone(), e))
+    }
+}
+
+impl Marker {
+    fn encode(&self) -> Vec<u8> {
+        let mut content = Vec::with_capacity(MARKER_HEADER + self.tags.len() * TAG_SIZE);
+        content.extend_from_slice(MARKER_MAGIC);
+        content.extend_from_slice(&self.target.to_le_bytes());
+        content.extend_from_slice(&self.position.to_le_bytes());
+        content.extend_from_slice(&self.sector_size.to_le_bytes());
+        content.extend(self.tags.iter().flatten());
+        content
+    }
+
+    fn decode(content: &[u8]) -> Option<Self> {
+        if content.len() < MARKER_HEADER || &content[..MARKER_MAGIC.len()] != MARKER_MAGIC {
+            return None;
+        }
+
+        let (header, tags) = content.split_at(MARKER_HEADER);
+        let target = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let position = u64::from_le_bytes(header[12..20].try_into().unwrap());
+        let sector_size = u32::from_le_bytes(header[20..24].try_into().unwrap());
+
+        if sector_size == 0 || !(sector_size as usize).is_multiple_of(512) || !tags.len().is_multiple_of(TAG_SIZE) {
+            return None;
+        }
+
+        let tags = tags.chunks_exact(TAG_SIZE).map(|tag| tag.try_into().unwrap()).collect();
+        Some(Self { target, position, sector_size, tags })
+    }
+}
+
+fn tag_key(data_key: &Key) -> [u8; 32] {
+    let raw = match data_key {
+        Key::Raw(raw) => raw,
+        _ => unreachable!("data keys are always raw")
+    };
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, raw)
+        .expand(TAG_KEY_INFO, &mut key)
+        .expect("output fits in HKDF-SHA256");
+    key
+}
+
+// Bound to the offset so sectors can't be swapped around
+fn sector_tag(key: &[u8], offset: u64, sector: &[u8]) -> [u8; TAG_SIZE] {
+    Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any size")
+        .chain_update(offset.to_le_bytes())
+        .chain_update(sector)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+fn chunk_tags(key: &[u8], position: u64, chunk: &[u8], sector_size: u32) -> Vec<[u8; TAG_SIZE]> {
+    chunk.chunks(sector_size as usize)
+        .enumerate()
+        .map(|(i, sector)| sector_tag(key, position + (i * sector_size as usize) as u64, sector))
+        .collect()
+}
+
+// Copies the sectors of the marked chunk that still hold the old plaintext
+// and returns where to continue. A sector already under the new key reads
+// as garbage or fails authentication through the old mapping, either way it
+// doesn't match its tag. Both devices are opened with O_DIRECT so every
+// sector is read and written on its own.
+fn recover_chunk(marker: &Marker, old: &Path, new: &Path, tag_key: &[u8]) -> Result<u64, ReencryptError> {
+    if marker.tags.is_empty() {
+        return Ok(marker.position);
+    }
+
+    let old_dev = File::options().read(true).custom_flags(O_DIRECT).open(old)
+        .map_err(|e| ReencryptError::DeviceReadError(old.to_owned(), e))?;
+    let new_dev = File::options().write(true).custom_flags(O_DIRECT).open(new)
+        .map_err(|e| ReencryptError::DeviceWriteError(new.to_owned(), e))?;
+
+    let sector_size = marker.sector_size as usize;
+    let mut storage = vec![0u8; sector_size + DIRECT_ALIGN];
+    let align = storage.as_ptr().align_offset(DIRECT_ALIGN);
+    let sector = &mut storage[align..align + sector_size];
+    let mut copied = 0;
+
+    let res = marker.tags.iter().enumerate().try_for_each(|(i, tag)| {
+        let offset = marker.position + (i * sector_size) as u64;
+
+        let old_key = old_dev.read_exact_at(sector, offset).is_ok()
+            && sector_tag(tag_key, offset, sector) == *tag;
+        if old_key {
+            new_dev.write_all_at(sector, offset)?;
+            copied += 1;
+        }
+        Ok(())
+    });
+
+    wipe(&mut storage);
+    res.and_then(|_| new_dev.sync_data())
+        .map_err(|e| ReencryptError::DeviceWriteError(new.to_owned(), e))?;
+    debug!("Recovered chunk at {}, {} of {} sectors were still under the old key", marker.position, copied, marker.tags.len());
+
+    Ok(marker.position + (marker.tags.len() * sector_size) as u64)
+}
+
+fn device_size(dev: &mut File, path: &Path) -> Result<u64, ReencryptError> {
+    dev.seek(SeekFrom::End(0)).map_err(|e| ReencryptError::DeviceReadError(path.to_owned(), e))
+}
+
+fn read_chunk(dev: &mut File, path: &Path, position: u64, size: u64) -> Result<Vec<u8>, ReencryptError> {
+    let err = |e| ReencryptError::DeviceReadError(path.to_owned(), e);
+    let mut chunk = vec![0u8; CHUNK_SIZE.min(size - position) as usize];
+
+    dev.seek(SeekFrom::Start(position)).map_err(err)?;
+    if let Err(e) = dev.read_exact(&mut chunk) {
+        wipe(&mut chunk);
+        return Err(err(e));
+    }
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u32 = 4096;
+
+    fn file(dir: &tempfile::TempDir, name: &str, content: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+
+    fn plaintext(sectors: usize) -> Vec<u8> {
+        (0..sectors * SECTOR as usize).map(|i| (i / SECTOR as usize + i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn marker_roundtrip() {
+        let marker = Marker { target: 3, position: 5 * CHUNK_SIZE, sector_size: SECTOR, tags: vec![[1; TAG_SIZE], [2; TAG_SIZE]] };
+        let content = marker.encode();
+        assert_eq!(content.len(), MARKER_HEADER + 2 * TAG_SIZE);
+
+        let decoded = Marker::decode(&content).unwrap();
+        assert_eq!((decoded.target, decoded.position, decoded.sector_size), (3, 5 * CHUNK_SIZE, SECTOR));
+        assert_eq!(decoded.tags, marker.tags);
+    }
+
+    #[test]
+    fn invalid_markers() {
+        let content = Marker { target: 1, position: 0, sector_size: 512, tags: vec![[1; TAG_SIZE]] }.encode();
+        assert!(Marker::decode(&content[..MARKER_HEADER - 1]).is_none());
+        assert!(Marker::decode(&content[..content.len() - 1]).is_none());
+
+        let mut old_magic = content.clone();
+        old_magic[..MARKER_MAGIC.len()].copy_from_slice(b"ROTATE01");
+        assert!(Marker::decode(&old_magic).is_none());
+
+        for sector_size in [0u32, 1000] {
+            let mut content = content.clone();
+            content[20..24].copy_from_slice(&sector_size.to_le_bytes());
+            assert!(Marker::decode(&content).is_none());
+        }
+    }
+
+    #[test]
+    fn tags_cover_every_sector_at_its_offset() {
+        let key = [7u8; 32];
+        let chunk = vec![0u8; 2 * SECTOR as usize + 512];
+        let tags = chunk_tags(&key, CHUNK_SIZE, &chunk, SECTOR);
+
+        assert_eq!(tags.len(), 3);
+        assert_ne!(tags[0], tags[1]);
+        assert_eq!(tags[1], sector_tag(&key, CHUNK_SIZE + SECTOR as u64, &chunk[..SECTOR as usize]));
+        assert_eq!(tags, chunk_tags(&key, CHUNK_SIZE, &chunk, SECTOR));
+        assert_ne!(tags, chunk_tags(&[8u8; 32], CHUNK_SIZE, &chunk, SECTOR));
+    }
+
+    #[test]
+    fn tag_key_depends_on_the_data_key() {
+        assert_eq!(tag_key(&Key::Raw(vec![1; 64])), tag_key(&Key::Raw(vec![1; 64])));
+        assert_ne!(tag_key(&Key::Raw(vec![1; 64])), tag_key(&Key::Raw(vec![2; 64])));
+    }
+
+    #[test]
+    fn unwritten_chunk_needs_no_recovery() {
+        let marker = Marker { target: 1, position: CHUNK_SIZE, sector_size: SECTOR, tags: Vec::new() };
+        let missing = Path::new("/nonexistent");
+        assert_eq!(recover_chunk(&marker, missing, missing, &[0; 32]).unwrap(), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn recovery_copies_sectors_still_under_the_old_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [9u8; 32];
+        let data = plaintext(4);
+        let sector = SECTOR as usize;
+
+        // The first two sectors were rewritten before the crash, through the
+        // old mapping they now read as garbage
+        let mut old = data.clone();
+        old[..2 * sector].fill(0x5a);
+        let mut new = vec![0u8; data.len()];
+        new[..2 * sector].copy_from_slice(&data[..2 * sector]);
+
+        let old = file(&dir, "old", &old);
+        let new = file(&dir, "new", &new);
+        let marker = Marker { target: 1, position: 0, sector_size: SECTOR, tags: chunk_tags(&key, 0, &data, SECTOR) };
+
+        assert_eq!(recover_chunk(&marker, &old, &new, &key).unwrap(), data.len() as u64);
+        assert_eq!(std::fs::read(&new).unwrap(), data);
+    }
+
+    #[test]
+    fn rotation_copies_and_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = plaintext(3 * (CHUNK_SIZE as usize / SECTOR as usize) / 2);
+        let old = file(&dir, "old", &data);
+        let new = file(&dir, "new", &vec![0u8; data.len()]);
+        let rotation = KeyRotation::new(dir.path().to_owned());
+
+        assert_eq!(rotation.generation().unwrap(), 0);
+        assert!(matches!(rotation.run(&old, &new, &Key::Raw(vec![1; 64])), Err(ReencryptError::NoRotationInProgress())));
+
+        assert!(!rotation.started().unwrap());
+        rotation.begin(1, SECTOR).unwrap();
+        assert_eq!(rotation.pending().unwrap(), Some(1));
+        assert!(!rotation.started().unwrap());
+        assert_eq!(rotation.run(&old, &new, &Key::Raw(vec![1; 64])).unwrap(), 1);
+
+        assert_eq!(std::fs::read(&new).unwrap(), data);
+        assert_eq!(rotation.generation().unwrap(), 1);
+        assert_eq!(rotation.pending().unwrap(), None);
+    }
+
+    #[test]
+    fn interrupted_rotation_resumes_at_the_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::Raw(vec![1; 64]);
+        let data = plaintext(2 * (CHUNK_SIZE as usize / SECTOR as usize));
+        let chunk = CHUNK_SIZE as usize;
+
+        // Crashed in the middle of writing the second chunk
+        let mut new = vec![0u8; data.len()];
+        new[..chunk + SECTOR as usize].copy_from_slice(&data[..chunk + SECTOR as usize]);
+        let mut old = data.clone();
+        old[..chunk + SECTOR as usize].fill(0x5a);
+
+        let old = file(&dir, "old", &old);
+        let new = file(&dir, "new", &new);
+        let rotation = KeyRotation::new(dir.path().to_owned());
+        let tags = chunk_tags(&tag_key(&key), CHUNK_SIZE, &data[chunk..], SECTOR);
+        rotation.write_marker(&Marker { target: 1, position: CHUNK_SIZE, sector_size: SECTOR, tags }).unwrap();
+        assert!(rotation.started().unwrap());
+
+        assert_eq!(rotation.run(&old, &new, &key).unwrap(), 1);
+        assert_eq!(std::fs::read(&new).unwrap(), data);
+    }
+
+    #[test]
+    fn committed_rotation_drops_the_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let rotation = KeyRotation::new(dir.path().to_owned());
+        rotation.begin(1, SECTOR).unwrap();
+        rotation.write_atomic(KEY_STATE, br#"{"generation":1}"#).unwrap();
+
+        assert_eq!(rotation.pending().unwrap(), None);
+        assert!(!dir.path().join(MARKER).exists());
+    }
+
+    #[test]
+    fn mismatched_mappings_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = file(&dir, "old", &plaintext(2));
+        let new = file(&dir, "new", &plaintext(3));
+        let rotation = KeyRotation::new(dir.path().to_owned());
+
+        rotation.begin(1, SECTOR).unwrap();
+        assert!(matches!(rotation.run(&old, &new, &Key::Raw(vec![1; 64])), Err(ReencryptError::SizeMismatch(..))));
+
+        let odd = file(&dir, "odd", &vec![0u8; SECTOR as usize + 512]);
+        let odd_new = file(&dir, "odd-new", &vec![0u8; SECTOR as usize + 512]);
+        assert!(matches!(rotation.run(&odd, &odd_new, &Key::Raw(vec![1; 64])), Err(ReencryptError::UnalignedSize(..))));
+        assert_eq!(rotation.generation().unwrap(), 0);
+    }
+}
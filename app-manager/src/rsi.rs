@@ -0,0 +1,88 @@
+use std::{fs::File, os::fd::AsRawFd};
+
+use nix::{ioctl_readwrite, errno::Errno};
+use thiserror::Error;
+
+// Interface of the islet rsi kernel module, see linux-rsi/rsi.h
+
+const RSI_DEVICE: &str = "/dev/rsi";
+
+/// Bind the sealing key to the realm initial measurement
+pub const RSI_SEALING_KEY_FLAGS_RIM: u64 = 1 << 1;
+
+pub const CHALLENGE_LEN: usize = 64;
+pub const MAX_TOKEN_LEN: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum RsiError {
+    #[error("Cannot open {0}, is the rsi module loaded?")]
+    DeviceOpenError(&'static str, #[source] std::io::Error),
+
+    #[error("RSIIO_SEALING_KEY ioctl failed")]
+    SealingKeyError(#[source] Errno),
+
+    #[error("RSIIO_ATTESTATION_TOKEN ioctl failed")]
+    AttestationTokenError(#[source] Errno),
+
+    #[error("RMM returned a token of {0} bytes, more than fits the buffer")]
+    TokenTooLong(u64)
+}
+
+#[repr(C)]
+pub struct RsiSealingKey {
+    pub flags: u64,
+    pub svn: u64,
+    pub realm_sealing_key: [u8; 32]
+}
+
+#[repr(C)]
+pub struct RsiAttestation {
+    pub challenge: [u8; CHALLENGE_LEN],
+    pub token_len: u64,
+    pub token: [u8; MAX_TOKEN_LEN]
+}
+
+ioctl_readwrite!(rsi_attestation_token, b'x', 194, RsiAttestation);
+ioctl_readwrite!(rsi_sealing_key, b'x', 200, RsiSealingKey);
+
+pub struct Rsi {
+    device: File
+}
+
+impl Rsi {
+    pub fn open() -> Result<Self, RsiError> {
+        let device = File::options()
+            .read(true)
+            .write(true)
+            .open(RSI_DEVICE)
+            .map_err(|e| RsiError::DeviceOpenError(RSI_DEVICE, e))?;
+
+        Ok(Self { device })
+    }
+
+    pub fn sealing_key(&self, flags: u64, svn: u64) -> Result<[u8; 32], RsiError> {
+        let mut req = RsiSealingKey { flags, svn, realm_sealing_key: [0u8; 32] };
+
+        unsafe { rsi_sealing_key(self.device.as_raw_fd(), &mut req) }
+            .map_err(RsiError::SealingKeyError)?;
+
+        Ok(req.realm_sealing_key)
+    }
+
+    pub fn attestation_token(&self, challenge: &[u8; CHALLENGE_LEN]) -> Result<Vec<u8>, RsiError> {
+        let mut req = Box::new(RsiAttestation {
+            challenge: *challenge,
+            token_len: 0,
+            token: [0u8; MAX_TOKEN_LEN]
+        });
+
+        unsafe { rsi_attestation_token(self.device.as_raw_fd(), req.as_mut()) }
+            .map_err(RsiError::AttestationTokenError)?;
+
+        if req.token_len > MAX_TOKEN_LEN as u64 {
+            return Err(RsiError::TokenTooLong(req.token_len));
+        }
+
+        Ok(req.token[..req.token_len as usize].to_vec())
+    }
+}
@@ -0,0 +1,13 @@
+// Fixtures shared by the unit tests
+use std::io::{Seek, SeekFrom, Write};
+
+use tempfile::NamedTempFile;
+
+/// Sparse file of `size` bytes with `data` at `offset`, removed once dropped
+pub fn device(size: u64, offset: u64, data: &[u8]) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.as_file().set_len(size).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(data).unwrap();
+    file
+}
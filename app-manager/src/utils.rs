@@ -1,7 +1,6 @@
-use std::{ffi::{c_void, CStr, CString, NulError, OsStr}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process::Command};
+use std::{ffi::{c_void, CStr, CString, NulError, OsStr}, fs::{rename, File}, io::{Read, Seek, SeekFrom, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
 
-use log::debug;
-use nix::{errno::Errno, libc::{c_char, mount}};
+use nix::{errno::Errno, libc::{c_char, mount, umount}};
 use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -12,15 +11,15 @@ use futures_util::SinkExt;
 
 #[derive(Error, Debug)]
 pub enum UtilitiesError {
-    #[error("Error running mkfs.ext2")]
-    MkfsSpawnError(#[source] std::io::Error),
-
-    #[error("Error waiting for mkfs completion")]
-    MkfsCompletionError(#[source] std::io::Error),
+    #[error("Cannot wipe {0:?}")]
+    WipeError(PathBuf, #[source] std::io::Error),
 
     #[error("Mounting error")]
     MountError(#[source] Errno),
 
+    #[error("Cannot unmount {0:?}")]
+    UnmountError(PathBuf, #[source] Errno),
+
     #[error("CString conversion error in {0:?}")]
     CStringConvError(PathBuf, #[source] NulError),
 
@@ -34,38 +33,49 @@ pub enum UtilitiesError {
     SerdeWriteError(#[source] std::io::Error)
 }
 
-pub fn format_ext2(devpath: &Path, label: Option<impl AsRef<str>>) -> Result<(), UtilitiesError> {
-    let mut cmd = Command::new("/bin/mkfs.ext2");
+pub fn random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf)
+}
 
-    if let Some(label) = label.as_ref() {
-        cmd.arg("-L").arg(label.as_ref());
+/// Overwrites the whole device with zeros
+pub fn zero_device(devpath: &Path) -> Result<(), UtilitiesError> {
+    let err = |e| UtilitiesError::WipeError(devpath.to_owned(), e);
+    let mut dev = File::options().write(true).open(devpath).map_err(err)?;
+    let size = dev.seek(SeekFrom::End(0)).map_err(err)?;
+    let zeros = vec![0u8; 1024 * 1024];
+
+    dev.seek(SeekFrom::Start(0)).map_err(err)?;
+    let mut written = 0;
+    while written < size {
+        let len = zeros.len().min((size - written) as usize);
+        dev.write_all(&zeros[..len]).map_err(err)?;
+        written += len as u64;
     }
-    cmd.arg(devpath);
 
-    let mut pid = cmd.spawn().map_err(UtilitiesError::MkfsSpawnError)?;
-    let res = pid.wait().map_err(UtilitiesError::MkfsCompletionError)?;
-    debug!("mkfs.ext2 existed with code: {}", res);
-
-    Ok(())
+    dev.sync_all().map_err(err)
 }
 
-pub fn mount_ext2(devpath: &Path, target: &Path) -> Result<(), UtilitiesError> {
-    let src = CString::new(devpath.as_os_str().as_bytes())
-        .map_err(|e| UtilitiesError::CStringConvError(devpath.to_owned(), e))?;
+pub fn mount_overlay(lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<(), UtilitiesError> {
+    let fs = CString::new("overlay").unwrap();
     let dst = CString::new(target.as_os_str().as_bytes())
         .map_err(|e| UtilitiesError::CStringConvError(target.to_owned(), e))?;
-    let fs = CString::new("ext2").unwrap();
+    let options = format!("lowerdir={},upperdir={},workdir={}",
+        lower.to_string_lossy(), upper.to_string_lossy(), work.to_string_lossy());
+    let opt = CString::new(options).unwrap();
 
     let ret = unsafe {
         mount(
-            src.as_ptr() as *const c_char,
+            fs.as_ptr() as *const c_char,
             dst.as_ptr() as *const c_char,
             fs.as_ptr() as *const c_char,
             0,
-            0 as *const c_void
+            opt.as_ptr() as *const c_void
         )
     };
 
+
     if ret != 0 {
         Err(UtilitiesError::MountError(Errno::last()))
     } else {
@@ -73,32 +83,36 @@ pub fn mount_ext2(devpath: &Path, target: &Path) -> Result<(), UtilitiesError> {
     }
 }
 
-pub fn mount_overlay(lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<(), UtilitiesError> {
-    let fs = CString::new("overlay").unwrap();
+pub fn unmount(target: &Path) -> Result<(), UtilitiesError> {
     let dst = CString::new(target.as_os_str().as_bytes())
         .map_err(|e| UtilitiesError::CStringConvError(target.to_owned(), e))?;
-    let options = format!("lowerdir={},upperdir={},workdir={}",
-        lower.to_string_lossy(), upper.to_string_lossy(), work.to_string_lossy());
-    let opt = CString::new(options).unwrap();
-
-    let ret = unsafe {
-        mount(
-            fs.as_ptr() as *const c_char,
-            dst.as_ptr() as *const c_char,
-            fs.as_ptr() as *const c_char,
-            0,
-            opt.as_ptr() as *const c_void
-        )
-    };
 
+    let ret = unsafe { umount(dst.as_ptr() as *const c_char) };
 
     if ret != 0 {
-        Err(UtilitiesError::MountError(Errno::last()))
+        Err(UtilitiesError::UnmountError(target.to_owned(), Errno::last()))
     } else {
         Ok(())
     }
 }
 
+/// Writes to a temporary file and renames it over `path`, so a crash leaves
+/// either the old or the new content
+pub fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    rename(&tmp, path)?;
+
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(())
+    }
+}
+
 pub async fn serde_read<T: DeserializeOwned + Unpin>(stream: impl AsyncRead + Unpin) -> Result<T, UtilitiesError> {
     let length_delimited = FramedRead::new(stream, LengthDelimitedCodec::new());
     let mut deserialized = SymmetricallyFramed::new(length_delimited, SymmetricalJson::<T>::default());
@@ -0,0 +1,193 @@
+// Drives the host daemon against the fake realm, the real app-manager
+// running as a host process, through the daemon's command socket
+use std::{env, fs, io::{Read, Write}, os::unix::net::UnixStream, path::{Path, PathBuf}, process::{Child, Command, Stdio}, sync::OnceLock, thread::sleep, time::{Duration, Instant}};
+
+use sha2::{Digest, Sha256};
+
+const IMAGE: &str = "00000000-0000-0000-0000-000000000001";
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// The daemon is built once from the vm crate next to this one
+fn vm_binary() -> &'static Path {
+    static VM: OnceLock<PathBuf> = OnceLock::new();
+    VM.get_or_init(|| {
+        if let Some(vm) = env::var_os("VM_BIN") {
+            return vm.into();
+        }
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("../vm/Cargo.toml");
+        let status = Command::new(env::var_os("CARGO").unwrap_or("cargo".into()))
+            .arg("build").arg("--manifest-path").arg(&manifest)
+            .status()
+            .expect("cannot run cargo");
+        assert!(status.success(), "cannot build the vm daemon");
+        manifest.with_file_name("target/debug/vm")
+    })
+}
+
+struct Daemon {
+    dir: PathBuf,
+    process: Child,
+    cli: UnixStream
+}
+
+impl Daemon {
+    // Reference values accept the fake-realm binary and release one image
+    fn start(name: &str) -> Self {
+        let fake_realm = env!("CARGO_BIN_EXE_fake-realm");
+        let dir = env::temp_dir().join(format!("fake-realm-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let measurement = hex::encode(Sha256::digest(fs::read(fake_realm).unwrap()));
+        fs::write(dir.join("image.tar"), (0..100000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap();
+        fs::write(dir.join("values.json"), format!(
+            r#"{{"measurements":["{}"],"images":{{"{}":{{"root_of_trust":"aabbcc","image":"image.tar"}}}}}}"#,
+            measurement, IMAGE
+        )).unwrap();
+
+        let socket = dir.join("cli.sock");
+        let process = Command::new(vm_binary())
+            .arg("-c").arg(&socket)
+            .arg("-w").arg(dir.join("wd"))
+            .arg("-r").arg(dir.join("values.json"))
+            .arg("--accept-mock-tokens")
+            .env("FAKE_REALM_BIN", fake_realm)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::null())
+            .stderr(fs::File::create(dir.join("daemon.log")).unwrap())
+            .spawn()
+            .expect("cannot start the vm daemon");
+
+        let start = Instant::now();
+        let cli = loop {
+            match UnixStream::connect(&socket) {
+                Ok(cli) => break cli,
+                Err(_) if start.elapsed() < TIMEOUT => sleep(Duration::from_millis(100)),
+                Err(e) => panic!("daemon is not listening: {}", e)
+            }
+        };
+        cli.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        let mut daemon = Self { dir, process, cli };
+        daemon.prompt();
+        daemon
+    }
+
+    fn prompt(&mut self) -> String {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !out.ends_with(b"> ") {
+            let len = self.cli.read(&mut buf).expect("no response from the daemon");
+            assert!(len > 0, "daemon closed the connection");
+            out.extend_from_slice(&buf[..len]);
+        }
+        String::from_utf8_lossy(&out[..out.len() - 2]).trim().to_owned()
+    }
+
+    fn run(&mut self, cmd: &str) -> String {
+        writeln!(self.cli, "vm {}", cmd).unwrap();
+        self.prompt()
+    }
+
+    fn expect(&mut self, cmd: &str, expected: &str) -> String {
+        let resp = self.run(cmd);
+        assert!(resp.contains(expected), "{}: expected {:?}, got {:?}\n{}", cmd, expected, resp, self.logs());
+        resp
+    }
+
+    // The realm provisions in the background after launching
+    fn wait_status(&mut self, app: &str, realm: &str, expected: &str) {
+        let start = Instant::now();
+        loop {
+            let resp = self.run(&format!("app-status -i {} -r {}", app, realm));
+            if resp.contains(expected) {
+                return;
+            }
+            assert!(start.elapsed() < TIMEOUT, "{} never became {:?}, last {:?}\n{}", app, expected, resp, self.logs());
+            sleep(Duration::from_millis(200));
+        }
+    }
+
+    // Shutdown only asks the realm to stop, it may still be exiting
+    fn launch(&mut self, realm: &str) {
+        let start = Instant::now();
+        loop {
+            let resp = self.run(&format!("launch-realm -i {}", realm));
+            if !resp.contains("RealmAlreadyRunning") || start.elapsed() > TIMEOUT {
+                assert!(resp.contains("RealmLaunched"), "launch-realm -i {}: got {:?}\n{}", realm, resp, self.logs());
+                return;
+            }
+            sleep(Duration::from_millis(200));
+        }
+    }
+
+    fn create(&mut self, realm: &str, app: &str) {
+        self.expect(&format!("create-realm -i {} -b fake -k /dev/null -v 10 --kernel-param app_manager.image_release=verifier", realm), "RealmCreated");
+        self.expect(&format!("create-application -i {} -r {} -m 64 -s 16 -p {}", app, realm, IMAGE), "ApplicationCreated");
+    }
+
+    fn logs(&self) -> String {
+        let console = fs::read_dir(self.dir.join("wd")).into_iter().flatten().flatten()
+            .filter_map(|realm| fs::read_to_string(realm.path().join("console.log")).ok())
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("daemon:\n{}\nrealm:\n{}", fs::read_to_string(self.dir.join("daemon.log")).unwrap_or_default(), console)
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        if !std::thread::panicking() {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+#[test]
+fn provision_launch_and_stop() {
+    let mut daemon = Daemon::start("lifecycle");
+    daemon.create("r0", "a0");
+
+    daemon.launch("r0");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+
+    daemon.expect("terminate-app -i a0 -r r0", "stopped gracefully");
+    daemon.expect("start-app -i a0 -r r0", "ApplicationStarted");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("terminate-app -i a0 -r r0 -g 0", "killed after the grace period");
+
+    daemon.expect("shutdown -i r0", "RealmExited");
+}
+
+#[test]
+fn relaunch_keeps_provisioned_application() {
+    let mut daemon = Daemon::start("relaunch");
+    daemon.create("r0", "a0");
+
+    daemon.launch("r0");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("shutdown -i r0", "RealmExited");
+
+    daemon.launch("r0");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("shutdown -i r0", "RealmExited");
+}
+
+#[test]
+fn rotate_key_relaunches_application() {
+    let mut daemon = Daemon::start("rotate");
+    daemon.create("r0", "a0");
+
+    daemon.launch("r0");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("rotate-key -i a0 -r r0", "KeyRotated");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("shutdown -i r0", "RealmExited");
+
+    // The secure storage opens under the new key
+    daemon.launch("r0");
+    daemon.wait_status("a0", "r0", "ApplicationStatus: Running");
+    daemon.expect("shutdown -i r0", "RealmExited");
+}
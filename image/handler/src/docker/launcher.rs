@@ -1,12 +1,12 @@
-use std::{env::set_current_dir, ffi::OsString, os::unix::fs::chroot, path::PathBuf, process::{ExitCode, ExitStatus, Stdio}};
+use std::{env::set_current_dir, ffi::OsString, future::pending, os::unix::fs::chroot, path::PathBuf, process::{ExitCode, ExitStatus, Stdio}, str::FromStr, sync::{Arc, Mutex}, time::Duration};
 
 use async_trait::async_trait;
 use nix::{errno::Errno, sys::{self, signal::{self, Signal}}, unistd::{getgid, getuid, setgid, setuid, Gid, Group, Pid, Uid, User}};
 use thiserror::Error;
-use tokio::{io::{AsyncBufReadExt, AsyncReadExt, BufReader}, process::{Child, Command}, select, sync::mpsc::{self, channel, Receiver, Sender}, task};
-use log::info;
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, BufReader}, process::{Child, Command}, select, sync::mpsc::{self, channel, Receiver, Sender}, task, time::{sleep_until, Instant}};
+use log::{info, warn};
 
-use crate::{docker::manifests::UserConfig, ImageError};
+use crate::{docker::manifests::UserConfig, ImageError, StopStatus};
 
 use super::manifests::{ContainerConfig, Id};
 
@@ -40,7 +40,10 @@ pub enum LauncherError {
     ChannelClosed(),
 
     #[error("Application is not running")]
-    AppNotRunning()
+    AppNotRunning(),
+
+    #[error("Unknown stop signal {0}")]
+    InvalidStopSignal(String)
 }
 
 type Result<V> = std::result::Result<V, LauncherError>;
@@ -52,24 +55,50 @@ impl From<LauncherError> for ImageError {
 }
 
 enum Request {
-    Stop,
+    Stop(Duration),
     Kill,
     Wait
 }
 
 enum Response {
-    Status(ExitStatus)
+    Status(StopStatus)
 }
 
+// Accepts `SIGQUIT`, `QUIT` and `3` like docker does
+fn parse_signal(name: &str) -> Result<Signal> {
+    let err = || LauncherError::InvalidStopSignal(name.to_owned());
+
+    if let Ok(num) = name.parse::<i32>() {
+        return Signal::try_from(num).map_err(|_| err());
+    }
+
+    let name = name.to_uppercase();
+    match name.starts_with("SIG") {
+        true => Signal::from_str(&name),
+        false => Signal::from_str(&format!("SIG{}", name))
+    }.map_err(|_| err())
+}
+
+async fn sleep_until_opt(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => pending().await
+    }
+}
+
+// Written by the handler before it drops its end of the channels
+type RecordedStatus = Arc<Mutex<Option<ExitStatus>>>;
+
 pub struct Launcher {
     rootfs: PathBuf,
     conf: ContainerConfig,
     txrx: Option<(Sender<Request>, Receiver<Response>)>,
+    exit_status: RecordedStatus
 }
 
 impl Launcher {
     pub fn new(rootfs: PathBuf, config: ContainerConfig) -> Launcher {
-        Self { rootfs, conf: config, txrx: None }
+        Self { rootfs, conf: config, txrx: None, exit_status: RecordedStatus::default() }
     }
 
     fn env(&self) -> &Vec<String> {
@@ -112,7 +141,14 @@ impl Launcher {
         }
     }
 
-    async fn handler(mut process: Child, mut tx: Sender<Response>, mut rx: Receiver<Request>) -> Result<()> {
+    fn stop_signal(&self) -> Result<Signal> {
+        self.conf.config.stop_signal.as_deref()
+            .map_or(Ok(Signal::SIGTERM), parse_signal)
+    }
+
+    // Output is drained while a stop request waits for the process, so an
+    // application logging on its way out doesn't block on a full pipe
+    async fn handler(mut process: Child, stop_signal: Signal, mut tx: Sender<Response>, mut rx: Receiver<Request>, exit_status: RecordedStatus) -> Result<ExitStatus> {
         let mut stdout = BufReader::new(process.stdout.take().unwrap());
         let mut stderr = BufReader::new(process.stderr.take().unwrap());
 
@@ -124,25 +160,40 @@ impl Launcher {
 
         let pid = Pid::from_raw(process.id().unwrap() as i32);
 
+        // Set once a request waits for the process to exit
+        let mut waiting = false;
+        let mut deadline = None;
+        let mut killed = false;
+
         loop {
             select! {
-                r = rx.recv() => {
-                    if let Some(req) = r {
-                        match req {
-                            Request::Stop => {
-                                signal::kill(pid, Signal::SIGTERM).map_err(LauncherError::StopError)?;
-                            },
-                            Request::Kill => {
-                                signal::kill(pid, Signal::SIGKILL).map_err(LauncherError::StopError)?;
-                            },
-                            Request::Wait => {}
+                r = rx.recv(), if !waiting => {
+                    match r {
+                        Some(Request::Stop(grace)) => {
+                            signal::kill(pid, stop_signal).map_err(LauncherError::StopError)?;
+                            deadline = Some(Instant::now() + grace);
+                        },
+                        Some(Request::Kill) => {
+                            signal::kill(pid, Signal::SIGKILL).map_err(LauncherError::StopError)?;
+                            killed = true;
+                        },
+                        Some(Request::Wait) => {},
+
+                        // The launcher is gone, nobody is left to stop the application
+                        None => {
+                            let status = process.wait().await.map_err(LauncherError::WaitpidError)?;
+                            *exit_status.lock().unwrap() = Some(status);
+                            break Ok(status);
                         }
-
-                        let status = process.wait().await.map_err(LauncherError::WaitpidError)?;
-                        tx.send(Response::Status(status)).await.map_err(LauncherError::ResponseChannelError)?;
                     }
 
-                    break;
+                    waiting = true;
+                }
+
+                _ = sleep_until_opt(deadline), if !killed => {
+                    warn!("Application didn't stop within the grace period, killing it");
+                    signal::kill(pid, Signal::SIGKILL).map_err(LauncherError::StopError)?;
+                    killed = true;
                 }
 
                 v = stdout.read_line(&mut stdout_line), if stdout_open => {
@@ -164,30 +215,44 @@ impl Launcher {
                 }
 
                 v = process.wait() => {
-                    let result = v.map_err(LauncherError::WaitpidError)?;
-                    info!("Application exited with {:?}", result);
-                    break;
+                    let status = v.map_err(LauncherError::WaitpidError)?;
+                    info!("Application exited with {:?}", status);
+                    *exit_status.lock().unwrap() = Some(status);
+
+                    if waiting {
+                        tx.send(Response::Status(StopStatus { status, killed, exited_before: false })).await.map_err(LauncherError::ResponseChannelError)?;
+                    }
+
+                    break Ok(status);
                 }
             }
         }
-
-        Ok(())
     }
 
-    async fn send_request(&mut self, req: Request) -> crate::Result<ExitStatus> {
-        if let Some((tx, rx)) = self.txrx.as_mut() {
-            tx.send(req).await.map_err(LauncherError::RequestChannelError)?;
-            let resp = rx.recv().await.ok_or(LauncherError::ChannelClosed())?;
-            Ok(match resp { Response::Status(s) => s })
-        } else {
-            Err(LauncherError::AppNotRunning().into())
+    // The handler ends with the process, an application that exited before
+    // the request was picked up reports the status the handler recorded
+    async fn send_request(&mut self, req: Request) -> crate::Result<StopStatus> {
+        let (tx, rx) = self.txrx.as_mut().ok_or(LauncherError::AppNotRunning())?;
+
+        let resp = match tx.send(req).await {
+            Ok(()) => rx.recv().await,
+            Err(_) => None
+        };
+
+        if let Some(Response::Status(status)) = resp {
+            return Ok(status);
+        }
+
+        match *self.exit_status.lock().unwrap() {
+            Some(status) => Ok(StopStatus { status, killed: false, exited_before: true }),
+            None => Err(LauncherError::ChannelClosed().into())
         }
     }
 }
 
 #[async_trait]
 impl crate::Launcher for Launcher {
-    fn launch(&mut self, disk_path: &PathBuf) -> crate::Result<tokio::task::JoinHandle<crate::Result<()>>> {
+    fn launch(&mut self, disk_path: &PathBuf) -> crate::Result<tokio::task::JoinHandle<crate::Result<ExitStatus>>> {
         let env = self.env();
         let argv = self.argv();
 
@@ -233,6 +298,7 @@ impl crate::Launcher for Launcher {
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
 
+        let stop_signal = self.stop_signal()?;
         let process = cmd.spawn()
             .map_err(LauncherError::SpawnError)?;
 
@@ -240,21 +306,23 @@ impl crate::Launcher for Launcher {
         let (tx2, rx2) = channel(1);
 
         self.txrx = Some((tx1, rx2));
+        self.exit_status = RecordedStatus::default();
+        let exit_status = self.exit_status.clone();
 
         Ok(task::spawn(async move {
-            Ok(Self::handler(process, tx2, rx1).await?)
+            Ok(Self::handler(process, stop_signal, tx2, rx1, exit_status).await?)
         }))
     }
 
-    async fn stop(&mut self) -> crate::Result<ExitStatus> {
-        self.send_request(Request::Stop).await
+    async fn stop(&mut self, grace: Duration) -> crate::Result<StopStatus> {
+        self.send_request(Request::Stop(grace)).await
     }
 
     async fn kill(&mut self) -> crate::Result<ExitStatus> {
-        self.send_request(Request::Kill).await
+        Ok(self.send_request(Request::Kill).await?.status)
     }
 
     async fn wait(&mut self) -> crate::Result<ExitStatus> {
-        self.send_request(Request::Wait).await
+        Ok(self.send_request(Request::Wait).await?.status)
     }
 }
@@ -181,7 +181,11 @@ pub struct LaunchConfig {
     pub args_escaped: bool,
 
     #[serde(rename = "OnBuild")]
-    pub on_build: Option<String>
+    pub on_build: Option<String>,
+
+    /// Signal name like `SIGQUIT` or its number, SIGTERM if not set
+    #[serde(rename = "StopSignal")]
+    pub stop_signal: Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
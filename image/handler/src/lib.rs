@@ -12,6 +12,7 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::process::ExitCode;
 use std::process::ExitStatus;
+use std::time::Duration;
 
 use async_trait::async_trait;
 pub use hasher::Hasher;
@@ -41,17 +42,33 @@ pub type Result<T> = std::result::Result<T, ImageError>;
 
 
 #[async_trait]
-pub trait InstallerTrait {
+pub trait InstallerTrait: Send + Sync {
     async fn install(&self, rot: Box<[u8]>, image: Box<dyn AsyncRead + Unpin + Send>) -> Result<Box<dyn Launcher>>;
     async fn validate(&self) -> Result<Box<dyn Launcher>>;
 }
 
 
 
+/// How `Launcher::stop` got the application down
+#[derive(Debug, Clone, Copy)]
+pub struct StopStatus {
+    pub status: ExitStatus,
+
+    /// The grace period ran out and the application was killed
+    pub killed: bool,
+
+    /// The application had exited on its own before the request
+    pub exited_before: bool
+}
+
 #[async_trait]
-pub trait Launcher {
-    fn launch(&mut self, disk_path: &PathBuf) -> Result<JoinHandle<Result<()>>>;
-    async fn stop(&mut self) -> Result<ExitStatus>;
+pub trait Launcher: Send + Sync {
+    /// The task ends with the exit status once the application is gone
+    fn launch(&mut self, disk_path: &PathBuf) -> Result<JoinHandle<Result<ExitStatus>>>;
+
+    /// Sends the stop signal of the image and kills the application if it
+    /// is still there after `grace`
+    async fn stop(&mut self, grace: Duration) -> Result<StopStatus>;
     async fn kill(&mut self) -> Result<ExitStatus>;
     async fn wait(&mut self) -> Result<ExitStatus>;
 }
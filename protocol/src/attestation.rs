@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// Size of the challenge a CCA attestation token is bound to, shorter
+/// challenges are zero padded
+pub const CHALLENGE_LEN: usize = 64;
+
+/// Token issued by the mock attestation backend where there's no RMM. It
+/// carries the challenge and a hash of the binary standing in for the realm
+/// so verifiers have something to compare against reference values.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MockToken {
+    pub challenge: Vec<u8>,
+    pub measurement: Vec<u8>
+}
+
+/// Zero pads the challenge to `CHALLENGE_LEN`, `None` if it's too long
+pub fn pad_challenge(challenge: &[u8]) -> Option<[u8; CHALLENGE_LEN]> {
+    if challenge.len() > CHALLENGE_LEN {
+        return None;
+    }
+
+    let mut padded = [0u8; CHALLENGE_LEN];
+    padded[..challenge.len()].copy_from_slice(challenge);
+    Some(padded)
+}
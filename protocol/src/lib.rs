@@ -1,7 +1,16 @@
 mod protocol;
+pub mod attestation;
+pub mod transport;
 
 pub use protocol::ApplicationInfo;
+pub use protocol::Filesystem;
+pub use protocol::StorageFilesystem;
 pub use protocol::RealmInfo;
 pub use protocol::ProvisionInfo;
+pub use protocol::ProvisionRequest;
+pub use protocol::ProvisionResponse;
 pub use protocol::Command;
 pub use protocol::Response;
+pub use protocol::AppStatus;
+pub use protocol::ProvisionStage;
+pub use protocol::StopPath;
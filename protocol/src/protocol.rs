@@ -1,11 +1,43 @@
-use std::{collections::HashMap, os::unix::process::ExitStatusExt, process::ExitStatus};
+use std::{collections::HashMap, fmt::Display, os::unix::process::ExitStatusExt, process::ExitStatus, str::FromStr};
 
+use thiserror::Error;
 use uuid::Uuid;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+#[derive(Error, Debug)]
+#[error("Unknown filesystem `{0}`, expected ext4, xfs, btrfs, erofs or squashfs")]
+pub struct UnknownFilesystem(String);
+
+/// Filesystem the app-manager creates on an application storage, erofs and
+/// squashfs are read-only and only fit the image on the main storage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+    Erofs,
+    Squashfs
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StorageFilesystem {
+    #[serde(default)]
+    pub filesystem: Filesystem,
+
+    /// Filesystem specific options passed to mount, e.g. `compress=zstd`
+    #[serde(default)]
+    pub mount_options: Option<String>
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProvisionInfo {
-    pub uuid: Uuid
+    pub uuid: Uuid,
+
+    /// Wipe storage that already holds a provisioned application instead of keeping it
+    #[serde(default)]
+    pub reformat: bool
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -13,19 +45,81 @@ pub struct ApplicationInfo {
     pub main_partition_uuid: Uuid,
     pub secure_partition_uuid: Uuid,
 
-    pub provision_info: Option<ProvisionInfo>
+    /// Registry image the application runs, its root of trust is mixed into the storage keys
+    pub image_uuid: Option<Uuid>,
+
+    pub provision_info: Option<ProvisionInfo>,
+
+    /// Filesystem of the installed image, chosen when it is installed
+    #[serde(default)]
+    pub image_fs: StorageFilesystem,
+
+    /// Filesystem of the secure storage, chosen when it is provisioned
+    #[serde(default)]
+    pub secure_fs: StorageFilesystem
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RealmInfo {
-    pub apps: HashMap<String, ApplicationInfo>
+    pub apps: HashMap<String, ApplicationInfo>,
+
+    /// App-manager settings chosen by the host, named like the `app_manager.*`
+    /// kernel parameters and applied on top of them
+    #[serde(default)]
+    pub config: HashMap<String, String>
+}
+
+/// Sent by the realm while provisioning, the host answers the requests for
+/// data with a `ProvisionResponse` and starts sending commands after `Done`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ProvisionRequest {
+    /// Configuration the app-manager ended up with, as YAML
+    EffectiveConfig(String),
+
+    /// Nonce to bind the attestation token for an image release to
+    ImageNonce(Uuid),
+
+    /// Evidence in exchange for the root of trust of an image
+    ReleaseImage { uuid: Uuid, token: Vec<u8> },
+
+    /// Part of a released image starting at `offset`, an empty chunk ends it
+    ImageChunk { uuid: Uuid, offset: u64 },
+
+    Done()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ProvisionResponse {
+    Nonce(Vec<u8>),
+    RootOfTrust(Vec<u8>),
+    ImageChunk(Vec<u8>),
+    Denied(String)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     StartApp(String),
-    TerminateApp(String),
+
+    /// Sends the stop signal of the image, the application is killed once
+    /// `grace` seconds pass, the realm's default is used if not given
+    TerminateApp {
+        app: String,
+
+        #[serde(default)]
+        grace: Option<u64>
+    },
+
     KillApp(String),
+    RotateKey(String),
+
+    /// Moves the application to another registry image, keeping its secure storage
+    UpdateApp { app: String, image_uuid: Uuid },
+
+    /// Provisions an application that failed during boot once more
+    RetryProvision(String),
+    AppStatus(String),
+
+    Attest { challenge: Vec<u8> },
     Shutdown()
 }
 
@@ -35,7 +129,121 @@ pub enum Response {
 
     #[serde(serialize_with = "serialize_exit_status")]
     #[serde(deserialize_with = "deserialize_exit_status")]
-    ExitStatus(ExitStatus)
+    ExitStatus(ExitStatus),
+
+    /// How `TerminateApp` brought the application down
+    Terminated {
+        #[serde(serialize_with = "serialize_exit_status")]
+        #[serde(deserialize_with = "deserialize_exit_status")]
+        status: ExitStatus,
+
+        stop: StopPath
+    },
+
+    AttestationToken(Vec<u8>),
+    AppStatus(AppStatus),
+
+    /// The command failed, the realm keeps running
+    Error(String)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum StopPath {
+    /// Exited within the grace period after its stop signal
+    Graceful,
+
+    /// Still running when the grace period ran out and got SIGKILL
+    Killed,
+
+    /// Had exited before, its recorded status is returned
+    AlreadyExited
+}
+
+/// Steps an application goes through while the realm boots
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ProvisionStage {
+    RootOfTrust,
+    MainStorage,
+    Image,
+    SecureStorage,
+    Overlay,
+    Launch
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AppStatus {
+    /// Provisioned, the application can be started
+    Ready,
+    Running,
+
+    /// The application process ended, it can be started again
+    #[serde(serialize_with = "serialize_exit_status")]
+    #[serde(deserialize_with = "deserialize_exit_status")]
+    Exited(ExitStatus),
+
+    /// Provisioning stopped at `stage`, the other applications are not affected
+    Failed { stage: ProvisionStage, error: String }
+}
+
+impl Display for ProvisionStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProvisionStage::RootOfTrust => write!(f, "root of trust"),
+            ProvisionStage::MainStorage => write!(f, "main storage"),
+            ProvisionStage::Image => write!(f, "image"),
+            ProvisionStage::SecureStorage => write!(f, "secure storage"),
+            ProvisionStage::Overlay => write!(f, "overlay"),
+            ProvisionStage::Launch => write!(f, "launch")
+        }
+    }
+}
+
+impl Display for StopPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopPath::Graceful => write!(f, "stopped gracefully"),
+            StopPath::Killed => write!(f, "killed after the grace period"),
+            StopPath::AlreadyExited => write!(f, "had already exited")
+        }
+    }
+}
+
+impl Display for AppStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppStatus::Ready => write!(f, "Ready"),
+            AppStatus::Running => write!(f, "Running"),
+            AppStatus::Exited(status) => write!(f, "Exited with {}", status),
+            AppStatus::Failed { stage, error } => write!(f, "Failed at {}: {}", stage, error)
+        }
+    }
+}
+
+impl Display for Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filesystem::Ext4 => write!(f, "ext4"),
+            Filesystem::Xfs => write!(f, "xfs"),
+            Filesystem::Btrfs => write!(f, "btrfs"),
+            Filesystem::Erofs => write!(f, "erofs"),
+            Filesystem::Squashfs => write!(f, "squashfs")
+        }
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = UnknownFilesystem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ext4" => Ok(Filesystem::Ext4),
+            "xfs" => Ok(Filesystem::Xfs),
+            "btrfs" => Ok(Filesystem::Btrfs),
+            "erofs" => Ok(Filesystem::Erofs),
+            "squashfs" => Ok(Filesystem::Squashfs),
+            _ => Err(UnknownFilesystem(s.to_owned()))
+        }
+    }
 }
 
 fn serialize_exit_status<S: Serializer>(status: &ExitStatus, s: S) -> Result<S::Ok, S::Error> {
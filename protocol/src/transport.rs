@@ -0,0 +1,229 @@
+use std::{fmt::Display, fs::{read_dir, read_to_string, remove_file}, net::SocketAddr, path::{Path, PathBuf}, str::FromStr};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use tokio::{fs::OpenOptions, io::{AsyncRead, AsyncWrite}, net::{TcpListener, TcpStream, UnixListener, UnixStream}};
+use tokio_vsock::{VsockAddr, VsockStream, VMADDR_CID_HOST};
+
+/// Name of the virtio-serial port the host exposes to the realm
+pub const SERIAL_PORT_NAME: &str = "app-manager";
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("Invalid transport `{0}`, expected vsock:<port>, serial:<name>, unix:<path> or tcp:<addr>")]
+    InvalidTransport(String),
+
+    #[error("Failed to connect to host over {0}")]
+    ConnectionFailed(String, #[source] std::io::Error),
+
+    #[error("Virtio serial port `{0}` not found")]
+    SerialPortNotFound(String),
+
+    #[error("Cannot bind realm channel on {0}")]
+    BindError(String, #[source] std::io::Error),
+
+    #[error("Realm connection accept error")]
+    AcceptError(#[source] std::io::Error)
+}
+
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Realm end of a transport
+#[async_trait]
+pub trait Connector: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn Stream>, TransportError>;
+}
+
+/// Host end of a transport, bound before the realm starts so the realm can
+/// connect right away
+#[async_trait]
+pub trait Acceptor: Send {
+    /// Waits for the realm to connect
+    async fn accept(&mut self) -> Result<Box<dyn Stream>, TransportError>;
+}
+
+/// Channel used by the realm to reach the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// vsock port on the host cid
+    Vsock(u32),
+
+    /// Named virtio-serial port, the host end is a unix socket
+    Serial(String),
+
+    /// Unix socket, for realms running as host processes
+    Unix(PathBuf),
+
+    /// TCP address of the host. Nothing authenticates the connection, so
+    /// this is only meant for testing on a trusted network.
+    Tcp(SocketAddr)
+}
+
+impl Transport {
+    pub fn connector(&self) -> Box<dyn Connector> {
+        match self {
+            Transport::Vsock(port) => Box::new(VsockConnector(*port)),
+            Transport::Serial(name) => Box::new(SerialConnector(name.clone())),
+            Transport::Unix(path) => Box::new(UnixConnector(path.clone())),
+            Transport::Tcp(addr) => Box::new(TcpConnector(*addr))
+        }
+    }
+}
+
+pub struct VsockConnector(pub u32);
+
+#[async_trait]
+impl Connector for VsockConnector {
+    async fn connect(&self) -> Result<Box<dyn Stream>, TransportError> {
+        let stream = VsockStream::connect(VsockAddr::new(VMADDR_CID_HOST, self.0)).await
+            .map_err(|e| TransportError::ConnectionFailed(Transport::Vsock(self.0).to_string(), e))?;
+        Ok(Box::new(stream))
+    }
+}
+
+pub struct SerialConnector(pub String);
+
+#[async_trait]
+impl Connector for SerialConnector {
+    async fn connect(&self) -> Result<Box<dyn Stream>, TransportError> {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(serial_port_path(&self.0)?)
+            .await
+            .map_err(|e| TransportError::ConnectionFailed(Transport::Serial(self.0.clone()).to_string(), e))?;
+        Ok(Box::new(port))
+    }
+}
+
+pub struct UnixConnector(pub PathBuf);
+
+#[async_trait]
+impl Connector for UnixConnector {
+    async fn connect(&self) -> Result<Box<dyn Stream>, TransportError> {
+        let stream = UnixStream::connect(&self.0).await
+            .map_err(|e| TransportError::ConnectionFailed(Transport::Unix(self.0.clone()).to_string(), e))?;
+        Ok(Box::new(stream))
+    }
+}
+
+pub struct TcpConnector(pub SocketAddr);
+
+#[async_trait]
+impl Connector for TcpConnector {
+    async fn connect(&self) -> Result<Box<dyn Stream>, TransportError> {
+        let stream = TcpStream::connect(self.0).await
+            .map_err(|e| TransportError::ConnectionFailed(Transport::Tcp(self.0).to_string(), e))?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Host end of the serial and unix transports
+pub struct UnixAcceptor(UnixListener);
+
+impl UnixAcceptor {
+    /// Replaces a socket left behind by a previous run
+    pub fn bind(path: &Path) -> Result<Self, TransportError> {
+        let err = |e| TransportError::BindError(path.to_string_lossy().into_owned(), e);
+
+        if path.exists() {
+            remove_file(path).map_err(err)?;
+        }
+
+        Ok(Self(UnixListener::bind(path).map_err(err)?))
+    }
+}
+
+#[async_trait]
+impl Acceptor for UnixAcceptor {
+    async fn accept(&mut self) -> Result<Box<dyn Stream>, TransportError> {
+        let (stream, _) = self.0.accept().await.map_err(TransportError::AcceptError)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Host end of the tcp transport, takes the first connection to `addr`
+pub struct TcpAcceptor(TcpListener);
+
+impl TcpAcceptor {
+    pub fn bind(addr: SocketAddr) -> Result<Self, TransportError> {
+        let err = |e| TransportError::BindError(addr.to_string(), e);
+
+        warn!("Realm channel on {} is not authenticated, the tcp transport is for testing only", addr);
+
+        let listener = std::net::TcpListener::bind(addr).map_err(err)?;
+        listener.set_nonblocking(true).map_err(err)?;
+
+        Ok(Self(TcpListener::from_std(listener).map_err(err)?))
+    }
+}
+
+#[async_trait]
+impl Acceptor for TcpAcceptor {
+    async fn accept(&mut self) -> Result<Box<dyn Stream>, TransportError> {
+        let (stream, peer) = self.0.accept().await.map_err(TransportError::AcceptError)?;
+        info!("Realm connected from {}", peer);
+        Ok(Box::new(stream))
+    }
+}
+
+// Without udev there is no /dev/virtio-ports/<name> symlink, so look the
+// port up by name in sysfs.
+fn serial_port_path(name: &str) -> Result<PathBuf, TransportError> {
+    let ports = read_dir("/sys/class/virtio-ports")
+        .map_err(|_| TransportError::SerialPortNotFound(name.to_owned()))?;
+
+    for port in ports.flatten() {
+        if let Ok(port_name) = read_to_string(port.path().join("name")) {
+            if port_name.trim() == name {
+                return Ok(Path::new("/dev").join(port.file_name()));
+            }
+        }
+    }
+
+    Err(TransportError::SerialPortNotFound(name.to_owned()))
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Vsock(port) => write!(f, "vsock:{}", port),
+            Transport::Serial(name) => write!(f, "serial:{}", name),
+            Transport::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
+            Transport::Tcp(addr) => write!(f, "tcp:{}", addr)
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = TransportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransportError::InvalidTransport(s.to_owned());
+        let (ty, val) = s.split_once(':').ok_or_else(invalid)?;
+
+        match ty {
+            "vsock" => Ok(Transport::Vsock(val.parse().map_err(|_| invalid())?)),
+            "serial" => Ok(Transport::Serial(val.to_owned())),
+            "unix" => Ok(Transport::Unix(PathBuf::from(val))),
+            "tcp" => Ok(Transport::Tcp(val.parse().map_err(|_| invalid())?)),
+            _ => Err(invalid())
+        }
+    }
+}
+
+impl Serialize for Transport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string().as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Transport {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = String::deserialize(deserializer)?;
+        v.parse().map_err(D::Error::custom)
+    }
+}
@@ -1,11 +1,13 @@
-use std::{fs::create_dir, path::PathBuf};
+use std::{fs::create_dir, path::{Path, PathBuf}};
 
+use log::info;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::task::JoinError;
 use uuid::Uuid;
 
-use crate::{qdisk::{QEMUDisk, QEMUDiskError}, qemu::VMBuilder};
-use protocol::{ApplicationInfo, ProvisionInfo};
+use crate::{bundle::{checksum, write_bundle, BundleError, BundleManifest, BundledDisk}, qdisk::{DiskFormat, QEMUDisk, QEMUDiskError}, vmm::VMBuilder};
+use protocol::{ApplicationInfo, ProvisionInfo, StorageFilesystem};
 
 #[derive(Error, Debug)]
 pub enum ApplicationError {
@@ -19,14 +21,34 @@ pub enum ApplicationError {
     JoinError(#[from] JoinError),
 
     #[error("Path decoding error {0}")]
-    PathDecodingError(PathBuf)
+    PathDecodingError(PathBuf),
+
+    #[error("Application bundle error")]
+    BundleError(#[from] BundleError),
+
+    #[error("Applications on a backing file can't be exported")]
+    BackingFileExport(),
+
+    #[error("Bundled disk {0} doesn't match the application config")]
+    UnexpectedBundledDisk(String),
+
+    #[error("Partition GUID of {0:?} changed, expected {1} got {2}")]
+    PartitionUuidMismatch(PathBuf, Uuid, Uuid)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApplicationConfig {
     pub main_storage_size_mb: usize,
     pub secure_storage_size_mb: usize,
-    pub provision_from: Option<Uuid>
+    pub provision_from: Option<Uuid>,
+    pub disk_format: DiskFormat,
+    pub main_storage_backing: Option<PathBuf>,
+    #[serde(default)]
+    pub provisioned: bool,
+    #[serde(default)]
+    pub image_fs: StorageFilesystem,
+    #[serde(default)]
+    pub secure_fs: StorageFilesystem
 }
 
 #[derive(Debug)]
@@ -44,14 +66,17 @@ impl Application {
                 .map_err(ApplicationError::WorkdirMkdirFail)?;
         }
 
-        let main_storage_path = workdir.join("main.raw");
+        let format = config.disk_format;
+
+        let main_storage_path = Self::disk_path(&workdir, "main", format);
+        let main_storage_backing = config.main_storage_backing.clone();
         let main_storage = tokio::task::spawn_blocking(move || {
-            QEMUDisk::new(main_storage_path, config.main_storage_size_mb)
+            QEMUDisk::new(main_storage_path, format, config.main_storage_size_mb, main_storage_backing)
         });
 
-        let secure_storage_path = workdir.join("secure.raw");
+        let secure_storage_path = Self::disk_path(&workdir, "secure", format);
         let secure_storage = tokio::task::spawn_blocking(move || {
-            QEMUDisk::new(secure_storage_path, config.secure_storage_size_mb)
+            QEMUDisk::new(secure_storage_path, format, config.secure_storage_size_mb, None)
         });
 
         Ok(Self {
@@ -62,27 +87,120 @@ impl Application {
         })
     }
 
+    /// Registers storage unpacked from a bundle, the disks are opened as is
+    /// so the partition GUIDs the app-manager looks them up by are kept.
+    pub async fn import(workdir: PathBuf, manifest: BundleManifest) -> Result<Self, ApplicationError> {
+        let mut config = manifest.config;
+        let format = config.disk_format;
+
+        for (name, disk) in [("main", &manifest.main_storage), ("secure", &manifest.secure_storage)] {
+            if Self::disk_path(&workdir, name, format) != workdir.join(&disk.file) {
+                return Err(ApplicationError::UnexpectedBundledDisk(disk.file.clone()));
+            }
+        }
+
+        // Provisioning would format the storage that was just imported, the
+        // image uuid is still needed by the app-manager to derive the keys
+        if config.provision_from.is_some() && !config.provisioned {
+            info!("Imported application {} is already provisioned", manifest.id);
+            config.provisioned = true;
+        }
+
+        let app = Self::new(workdir, config).await?;
+
+        for (disk, bundled) in [(&app.main_storage, &manifest.main_storage), (&app.secure_storage, &manifest.secure_storage)] {
+            if disk.part_uuid() != &bundled.part_uuid {
+                return Err(ApplicationError::PartitionUuidMismatch(disk.path().clone(), bundled.part_uuid, *disk.part_uuid()));
+            }
+        }
+
+        Ok(app)
+    }
+
+    pub async fn export(&self, id: &str, path: &Path) -> Result<(), ApplicationError> {
+        // The base image is not part of the bundle
+        if self.config.main_storage_backing.is_some() {
+            return Err(ApplicationError::BackingFileExport());
+        }
+
+        let manifest = BundleManifest {
+            id: id.to_owned(),
+            config: self.config.clone(),
+            main_storage: Self::bundled_disk(&self.main_storage).await?,
+            secure_storage: Self::bundled_disk(&self.secure_storage).await?
+        };
+
+        write_bundle(path, &manifest, &self.workdir).await?;
+
+        Ok(())
+    }
+
+    async fn bundled_disk(disk: &QEMUDisk) -> Result<BundledDisk, ApplicationError> {
+        let file = disk.path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(ApplicationError::PathDecodingError(disk.path().clone()))?;
+
+        Ok(BundledDisk {
+            file: file.to_owned(),
+            disk_uuid: *disk.disk_uuid(),
+            part_uuid: *disk.part_uuid(),
+            sha256: checksum(disk.path()).await?
+        })
+    }
+
+    fn disk_path(workdir: &Path, name: &str, format: DiskFormat) -> PathBuf {
+        workdir.join(name).with_extension(format.extension())
+    }
+
+    pub async fn resize(&mut self, main_storage_size_mb: Option<usize>, secure_storage_size_mb: Option<usize>) -> Result<(), ApplicationError> {
+        if let Some(size_mb) = main_storage_size_mb {
+            let disk = self.main_storage.clone();
+            tokio::task::spawn_blocking(move || disk.resize(size_mb)).await??;
+            self.config.main_storage_size_mb = size_mb;
+        }
+
+        if let Some(size_mb) = secure_storage_size_mb {
+            let disk = self.secure_storage.clone();
+            tokio::task::spawn_blocking(move || disk.resize(size_mb)).await??;
+            self.config.secure_storage_size_mb = size_mb;
+        }
+
+        Ok(())
+    }
+
     pub fn configure(&self, builder: &mut dyn VMBuilder) -> Result<(), ApplicationError> {
         let main_storage_path = self.main_storage.path();
         builder.block_device(
             &main_storage_path.to_str()
-                .ok_or(ApplicationError::PathDecodingError(main_storage_path.clone()))?
+                .ok_or(ApplicationError::PathDecodingError(main_storage_path.clone()))?,
+            self.main_storage.format()
         );
 
         let secure_storage_path = self.secure_storage.path();
         builder.block_device(
             &secure_storage_path.to_str()
-                .ok_or(ApplicationError::PathDecodingError(secure_storage_path.clone()))?
+                .ok_or(ApplicationError::PathDecodingError(secure_storage_path.clone()))?,
+            self.secure_storage.format()
         );
 
         Ok(())
     }
 
-    pub fn application_info(&self) -> ApplicationInfo {
+    pub fn set_image(&mut self, image_uuid: Uuid) {
+        self.config.provision_from = Some(image_uuid);
+    }
+
+    pub fn application_info(&self, reformat: bool) -> ApplicationInfo {
         ApplicationInfo {
-            main_partition_uuid: self.main_storage.part_uuid().clone(),
-            secure_partition_uuid: self.secure_storage.part_uuid().clone(),
-            provision_info: self.config.provision_from.as_ref().map(|uuid| ProvisionInfo { uuid: *uuid })
+            main_partition_uuid: *self.main_storage.part_uuid(),
+            secure_partition_uuid: *self.secure_storage.part_uuid(),
+            image_uuid: self.config.provision_from,
+            provision_info: self.config.provision_from.as_ref()
+                .filter(|_| !self.config.provisioned || reformat)
+                .map(|uuid| ProvisionInfo { uuid: *uuid, reformat }),
+            image_fs: self.config.image_fs.clone(),
+            secure_fs: self.config.secure_fs.clone()
         }
     }
 }
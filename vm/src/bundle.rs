@@ -0,0 +1,329 @@
+use std::path::{Path, PathBuf};
+
+use futures_util::StreamExt;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{fs::{create_dir, File}, io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt}};
+use tokio_tar::{Archive, Builder, Header};
+use uuid::Uuid;
+
+use crate::app::ApplicationConfig;
+
+/// Name of the manifest, always the first entry of a bundle
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Cannot create bundle {0:?}")]
+    BundleCreationError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot open bundle {0:?}")]
+    BundleOpenError(PathBuf, #[source] std::io::Error),
+
+    #[error("Failed to write bundle")]
+    BundleWriteError(#[source] std::io::Error),
+
+    #[error("Failed to read bundle")]
+    BundleReadError(#[source] std::io::Error),
+
+    #[error("Cannot read disk {0:?}")]
+    DiskReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot write disk {0:?}")]
+    DiskWriteError(PathBuf, #[source] std::io::Error),
+
+    #[error("Cannot create directory {0:?}")]
+    MkdirError(PathBuf, #[source] std::io::Error),
+
+    #[error("Manifest serialization error")]
+    ManifestError(#[from] serde_json::Error),
+
+    #[error("Bundle doesn't start with a manifest")]
+    MissingManifest(),
+
+    #[error("Unexpected file in bundle {0:?}")]
+    UnexpectedEntry(PathBuf),
+
+    #[error("Invalid disk file name {0} in manifest")]
+    InvalidDiskName(String),
+
+    #[error("Disk {0} is missing from the bundle")]
+    MissingDisk(String),
+
+    #[error("Checksum mismatch for {0}, expected {1} got {2}")]
+    ChecksumMismatch(String, String, String)
+}
+
+/// Disk image packed in a bundle
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundledDisk {
+    pub file: String,
+    pub disk_uuid: Uuid,
+    pub part_uuid: Uuid,
+    pub sha256: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BundleManifest {
+    pub id: String,
+    pub config: ApplicationConfig,
+    pub main_storage: BundledDisk,
+    pub secure_storage: BundledDisk
+}
+
+impl BundleManifest {
+    fn disks(&self) -> [&BundledDisk; 2] {
+        [&self.main_storage, &self.secure_storage]
+    }
+}
+
+pub async fn checksum(path: &Path) -> Result<String, BundleError> {
+    let file = File::open(path).await
+        .map_err(|e| BundleError::DiskReadError(path.to_owned(), e))?;
+    hash_stream(file, None).await
+        .map_err(|e| BundleError::DiskReadError(path.to_owned(), e))
+}
+
+/// Packs the manifest followed by the disk images it lists from `disk_dir`
+pub async fn write_bundle(path: &Path, manifest: &BundleManifest, disk_dir: &Path) -> Result<(), BundleError> {
+    let file = File::create(path).await
+        .map_err(|e| BundleError::BundleCreationError(path.to_owned(), e))?;
+    let mut builder = Builder::new(file);
+
+    let content = serde_json::to_vec_pretty(manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_NAME, content.as_slice()).await
+        .map_err(BundleError::BundleWriteError)?;
+
+    for disk in manifest.disks() {
+        debug!("Adding {} to bundle", disk.file);
+        builder.append_path_with_name(disk_dir.join(&disk.file), &disk.file).await
+            .map_err(BundleError::BundleWriteError)?;
+    }
+
+    builder.into_inner().await
+        .map_err(BundleError::BundleWriteError)?
+        .sync_all().await
+        .map_err(BundleError::BundleWriteError)?;
+
+    Ok(())
+}
+
+/// Unpacks a bundle into `target`, verifying every disk against the manifest
+pub async fn read_bundle(path: &Path, target: &Path) -> Result<BundleManifest, BundleError> {
+    let file = File::open(path).await
+        .map_err(|e| BundleError::BundleOpenError(path.to_owned(), e))?;
+    let mut archive = Archive::new(file);
+    let mut entries = archive.entries()
+        .map_err(BundleError::BundleReadError)?;
+
+    let mut entry = entries.next().await
+        .ok_or(BundleError::MissingManifest())?
+        .map_err(BundleError::BundleReadError)?;
+    if entry.path().map_err(BundleError::BundleReadError)?.as_ref() != Path::new(MANIFEST_NAME) {
+        return Err(BundleError::MissingManifest());
+    }
+
+    let mut manifest = Vec::new();
+    entry.read_to_end(&mut manifest).await
+        .map_err(BundleError::BundleReadError)?;
+    let manifest: BundleManifest = serde_json::from_slice(&manifest)?;
+    info!("Importing application {} from {:?}", manifest.id, path);
+
+    // Disks are unpacked by the names from the manifest, keep them inside target
+    if let Some(disk) = manifest.disks().into_iter().find(|d| Path::new(&d.file).file_name() != Some(d.file.as_ref())) {
+        return Err(BundleError::InvalidDiskName(disk.file.clone()));
+    }
+
+    create_dir(target).await
+        .map_err(|e| BundleError::MkdirError(target.to_owned(), e))?;
+
+    let mut unpacked = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry.map_err(BundleError::BundleReadError)?;
+        let name = entry.path().map_err(BundleError::BundleReadError)?.into_owned();
+
+        let disk = manifest.disks()
+            .into_iter()
+            .find(|d| Path::new(&d.file) == name && !unpacked.contains(&d.file))
+            .ok_or(BundleError::UnexpectedEntry(name))?;
+
+        let disk_path = target.join(&disk.file);
+        let out = File::create(&disk_path).await
+            .map_err(|e| BundleError::DiskWriteError(disk_path.clone(), e))?;
+        let sha256 = hash_stream(entry, Some(out)).await
+            .map_err(|e| BundleError::DiskWriteError(disk_path.clone(), e))?;
+
+        if sha256 != disk.sha256 {
+            return Err(BundleError::ChecksumMismatch(disk.file.clone(), disk.sha256.clone(), sha256));
+        }
+
+        unpacked.push(disk.file.clone());
+    }
+
+    if let Some(disk) = manifest.disks().into_iter().find(|d| !unpacked.contains(&d.file)) {
+        return Err(BundleError::MissingDisk(disk.file.clone()));
+    }
+
+    Ok(manifest)
+}
+
+// Hashes the stream, copying it to `out` if given. All-zero chunks are
+// skipped over so raw images stay sparse.
+async fn hash_stream(mut stream: impl AsyncRead + Unpin, mut out: Option<File>) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut len = 0u64;
+
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        let chunk = &buf[..n];
+        hasher.update(chunk);
+        len += n as u64;
+
+        if let Some(out) = out.as_mut() {
+            if chunk.iter().all(|b| *b == 0) {
+                out.seek(std::io::SeekFrom::Current(n as i64)).await?;
+            } else {
+                out.write_all(chunk).await?;
+            }
+        }
+    }
+
+    if let Some(out) = out {
+        out.set_len(len).await?;
+        out.sync_all().await?;
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn disk(file: &str, content: &[u8]) -> BundledDisk {
+        BundledDisk {
+            file: file.to_owned(),
+            disk_uuid: Uuid::new_v4(),
+            part_uuid: Uuid::new_v4(),
+            sha256: hex::encode(Sha256::digest(content))
+        }
+    }
+
+    fn manifest(main: &[u8], secure: &[u8]) -> BundleManifest {
+        let config = serde_json::from_str(r#"{
+            "main_storage_size_mb": 64, "secure_storage_size_mb": 16, "provision_from": null,
+            "disk_format": "raw", "main_storage_backing": null
+        }"#).unwrap();
+
+        BundleManifest {
+            id: "a0".to_owned(),
+            config,
+            main_storage: disk("main.raw", main),
+            secure_storage: disk("secure.raw", secure)
+        }
+    }
+
+    // Bundle with the given entries in order
+    async fn tar(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut builder = Builder::new(File::create(path).await.unwrap());
+        for (name, content) in entries {
+            let mut header = Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *content).await.unwrap();
+        }
+        builder.into_inner().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn written_bundle_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut main = vec![0u8; 3 * CHUNK_SIZE];
+        main[CHUNK_SIZE + 7] = 0xaa;
+        let secure = b"secure".to_vec();
+
+        let disks = dir.path().join("disks");
+        std::fs::create_dir(&disks).unwrap();
+        std::fs::write(disks.join("main.raw"), &main).unwrap();
+        std::fs::write(disks.join("secure.raw"), &secure).unwrap();
+
+        let bundle = dir.path().join("a0.tar");
+        let written = manifest(&main, &secure);
+        assert_eq!(checksum(&disks.join("main.raw")).await.unwrap(), written.main_storage.sha256);
+        write_bundle(&bundle, &written, &disks).await.unwrap();
+
+        let target = dir.path().join("imported");
+        let read = read_bundle(&bundle, &target).await.unwrap();
+        assert_eq!(read.id, "a0");
+        assert_eq!(read.main_storage.part_uuid, written.main_storage.part_uuid);
+        assert_eq!(std::fs::read(target.join("main.raw")).unwrap(), main);
+        assert_eq!(std::fs::read(target.join("secure.raw")).unwrap(), secure);
+    }
+
+    #[tokio::test]
+    async fn manifest_comes_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("a0.tar");
+        let content = serde_json::to_vec(&manifest(b"main", b"secure")).unwrap();
+
+        tar(&bundle, &[("main.raw", b"main"), (MANIFEST_NAME, &content)]).await;
+        assert!(matches!(read_bundle(&bundle, &dir.path().join("imported")).await, Err(BundleError::MissingManifest())));
+
+        tar(&bundle, &[]).await;
+        assert!(matches!(read_bundle(&bundle, &dir.path().join("imported")).await, Err(BundleError::MissingManifest())));
+    }
+
+    #[tokio::test]
+    async fn disk_names_stay_inside_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("a0.tar");
+        let target = dir.path().join("imported");
+
+        for name in ["../main.raw", "disks/main.raw", "/main.raw"] {
+            let mut manifest = manifest(b"main", b"secure");
+            manifest.main_storage.file = name.to_owned();
+            tar(&bundle, &[(MANIFEST_NAME, &serde_json::to_vec(&manifest).unwrap())]).await;
+
+            assert!(matches!(read_bundle(&bundle, &target).await, Err(BundleError::InvalidDiskName(n)) if n == name));
+            assert!(!target.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn disks_must_match_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("a0.tar");
+        let content = serde_json::to_vec(&manifest(b"main", b"secure")).unwrap();
+        let target = |n: usize| dir.path().join(format!("imported-{}", n));
+
+        tar(&bundle, &[(MANIFEST_NAME, &content), ("main.raw", b"main")]).await;
+        assert!(matches!(read_bundle(&bundle, &target(0)).await, Err(BundleError::MissingDisk(d)) if d == "secure.raw"));
+
+        tar(&bundle, &[(MANIFEST_NAME, &content), ("main.raw", b"main"), ("main.raw", b"main")]).await;
+        assert!(matches!(read_bundle(&bundle, &target(1)).await, Err(BundleError::UnexpectedEntry(_))));
+
+        tar(&bundle, &[(MANIFEST_NAME, &content), ("other.raw", b"main")]).await;
+        assert!(matches!(read_bundle(&bundle, &target(2)).await, Err(BundleError::UnexpectedEntry(_))));
+
+        tar(&bundle, &[(MANIFEST_NAME, &content), ("main.raw", b"tampered"), ("secure.raw", b"secure")]).await;
+        assert!(matches!(read_bundle(&bundle, &target(3)).await, Err(BundleError::ChecksumMismatch(d, ..)) if d == "main.raw"));
+
+        tar(&bundle, &[(MANIFEST_NAME, &content), ("secure.raw", b"secure"), ("main.raw", b"main")]).await;
+        assert!(read_bundle(&bundle, &target(4)).await.is_ok());
+    }
+}
@@ -1,12 +1,12 @@
 use std::{fs::create_dir, future::Future, io::Error, path::{Path, PathBuf}, sync::Arc};
 
 use tokio::{net::UnixListener, select, spawn, sync::Mutex, task::{JoinHandle, JoinSet}};
-use log::{debug, info};
+use log::{debug, info, warn};
 use thiserror::Error;
 use tokio_util::sync::CancellationToken;
 use tokio_vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY, VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_CID_LOCAL};
 
-use crate::{interface::ClientHandler, realm::RealmError, vsock::{ConnectionDispatcher, ConnectionDispatcherError}};
+use crate::{interface::ClientHandler, realm::RealmError, verifier::Verifier, vsock::{ConnectionDispatcher, ConnectionDispatcherError}};
 
 #[derive(Error, Debug)]
 pub enum DaemonError {
@@ -19,9 +19,6 @@ pub enum DaemonError {
     #[error("Cannot create workdir")]
     WorkdirMkdirFail(#[source] std::io::Error),
 
-    #[error("Vsock binding error")]
-    VsockBindError(#[source] std::io::Error),
-
     #[error("Vsock accept connection error")]
     VsockAcceptError(#[source] std::io::Error),
 
@@ -32,6 +29,8 @@ pub enum DaemonError {
 #[derive(Debug)]
 pub struct DaemonContext {
     pub workdir: PathBuf,
+    pub vsock_port: u32,
+    pub verifier: Option<Verifier>,
     pub cancel: CancellationToken,
     pub dispatcher: Mutex<ConnectionDispatcher>
 }
@@ -41,7 +40,7 @@ pub struct Daemon {
 }
 
 impl Daemon {
-    pub fn init(workdir: PathBuf) -> Result<Self, DaemonError> {
+    pub fn init(workdir: PathBuf, vsock_port: u32, verifier: Option<Verifier>) -> Result<Self, DaemonError> {
         if ! workdir.exists() {
             create_dir(&workdir)
                 .map_err(DaemonError::WorkdirMkdirFail)?;
@@ -50,6 +49,8 @@ impl Daemon {
         Ok(Self {
            ctx: Arc::new(DaemonContext {
                workdir,
+               vsock_port,
+               verifier,
                cancel: CancellationToken::new(),
                dispatcher: Mutex::new(ConnectionDispatcher::new())
            })
@@ -104,20 +105,30 @@ impl Daemon {
         Ok(())
     }
 
-    pub fn start_vsock_thread(&self, port: u32) -> JoinHandle<Result<(), DaemonError>> {
+    pub fn start_vsock_thread(&self) -> JoinHandle<Result<(), DaemonError>> {
         let ctx = self.ctx.clone();
 
         spawn(async move {
-            Daemon::listen_vsock(ctx, port).await
+            Daemon::listen_vsock(ctx).await
         })
     }
 
-    async fn listen_vsock(ctx: Arc<DaemonContext>, port: u32) -> Result<(), DaemonError> {
-        debug!("Listening on vsock port {}", port);
+    async fn listen_vsock(ctx: Arc<DaemonContext>) -> Result<(), DaemonError> {
+        debug!("Listening on vsock port {}", ctx.vsock_port);
+
+        let listener = VsockListener::bind(
+            VsockAddr::new(VMADDR_CID_ANY, ctx.vsock_port)
+        );
 
-        let mut listener = VsockListener::bind(
-            VsockAddr::new(VMADDR_CID_ANY, port)
-        ).map_err(DaemonError::VsockBindError)?;
+        // Realms can still use the serial or tcp transports
+        let mut listener = match listener {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Vsock is unavailable, only serial and tcp transports will work: {}", e);
+                ctx.cancel.cancelled().await;
+                return Ok(());
+            }
+        };
 
         info!("Ready for realm connections");
 
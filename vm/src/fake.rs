@@ -0,0 +1,119 @@
+use std::{env, fs::File, path::{Path, PathBuf}, process::Stdio};
+use tokio::process::{Child, Command};
+
+use log::debug;
+use thiserror::Error;
+
+use crate::{qdisk::DiskFormat, vmm::{VMBuilder, VMMError}};
+
+#[derive(Error, Debug)]
+pub enum FakeRealmError {
+    #[error("Cannot locate the fake-realm binary")]
+    BinaryNotFound(#[source] std::io::Error),
+
+    #[error("Failed to start fake-realm process")]
+    FailedToStart(#[source] std::io::Error),
+
+    #[error("Cannot create console log file {0:?}")]
+    ConsoleLogCreationError(PathBuf, #[source] std::io::Error)
+}
+
+/// Runs the realm as a host process, the app-manager built as `fake-realm`
+/// with stand-ins for the kernel. No hypervisor, kernel image or vsock are needed.
+pub struct FakeRunner {
+    workdir: PathBuf,
+    args: Vec<String>,
+    console_log: Option<PathBuf>,
+    kernel_params: Vec<String>
+}
+
+impl FakeRunner {
+    pub fn new(workdir: &Path) -> Self {
+        Self {
+            workdir: workdir.to_owned(),
+            args: Vec::new(),
+            console_log: None,
+            kernel_params: Vec::new()
+        }
+    }
+
+    fn binary() -> Result<PathBuf, FakeRealmError> {
+        if let Ok(path) = env::var("FAKE_REALM_BIN") {
+            return Ok(PathBuf::from(path));
+        }
+
+        // Part of the app-manager crate, installed next to the daemon
+        Ok(env::current_exe()
+            .map_err(FakeRealmError::BinaryNotFound)?
+            .with_file_name("fake-realm"))
+    }
+}
+
+impl VMBuilder for FakeRunner {
+    fn cpu(&mut self, ty: &dyn AsRef<str>) {
+        debug!("Fake realm ignores cpu type {}", ty.as_ref());
+    }
+
+    fn machine(&mut self, ty: &dyn AsRef<str>) {
+        debug!("Fake realm ignores machine type {}", ty.as_ref());
+    }
+
+    fn core_count(&mut self, _n: usize) {}
+
+    fn ram_size(&mut self, _size_mb: usize) {}
+
+    fn tap_device(&mut self, _name: &dyn AsRef<str>) {}
+
+    fn mac_addr(&mut self, _addr: &dyn AsRef<str>) {}
+
+    fn vsock_cid(&mut self, _cid: usize) {}
+
+    fn kernel(&mut self, _image: &dyn AsRef<str>) {}
+
+    fn block_device(&mut self, path: &dyn AsRef<str>, _format: DiskFormat) {
+        self.args.push("--disk".to_owned());
+        self.args.push(path.as_ref().to_owned());
+    }
+
+    fn stdout(&mut self, path: &dyn AsRef<str>) {
+        self.console_log = Some(PathBuf::from(path.as_ref()));
+    }
+
+    fn serial_channel(&mut self, socket: &dyn AsRef<str>) {
+        // There is no virtio-serial port on the host, hand the socket over directly
+        self.args.push("--serial-socket".to_owned());
+        self.args.push(socket.as_ref().to_owned());
+    }
+
+    fn kernel_param(&mut self, param: &dyn AsRef<str>) {
+        self.kernel_params.push(param.as_ref().to_owned());
+    }
+
+    fn launch(&mut self) -> Result<Child, VMMError> {
+        let mut command = Command::new(Self::binary()?);
+
+        command.arg("--workdir").arg(self.workdir.join("realm"));
+        command.arg("--cmdline").arg(self.kernel_params.join(" "));
+        command.args(self.args.iter());
+
+        debug!("cmd: {:?}", command);
+
+        command.stdin(Stdio::null());
+
+        if let Some(path) = self.console_log.as_ref() {
+            let log = File::create(path)
+                .map_err(|e| FakeRealmError::ConsoleLogCreationError(path.clone(), e))?;
+            let err = log.try_clone()
+                .map_err(|e| FakeRealmError::ConsoleLogCreationError(path.clone(), e))?;
+            command.stdout(log);
+            command.stderr(err);
+        } else {
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+        }
+
+        Ok(command.spawn()
+                .map_err(FakeRealmError::FailedToStart)?
+        )
+    }
+}
@@ -1,12 +1,13 @@
-use std::{collections::HashMap, fmt::Display, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, fmt::Display, net::SocketAddr, path::PathBuf, process::ExitStatus, str::FromStr, sync::Arc};
 
 use clap::{crate_name, Parser, Subcommand};
 use log::{debug, info};
 use thiserror::Error;
 use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufStream}, net::UnixStream, select, task::JoinSet};
+use protocol::{attestation::CHALLENGE_LEN, AppStatus, Filesystem, StopPath, StorageFilesystem};
 use uuid::Uuid;
 
-use crate::{app::ApplicationConfig, daemon::DaemonContext, qemu::{QEMURunner, VMBuilder}, realm::{NetworkConfig, Realm, RealmConfig, RealmError}};
+use crate::{app::ApplicationConfig, daemon::DaemonContext, qdisk::DiskFormat, realm::{NetworkConfig, Realm, RealmConfig, RealmError}, transport::TransportKind, utils::random_bytes, vmm::VMBackend};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -24,6 +25,10 @@ pub enum Command {
         #[clap(short, long)]
         id: String,
 
+        /// Virtual machine monitor to run the realm with
+        #[clap(short, long, value_enum, default_value = "qemu")]
+        backend: VMBackend,
+
         /// CPU type
         #[clap(short, long, default_value = "cortex-a57")]
         cpu: String,
@@ -52,9 +57,25 @@ pub enum Command {
         #[clap(short, long)]
         vsock_cid: usize,
 
+        /// Channel the realm uses to reach the host, defaults to vsock (serial for the fake backend)
+        #[clap(long, value_enum)]
+        transport: Option<TransportKind>,
+
+        /// Host address to listen on for the tcp transport
+        #[clap(long, default_value = "192.168.100.1:1337")]
+        tcp_address: SocketAddr,
+
         /// Path to kernel image
         #[clap(short, long)]
-        kernel: PathBuf
+        kernel: PathBuf,
+
+        /// Extra kernel command line parameter, can be repeated
+        #[clap(long)]
+        kernel_param: Vec<String>,
+
+        /// App-manager setting sent with the realm info as NAME=VALUE, can be repeated
+        #[clap(long, value_parser = parse_setting)]
+        app_manager_config: Vec<(String, String)>
     },
 
     /// List all realms
@@ -80,14 +101,91 @@ pub enum Command {
 
         /// Provision from
         #[clap(short, long)]
-        provision_from: Option<Uuid>
+        provision_from: Option<Uuid>,
+
+        /// Disk image format
+        #[clap(short = 'f', long, value_enum, default_value = "raw")]
+        disk_format: DiskFormat,
+
+        /// Base image for main storage, qcow2 only
+        #[clap(short, long)]
+        backing_file: Option<PathBuf>,
+
+        /// Filesystem of the installed image: ext4, erofs or squashfs
+        #[clap(long, value_parser = parse_image_fs, default_value = "ext4")]
+        image_fs: Filesystem,
+
+        /// Mount options of the installed image
+        #[clap(long)]
+        image_mount_options: Option<String>,
+
+        /// Filesystem of the secure storage: ext4, xfs or btrfs
+        #[clap(long, value_parser = parse_secure_fs, default_value = "ext4")]
+        secure_fs: Filesystem,
+
+        /// Mount options of the secure storage
+        #[clap(long)]
+        secure_mount_options: Option<String>
+    },
+
+    /// Pack an application's storage and config into a bundle
+    ExportApplication {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+
+        /// Bundle file to write
+        #[clap(short, long)]
+        output: PathBuf
+    },
+
+    /// Register an application from a bundle in a realm
+    ImportApplication {
+        /// Bundle file
+        #[clap(short, long)]
+        bundle: PathBuf,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+
+        /// Application id, defaults to the one it was exported with
+        #[clap(short, long)]
+        id: Option<String>
+    },
+
+    /// Grow the storage of an application, takes effect on next realm launch
+    ResizeApplicationStorage {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+
+        /// New main storage size in MB
+        #[clap(short, long)]
+        main_storage_size_mb: Option<usize>,
+
+        /// New secure storage size in MB
+        #[clap(short, long)]
+        secure_storage_size_mb: Option<usize>
     },
 
     /// Launch a configured realm
     LaunchRealm {
         /// Realm id to launch
         #[clap(short, long)]
-        id: String
+        id: String,
+
+        /// Wipe the storage of already provisioned applications and provision them again
+        #[clap(long)]
+        reformat: bool
     },
 
     /// Start a stopped application
@@ -110,6 +208,10 @@ pub enum Command {
         /// Realm id
         #[clap(short, long)]
         realm_id: String,
+
+        /// Seconds to wait after the stop signal before killing the application
+        #[clap(short, long)]
+        grace: Option<u64>,
     },
 
     /// Kill a running application
@@ -123,6 +225,69 @@ pub enum Command {
         realm_id: String,
     },
 
+    /// Get an attestation token of a running realm
+    Attest {
+        /// Realm id
+        #[clap(short, long)]
+        id: String,
+
+        /// Hex encoded challenge, up to 64 bytes, random if not given
+        #[clap(short, long)]
+        challenge: Option<String>,
+
+        /// Save the token to a file instead of printing it
+        #[clap(short, long)]
+        output: Option<PathBuf>
+    },
+
+    /// Re-encrypt application's secure storage under a new key
+    RotateKey {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+    },
+
+    /// Move a running application to another image, its secure storage is kept
+    UpdateApp {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+
+        /// Registry image to update to
+        #[clap(short = 'p', long)]
+        image_uuid: Uuid
+    },
+
+    /// Provision an application that failed while the realm booted again
+    RetryProvision {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+    },
+
+    /// Show whether an application was provisioned or where it failed
+    AppStatus {
+        /// Application id
+        #[clap(short, long)]
+        id: String,
+
+        /// Realm id
+        #[clap(short, long)]
+        realm_id: String,
+    },
+
     /// Shutdown realm
     Shutdown {
         /// Realm id
@@ -131,14 +296,44 @@ pub enum Command {
     }
 }
 
+fn parse_setting(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .ok_or(format!("expected NAME=VALUE, got `{}`", arg))
+}
+
+// The image is only written while it is installed
+fn parse_image_fs(arg: &str) -> Result<Filesystem, String> {
+    match Filesystem::from_str(arg).map_err(|e| e.to_string())? {
+        Filesystem::Xfs | Filesystem::Btrfs => Err(format!("{} is not supported for the image", arg)),
+        fs => Ok(fs)
+    }
+}
+
+fn parse_secure_fs(arg: &str) -> Result<Filesystem, String> {
+    match Filesystem::from_str(arg).map_err(|e| e.to_string())? {
+        Filesystem::Erofs | Filesystem::Squashfs => Err(format!("{} is read-only", arg)),
+        fs => Ok(fs)
+    }
+}
+
 #[derive(Debug)]
 enum CommandResult {
     RealmCreated,
     ApplicationCreated,
+    ApplicationStorageResized,
+    ApplicationExported,
+    ApplicationImported(String),
     RealmLaunched,
     Msg(String),
     ApplicationStarted,
-    ApplicationExited,
+    ApplicationExited(ExitStatus),
+    ApplicationTerminated(ExitStatus, StopPath),
+    KeyRotated,
+    ApplicationUpdated,
+    ApplicationStatus(AppStatus),
+    AttestationToken(Vec<u8>),
+    AttestationTokenSaved(PathBuf),
     RealmExited,
 }
 
@@ -147,10 +342,19 @@ impl Display for CommandResult {
         match &self {
             CommandResult::RealmCreated => write!(f, "RealmCreated"),
             CommandResult::ApplicationCreated => write!(f, "ApplicationCreated"),
+            CommandResult::ApplicationStorageResized => write!(f, "ApplicationStorageResized"),
+            CommandResult::ApplicationExported => write!(f, "ApplicationExported"),
+            CommandResult::ApplicationImported(id) => write!(f, "ApplicationImported: {}", id),
             CommandResult::RealmLaunched => write!(f, "RealmLaunched"),
             CommandResult::Msg(v) => write!(f, "{}", v),
-            CommandResult::ApplicationExited => write!(f, "ApplicationExited"),
+            CommandResult::ApplicationExited(status) => write!(f, "ApplicationExited: {}", status),
+            CommandResult::ApplicationTerminated(status, stop) => write!(f, "ApplicationExited: {}, {}", status, stop),
             CommandResult::ApplicationStarted => write!(f, "ApplicationStarted"),
+            CommandResult::KeyRotated => write!(f, "KeyRotated"),
+            CommandResult::ApplicationUpdated => write!(f, "ApplicationUpdated"),
+            CommandResult::ApplicationStatus(status) => write!(f, "ApplicationStatus: {}", status),
+            CommandResult::AttestationToken(token) => write!(f, "AttestationToken: {}", hex::encode(token)),
+            CommandResult::AttestationTokenSaved(path) => write!(f, "AttestationTokenSaved: {:?}", path),
             CommandResult::RealmExited => write!(f, "RealmExited")
         }
     }
@@ -176,6 +380,15 @@ pub enum ClientHandlerError {
     #[error("Realm of id {0} doesn't exist")]
     RealmDoesNotExist(String),
 
+    #[error("Invalid challenge `{0}`, expected at most {CHALLENGE_LEN} hex encoded bytes")]
+    InvalidChallenge(String),
+
+    #[error("Cannot generate a challenge")]
+    ChallengeGenerationError(#[source] std::io::Error),
+
+    #[error("Cannot save attestation token to {0:?}")]
+    TokenWriteError(PathBuf, #[source] std::io::Error),
+
     #[error("Errror occured while modyfing realm")]
     RealmError(#[from] RealmError)
 }
@@ -281,26 +494,52 @@ impl ClientHandler {
 
     async fn handle_command(&mut self, command: Command) -> Result<CommandResult, ClientHandlerError> {
         match command {
-            Command::CreateRealm { id, cpu, machine, core_count, ram_size, tap_device, mac_addr, vsock_cid, kernel }
+            Command::CreateRealm { id, backend, cpu, machine, core_count, ram_size, tap_device, mac_addr, vsock_cid, transport, tcp_address, kernel, kernel_param, app_manager_config }
                 => self.handle_create_realm(id, RealmConfig {
+                    backend,
                     cpu,
                     machine,
                     core_count,
                     ram_size,
                     network_config: NetworkConfig { tap_device, mac_addr },
                     vsock_cid,
-                    kernel
+                    transport: transport.unwrap_or(backend.default_transport()),
+                    tcp_address,
+                    kernel,
+                    kernel_params: kernel_param,
+                    app_manager_config: app_manager_config.into_iter().collect()
                 }),
 
             Command::ListRealms {  } => self.handle_list_realms(),
 
-            Command::CreateApplication { id, realm_id, main_storage_size_mb, secure_storage_size_mb, provision_from }
-                => self.handle_create_application(id, realm_id, ApplicationConfig { main_storage_size_mb, secure_storage_size_mb, provision_from }).await,
-
-            Command::LaunchRealm { id } => self.handle_launch_realm(id),
+            Command::CreateApplication { id, realm_id, main_storage_size_mb, secure_storage_size_mb, provision_from, disk_format, backing_file,
+                                         image_fs, image_mount_options, secure_fs, secure_mount_options }
+                => self.handle_create_application(id, realm_id, ApplicationConfig {
+                    main_storage_size_mb,
+                    secure_storage_size_mb,
+                    provision_from,
+                    disk_format,
+                    main_storage_backing: backing_file,
+                    provisioned: false,
+                    image_fs: StorageFilesystem { filesystem: image_fs, mount_options: image_mount_options },
+                    secure_fs: StorageFilesystem { filesystem: secure_fs, mount_options: secure_mount_options }
+                }).await,
+
+            Command::ExportApplication { id, realm_id, output } => self.handle_export_application(id, realm_id, output).await,
+            Command::ImportApplication { bundle, realm_id, id } => self.handle_import_application(bundle, realm_id, id).await,
+
+            Command::ResizeApplicationStorage { id, realm_id, main_storage_size_mb, secure_storage_size_mb }
+                => self.handle_resize_application_storage(id, realm_id, main_storage_size_mb, secure_storage_size_mb).await,
+
+            Command::LaunchRealm { id, reformat } => self.handle_launch_realm(id, reformat),
             Command::StartApp { id, realm_id } => self.handle_start_app(id, realm_id).await,
-            Command::TerminateApp { id, realm_id } => self.handle_terminate_app(id, realm_id).await,
+            Command::TerminateApp { id, realm_id, grace } => self.handle_terminate_app(id, realm_id, grace).await,
             Command::KillApp { id, realm_id } => self.handle_kill_app(id, realm_id).await,
+            Command::RotateKey { id, realm_id } => self.handle_rotate_key(id, realm_id).await,
+            Command::UpdateApp { id, realm_id, image_uuid } => self.handle_update_app(id, realm_id, image_uuid).await,
+            Command::RetryProvision { id, realm_id } => self.handle_retry_provision(id, realm_id).await,
+            Command::AppStatus { id, realm_id } => self.handle_app_status(id, realm_id).await,
+            Command::Attest { id, challenge, output } => self.handle_attest(id, challenge, output).await,
             Command::Shutdown { id } => self.handle_shutdown(id).await
         }
     }
@@ -312,6 +551,27 @@ impl ClientHandler {
         Ok(CommandResult::ApplicationCreated)
     }
 
+    async fn handle_export_application(&mut self, id: String, realm_id: String, output: PathBuf) -> Result<CommandResult, ClientHandlerError> {
+        self.realms.get(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?
+            .export_application(id, output).await?;
+        Ok(CommandResult::ApplicationExported)
+    }
+
+    async fn handle_import_application(&mut self, bundle: PathBuf, realm_id: String, id: Option<String>) -> Result<CommandResult, ClientHandlerError> {
+        let id = self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?
+            .import_application(bundle, id).await?;
+        Ok(CommandResult::ApplicationImported(id))
+    }
+
+    async fn handle_resize_application_storage(&mut self, id: String, realm_id: String, main_storage_size_mb: Option<usize>, secure_storage_size_mb: Option<usize>) -> Result<CommandResult, ClientHandlerError> {
+        self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?
+            .resize_application_storage(id, main_storage_size_mb, secure_storage_size_mb).await?;
+        Ok(CommandResult::ApplicationStorageResized)
+    }
+
     fn handle_list_realms(&self) -> Result<CommandResult, ClientHandlerError> {
         Ok(CommandResult::Msg(format!("Realms: {:#?}\n", self.realms)))
     }
@@ -329,13 +589,11 @@ impl ClientHandler {
         }
     }
 
-    fn handle_launch_realm(&mut self, id: String) -> Result<CommandResult, ClientHandlerError> {
+    fn handle_launch_realm(&mut self, id: String, reformat: bool) -> Result<CommandResult, ClientHandlerError> {
         let realm = self.realms.get_mut(&id)
             .ok_or(ClientHandlerError::RealmDoesNotExist(id))?;
 
-        let mut runner = QEMURunner::new();
-        runner.arg(&"-nographic");
-        realm.launch(&mut runner, self.context.clone(), &mut self.handler_threads)?;
+        realm.launch(self.context.clone(), &mut self.handler_threads, reformat)?;
 
         Ok(CommandResult::RealmLaunched)
     }
@@ -348,18 +606,68 @@ impl ClientHandler {
         Ok(CommandResult::ApplicationStarted)
     }
 
-    pub async fn handle_terminate_app(&mut self, id: String, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
+    pub async fn handle_terminate_app(&mut self, id: String, realm_id: String, grace: Option<u64>) -> Result<CommandResult, ClientHandlerError> {
         let realm = self.realms.get_mut(&realm_id)
             .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
-        realm.terminate_app(id).await?;
-        Ok(CommandResult::ApplicationExited)
+        let (status, stop) = realm.terminate_app(id, grace).await?;
+        Ok(CommandResult::ApplicationTerminated(status, stop))
     }
 
     pub async fn handle_kill_app(&mut self, id: String, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
         let realm = self.realms.get_mut(&realm_id)
             .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
-        realm.kill_app(id).await?;
-        Ok(CommandResult::ApplicationExited)
+        Ok(CommandResult::ApplicationExited(realm.kill_app(id).await?))
+    }
+
+    pub async fn handle_attest(&mut self, id: String, challenge: Option<String>, output: Option<PathBuf>) -> Result<CommandResult, ClientHandlerError> {
+        let challenge = match challenge {
+            Some(challenge) => hex::decode(&challenge)
+                .map_err(|_| ClientHandlerError::InvalidChallenge(challenge))?,
+            None => random_bytes(CHALLENGE_LEN).await
+                .map_err(ClientHandlerError::ChallengeGenerationError)?
+        };
+
+        if challenge.len() > CHALLENGE_LEN {
+            return Err(ClientHandlerError::InvalidChallenge(hex::encode(challenge)));
+        }
+
+        let realm = self.realms.get_mut(&id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(id))?;
+        let token = realm.attest(challenge).await?;
+
+        if let Some(path) = output {
+            tokio::fs::write(&path, &token).await
+                .map_err(|e| ClientHandlerError::TokenWriteError(path.clone(), e))?;
+            Ok(CommandResult::AttestationTokenSaved(path))
+        } else {
+            Ok(CommandResult::AttestationToken(token))
+        }
+    }
+
+    pub async fn handle_rotate_key(&mut self, id: String, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
+        let realm = self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
+        realm.rotate_key(id).await?;
+        Ok(CommandResult::KeyRotated)
+    }
+
+    pub async fn handle_update_app(&mut self, id: String, realm_id: String, image_uuid: Uuid) -> Result<CommandResult, ClientHandlerError> {
+        let realm = self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
+        realm.update_app(id, image_uuid).await?;
+        Ok(CommandResult::ApplicationUpdated)
+    }
+
+    pub async fn handle_retry_provision(&mut self, id: String, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
+        let realm = self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
+        Ok(CommandResult::ApplicationStatus(realm.retry_provision(id).await?))
+    }
+
+    pub async fn handle_app_status(&mut self, id: String, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
+        let realm = self.realms.get_mut(&realm_id)
+            .ok_or(ClientHandlerError::RealmDoesNotExist(realm_id))?;
+        Ok(CommandResult::ApplicationStatus(realm.app_status(id).await?))
     }
 
     pub async fn handle_shutdown(&mut self, realm_id: String) -> Result<CommandResult, ClientHandlerError> {
@@ -0,0 +1,145 @@
+use std::{env, fs::File, path::PathBuf, process::Stdio};
+use tokio::process::{Child, Command};
+
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::{qdisk::DiskFormat, vmm::{VMBuilder, VMMError}};
+
+const LKVM_BIN: &str = "/usr/bin/lkvm";
+
+#[derive(Error, Debug)]
+pub enum KvmtoolError {
+    #[error("Failed to start lkvm process")]
+    FailedToStart(#[source] std::io::Error),
+
+    #[error("Cannot create console log file {0:?}")]
+    ConsoleLogCreationError(PathBuf, #[source] std::io::Error),
+
+    #[error("kvmtool has no virtio-serial support, use the vsock or tcp transport")]
+    SerialChannelUnsupported()
+}
+
+pub struct KvmtoolRunner {
+    command: Command,
+    tap_device: Option<String>,
+    mac_addr: Option<String>,
+    console_log: Option<PathBuf>,
+    kernel_params: Vec<String>,
+    serial_channel: bool
+}
+
+impl KvmtoolRunner {
+    pub fn new() -> Self {
+        let lkvm = env::var("LKVM_BIN").unwrap_or(LKVM_BIN.to_string());
+
+        let mut command = Command::new(lkvm);
+        command.arg("run");
+        command.arg("--console").arg("serial");
+
+        Self {
+            command,
+            tap_device: None,
+            mac_addr: None,
+            console_log: None,
+            kernel_params: Vec::new(),
+            serial_channel: false
+        }
+    }
+
+    fn network(&self) -> Option<String> {
+        let tap = self.tap_device.as_ref()?;
+        let mut network = format!("mode=tap,tapif={}", tap);
+
+        if let Some(mac) = self.mac_addr.as_ref() {
+            network.push_str(format!(",guest_mac={}", mac).as_str());
+        }
+
+        Some(network)
+    }
+}
+
+impl VMBuilder for KvmtoolRunner {
+    fn cpu(&mut self, ty: &dyn AsRef<str>) {
+        debug!("kvmtool always uses the host cpu, ignoring cpu type {}", ty.as_ref());
+    }
+
+    fn machine(&mut self, ty: &dyn AsRef<str>) {
+        debug!("kvmtool has a single machine model, ignoring machine type {}", ty.as_ref());
+    }
+
+    fn core_count(&mut self, n: usize) {
+        self.command.arg("--cpus").arg(n.to_string());
+    }
+
+    fn ram_size(&mut self, size_mb: usize) {
+        self.command.arg("--mem").arg(size_mb.to_string());
+    }
+
+    fn tap_device(&mut self, name: &dyn AsRef<str>) {
+        self.tap_device = Some(name.as_ref().to_owned());
+    }
+
+    fn mac_addr(&mut self, addr: &dyn AsRef<str>) {
+        self.mac_addr = Some(addr.as_ref().to_owned());
+    }
+
+    fn vsock_cid(&mut self, cid: usize) {
+        self.command.arg("--vsock").arg(cid.to_string());
+    }
+
+    fn kernel(&mut self, image: &dyn AsRef<str>) {
+        self.command.arg("--kernel").arg(image.as_ref());
+    }
+
+    fn block_device(&mut self, path: &dyn AsRef<str>, _format: DiskFormat) {
+        // lkvm probes qcow2 images by their header
+        self.command.arg("--disk").arg(path.as_ref());
+    }
+
+    fn stdout(&mut self, path: &dyn AsRef<str>) {
+        // lkvm writes the serial console to its own stdout
+        self.console_log = Some(PathBuf::from(path.as_ref()));
+    }
+
+    fn serial_channel(&mut self, _socket: &dyn AsRef<str>) {
+        self.serial_channel = true;
+    }
+
+    fn kernel_param(&mut self, param: &dyn AsRef<str>) {
+        self.kernel_params.push(param.as_ref().to_owned());
+    }
+
+    fn launch(&mut self) -> Result<Child, VMMError> {
+        if self.serial_channel {
+            return Err(KvmtoolError::SerialChannelUnsupported().into());
+        }
+
+        if !self.kernel_params.is_empty() {
+            self.command.arg("--params").arg(self.kernel_params.join(" "));
+        }
+
+        if let Some(network) = self.network() {
+            self.command.arg("--network").arg(network);
+        } else if self.mac_addr.is_some() {
+            warn!("MAC address set without a tap device, realm will have no network");
+        }
+
+        debug!("cmd: {:?}", self.command);
+
+        self.command.stdin(Stdio::null());
+        self.command.stderr(Stdio::piped());
+
+        if let Some(path) = self.console_log.as_ref() {
+            let log = File::create(path)
+                .map_err(|e| KvmtoolError::ConsoleLogCreationError(path.clone(), e))?;
+            self.command.stdout(log);
+        } else {
+            self.command.stdout(Stdio::piped());
+        }
+
+        Ok(self.command.spawn()
+                .map_err(KvmtoolError::FailedToStart)?
+        )
+    }
+}
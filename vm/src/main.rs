@@ -5,16 +5,23 @@ use std::{env::current_dir, fs::{canonicalize, remove_file}, future::IntoFuture,
 
 use clap::Parser;
 use daemon::Daemon;
+use verifier::Verifier;
 use log::{debug, info, error};
 use tokio::{join, select, signal::unix::{signal, SignalKind}, try_join};
 
 mod app;
+mod bundle;
 mod interface;
 mod daemon;
+mod fake;
+mod kvmtool;
 mod realm;
 mod qemu;
 mod qdisk;
+mod transport;
 mod utils;
+mod verifier;
+mod vmm;
 mod vsock;
 
 #[derive(Parser, Debug)]
@@ -31,6 +38,14 @@ struct Args {
     /// Vsock port to listen on
     #[clap(short, long, default_value_t = 1337)]
     port: u32,
+
+    /// Reference values for releasing images to attested realms
+    #[clap(short, long)]
+    reference_values: Option<PathBuf>,
+
+    /// Release images to realms presenting unsigned mock tokens, for testing only
+    #[clap(long)]
+    accept_mock_tokens: bool,
 }
 
 
@@ -45,10 +60,11 @@ async fn main() -> anyhow::Result<()> {
     }
     let workdir = absolute(args.workdir)?;
     debug!("Workdir: {:?}", workdir);
-    let daemon = Daemon::init(workdir)?;
+    let verifier = args.reference_values.as_deref().map(|path| Verifier::load(path, args.accept_mock_tokens)).transpose()?;
+    let daemon = Daemon::init(workdir, args.port, verifier)?;
 
     let mut unixsocket = daemon.start_unixsocket_thread(args.cli_socket);
-    let mut vsocksocket = daemon.start_vsock_thread(args.port);
+    let mut vsocksocket = daemon.start_vsock_thread();
 
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
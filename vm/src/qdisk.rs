@@ -1,9 +1,17 @@
-use std::{collections::BTreeMap, fs::File, io::{Cursor, Seek, Write, Read}, os::unix::fs::MetadataExt, path::PathBuf};
+use std::{collections::BTreeMap, env, fmt::Display, fs::{remove_file, rename, File, OpenOptions}, io::{Seek, Write}, os::unix::fs::MetadataExt, path::{Path, PathBuf}, process::{Command, ExitStatus}};
 
-use gpt::{mbr::ProtectiveMBR, partition_types, GptConfig};
+use clap::ValueEnum;
+use gpt::{header::Header, mbr::ProtectiveMBR, partition::Partition, partition_types, GptConfig};
+use log::debug;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use uuid::Uuid;
 
+const QEMU_IMG_BIN: &str = "/usr/bin/qemu-img";
+
+// Protective MBR, primary GPT header and 128 partition entries
+const GPT_PRIMARY_SECTORS: usize = 34;
+
 #[derive(Error, Debug)]
 pub enum QEMUDiskError {
     #[error("Disk file opening error")]
@@ -12,7 +20,7 @@ pub enum QEMUDiskError {
     #[error("Cannot fetch disk file stats")]
     DiskFileStatsError(#[source] std::io::Error),
 
-    #[error("Exsiting disk `{0}` file size mismatch, expected {1} got {2}")]
+    #[error("Exsiting disk `{0}` file size mismatch, expected {1} got {2}, use resize-application-storage to grow it")]
     ExistingDiskSizeMismatch(PathBuf, usize, usize),
 
     #[error("Error opening disk by gpt crate")]
@@ -38,93 +46,439 @@ pub enum QEMUDiskError {
 
     #[error("Error no partitions in initilized disk")]
     GPTErrorNoPartitions(),
+
+    #[error("Cannot grow disk file")]
+    DiskFileResizeError(#[source] std::io::Error),
+
+    #[error("Disk `{0}` cannot be shrunk from {1} to {2} bytes")]
+    ShrinkNotSupported(PathBuf, usize, usize),
+
+    #[error("Backing files are only supported for qcow2 disks")]
+    BackingFileNeedsQcow2(),
+
+    #[error("Failed to run qemu-img")]
+    QemuImgSpawnError(#[source] std::io::Error),
+
+    #[error("qemu-img exited with {0}: {1}")]
+    QemuImgFailed(ExitStatus, String),
+
+    #[error("Cannot parse qemu-img info output")]
+    QemuImgInfoParseError(#[source] serde_json::Error),
+
+    #[error("Failed to remove temporary image {0:?}")]
+    TemporaryImageRemoveError(PathBuf, #[source] std::io::Error)
+}
+
+/// On-disk format of an application disk
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskFormat {
+    /// Sparse raw file
+    Raw,
+
+    /// qcow2 image, optionally layered on a backing file
+    Qcow2
+}
+
+impl DiskFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2"
+        }
+    }
+}
+
+impl Display for DiskFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+#[derive(Deserialize)]
+struct QemuImgInfo {
+    format: String,
+
+    #[serde(rename = "virtual-size")]
+    virtual_size: u64
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QEMUDisk {
     path: PathBuf,
+    format: DiskFormat,
+    backing_file: Option<PathBuf>,
     disk_uuid: Uuid,
     part_uuid: Uuid
 }
 
 impl QEMUDisk {
-    pub fn new(path: PathBuf, size_mb: usize) -> Result<Self, QEMUDiskError> {
+    pub fn new(path: PathBuf, format: DiskFormat, size_mb: usize, backing_file: Option<PathBuf>) -> Result<Self, QEMUDiskError> {
         let size_b = size_mb * 1024 * 1024;
 
+        if backing_file.is_some() && format != DiskFormat::Qcow2 {
+            return Err(QEMUDiskError::BackingFileNeedsQcow2());
+        }
+
         if path.exists() {
-            let file_size_b = File::open(&path).map_err(QEMUDiskError::DiskFileOpenError)?
-                .metadata().map_err(QEMUDiskError::DiskFileStatsError)?
-                .size();
+            let file_size_b = Self::image_size(&path, format)?;
 
             if file_size_b != size_b as u64 {
                 return Err(QEMUDiskError::ExistingDiskSizeMismatch(path.clone(), size_b, file_size_b as usize));
             }
 
-         } else {
-
-             let mut file = File::create_new(&path)
-                 .map_err(QEMUDiskError::DiskFileCreationError)?;
-             file.seek(std::io::SeekFrom::Start(size_b as u64 - 1))
-                 .map_err(QEMUDiskError::SparseDiskCreationError)?;
-             file.write_all(&[0u8])
-                 .map_err(QEMUDiskError::SparseDiskCreationError)?;
-             file.seek(std::io::SeekFrom::Start(0u64))
-                 .map_err(QEMUDiskError::SparseDiskCreationError)?;
-
-             let mbr = ProtectiveMBR::with_lb_size((size_b / 512 - 1) as u32);
-             mbr.overwrite_lba0(&mut file)
-                 .map_err(QEMUDiskError::SparseDiskCreationError)?;
-
-             let mut gpt = GptConfig::new()
-                 .writable(true)
-                 .initialized(false)
-                 .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
-                 .create_from_device(Box::new(&mut file), None)
-                 .map_err(QEMUDiskError::GptOpenError)?;
-
-              gpt.update_partitions(BTreeMap::new())
-                  .map_err(QEMUDiskError::GPTDiskHeaderInitError)?;
-
-              let free_sectors = gpt.find_free_sectors();
-
-              if free_sectors.is_empty() {
-                  return Err(QEMUDiskError::GPTNoFreeSectors());
-              }
-
-              gpt.add_partition(
-                  "disk",
-                  free_sectors[0].1 * 512,
-                  partition_types::LINUX_FS,
-                  0,
-                  None
-              ).map_err(QEMUDiskError::GPTFailedToCreateMainPartition)?;
-
-              gpt.write().map_err(QEMUDiskError::GPTFailedToSaveConfToDisk)?;
-              file.sync_all()
-                 .map_err(QEMUDiskError::SparseDiskCreationError)?;
-        }
+        } else {
+            match (format, backing_file.as_ref()) {
+                (DiskFormat::Raw, _) => Self::create_raw(&path, size_b as u64)?,
 
-        let gpt = GptConfig::new()
-            .writable(false)
-            .initialized(true)
-            .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
-            .open(&path)
-            .map_err(QEMUDiskError::DiskFileOpenError)?;
+                (DiskFormat::Qcow2, None) => {
+                    let tmp = Self::temporary_path(&path);
+                    Self::create_raw(&tmp, size_b as u64)
+                        .and_then(|_| Self::convert(&tmp, &path, None))
+                        .inspect_err(|_| discard(&[&tmp, &path]))?;
+                    remove_file(&tmp)
+                        .map_err(|e| QEMUDiskError::TemporaryImageRemoveError(tmp.clone(), e))?;
+                },
 
-        let disk_uuid = gpt.guid().clone();
-        let (_, partition) = gpt.partitions()
-            .first_key_value()
-            .ok_or(QEMUDiskError::GPTErrorNoPartitions())?;
-        let part_uuid = partition.part_guid;
+                (DiskFormat::Qcow2, Some(backing)) => {
+                    // The overlay inherits the partition table of its backing file,
+                    // give it fresh GUIDs so disks sharing a base can't be confused
+                    let info = Self::image_info(backing)?;
+                    if (size_b as u64) < info.virtual_size {
+                        return Err(QEMUDiskError::ShrinkNotSupported(backing.clone(), info.virtual_size as usize, size_b));
+                    }
+
+                    qemu_img(&["create", "-f", "qcow2", "-b", &backing.to_string_lossy(),
+                        "-F", &info.format, &path.to_string_lossy(), &size_b.to_string()])
+                        .and_then(|_| Self::edit_qcow2(&path, Some(backing), |file| Self::relayout_gpt(file, size_b as u64, true)))
+                        .inspect_err(|_| discard(&[&path]))?;
+                }
+            }
+        }
+
+        let (disk_uuid, part_uuid) = Self::read_gpt(&path, format)?;
 
         Ok(Self {
             path,
+            format,
+            backing_file,
             disk_uuid,
             part_uuid
         })
     }
 
+    /// Grows the image and its partition to `size_mb`, the realm picks
+    /// the new size up on the next boot.
+    pub fn resize(&self, size_mb: usize) -> Result<(), QEMUDiskError> {
+        let size_b = (size_mb * 1024 * 1024) as u64;
+        let current_b = Self::image_size(&self.path, self.format)?;
+
+        if size_b < current_b {
+            return Err(QEMUDiskError::ShrinkNotSupported(self.path.clone(), current_b as usize, size_b as usize));
+        }
+
+        if size_b == current_b {
+            return Ok(());
+        }
+
+        debug!("Resizing {:?} from {} to {} bytes", self.path, current_b, size_b);
+
+        match self.format {
+            DiskFormat::Raw => {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&self.path)
+                    .map_err(QEMUDiskError::DiskFileOpenError)?;
+                Self::relayout_gpt(&mut file, size_b, false)?;
+            },
+
+            DiskFormat::Qcow2 => {
+                Self::edit_qcow2(&self.path, self.backing_file.as_deref(), |file| Self::relayout_gpt(file, size_b, false))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_raw(path: &Path, size_b: u64) -> Result<(), QEMUDiskError> {
+        let mut file = File::create_new(path)
+            .map_err(QEMUDiskError::DiskFileCreationError)?;
+        file.seek(std::io::SeekFrom::Start(size_b - 1))
+            .map_err(QEMUDiskError::SparseDiskCreationError)?;
+        file.write_all(&[0u8])
+            .map_err(QEMUDiskError::SparseDiskCreationError)?;
+        file.seek(std::io::SeekFrom::Start(0u64))
+            .map_err(QEMUDiskError::SparseDiskCreationError)?;
+
+        let mbr = ProtectiveMBR::with_lb_size((size_b / 512 - 1) as u32);
+        mbr.overwrite_lba0(&mut file)
+            .map_err(QEMUDiskError::SparseDiskCreationError)?;
+
+        let mut gpt = GptConfig::new()
+            .writable(true)
+            .initialized(false)
+            .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+            .create_from_device(Box::new(&mut file), None)
+            .map_err(QEMUDiskError::GptOpenError)?;
+
+        gpt.update_partitions(BTreeMap::new())
+            .map_err(QEMUDiskError::GPTDiskHeaderInitError)?;
+
+        let free_sectors = gpt.find_free_sectors();
+
+        if free_sectors.is_empty() {
+            return Err(QEMUDiskError::GPTNoFreeSectors());
+        }
+
+        gpt.add_partition(
+            "disk",
+            free_sectors[0].1 * 512,
+            partition_types::LINUX_FS,
+            0,
+            None
+        ).map_err(QEMUDiskError::GPTFailedToCreateMainPartition)?;
+
+        gpt.write().map_err(QEMUDiskError::GPTFailedToSaveConfToDisk)?;
+        file.sync_all()
+            .map_err(QEMUDiskError::SparseDiskCreationError)?;
+
+        Ok(())
+    }
+
+    // Extends the raw image to `size_b` and rewrites both GPT headers for the
+    // new size, the last partition is stretched to the end of the disk.
+    fn relayout_gpt(file: &mut File, size_b: u64, fresh_guids: bool) -> Result<(), QEMUDiskError> {
+        let (header, mut partitions) = Self::read_partition_table(file)?;
+
+        file.set_len(size_b)
+            .map_err(QEMUDiskError::DiskFileResizeError)?;
+
+        let mbr = ProtectiveMBR::with_lb_size((size_b / 512 - 1) as u32);
+        mbr.overwrite_lba0(file)
+            .map_err(QEMUDiskError::DiskFileResizeError)?;
+
+        let guid = if fresh_guids { Uuid::new_v4() } else { header.disk_guid };
+        if fresh_guids {
+            for partition in partitions.values_mut() {
+                partition.part_guid = Uuid::new_v4();
+            }
+        }
+
+        let mut gpt = GptConfig::new()
+            .writable(true)
+            .initialized(false)
+            .logical_block_size(gpt::disk::LogicalBlockSize::Lb512)
+            .create_from_device(Box::new(&mut *file), Some(guid))
+            .map_err(QEMUDiskError::GptOpenError)?;
+
+        gpt.update_partitions(partitions.clone())
+            .map_err(QEMUDiskError::GPTDiskHeaderInitError)?;
+        let last_usable = gpt.primary_header()
+            .ok_or(QEMUDiskError::GPTErrorNoPartitions())?
+            .last_usable;
+
+        partitions.values_mut()
+            .next_back()
+            .ok_or(QEMUDiskError::GPTErrorNoPartitions())?
+            .last_lba = last_usable;
+
+        gpt.update_partitions(partitions)
+            .map_err(QEMUDiskError::GPTDiskHeaderInitError)?;
+        gpt.write().map_err(QEMUDiskError::GPTFailedToSaveConfToDisk)?;
+        file.sync_all()
+            .map_err(QEMUDiskError::DiskFileResizeError)?;
+
+        Ok(())
+    }
+
+    // The gpt crate can only work on raw images, so qcow2 disks are edited
+    // through a temporary raw copy. This copies all of the image data.
+    fn edit_qcow2(path: &Path, backing_file: Option<&Path>, edit: impl FnOnce(&mut File) -> Result<(), QEMUDiskError>) -> Result<(), QEMUDiskError> {
+        let tmp = Self::temporary_path(path);
+        let converted = path.with_extension("qcow2.new");
+
+        Self::edit_through(path, &tmp, &converted, backing_file, edit)
+            .inspect_err(|_| discard(&[&tmp, &converted]))?;
+        remove_file(&tmp)
+            .map_err(|e| QEMUDiskError::TemporaryImageRemoveError(tmp.clone(), e))
+    }
+
+    fn edit_through(path: &Path, tmp: &Path, converted: &Path, backing_file: Option<&Path>, edit: impl FnOnce(&mut File) -> Result<(), QEMUDiskError>) -> Result<(), QEMUDiskError> {
+        qemu_img(&["convert", "-f", "qcow2", "-O", "raw", &path.to_string_lossy(), &tmp.to_string_lossy()])?;
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp)
+            .map_err(QEMUDiskError::DiskFileOpenError)?;
+        edit(&mut file)?;
+        drop(file);
+
+        Self::convert(tmp, converted, backing_file)?;
+        rename(converted, path)
+            .map_err(QEMUDiskError::DiskFileCreationError)
+    }
+
+    fn convert(raw: &Path, qcow2: &Path, backing_file: Option<&Path>) -> Result<(), QEMUDiskError> {
+        let raw = raw.to_string_lossy();
+        let qcow2 = qcow2.to_string_lossy();
+
+        match backing_file {
+            Some(backing) => {
+                let format = Self::image_info(backing)?.format;
+                qemu_img(&["convert", "-f", "raw", "-O", "qcow2", "-B", &backing.to_string_lossy(), "-F", &format, &raw, &qcow2])
+            },
+            None => qemu_img(&["convert", "-f", "raw", "-O", "qcow2", &raw, &qcow2])
+        }
+    }
+
+    fn read_gpt(path: &Path, format: DiskFormat) -> Result<(Uuid, Uuid), QEMUDiskError> {
+        let (header, partitions) = match format {
+            DiskFormat::Raw => Self::open_partition_table(path)?,
+            DiskFormat::Qcow2 => {
+                // Only the primary header is needed, pull it out instead of converting the image
+                let header_copy = Self::temporary_path(path);
+                let table = qemu_img(&["dd", "-f", "qcow2", "-O", "raw", "bs=512", &format!("count={}", GPT_PRIMARY_SECTORS),
+                    &format!("if={}", path.to_string_lossy()), &format!("of={}", header_copy.to_string_lossy())])
+                    .and_then(|_| Self::open_partition_table(&header_copy))
+                    .inspect_err(|_| discard(&[&header_copy]))?;
+                remove_file(&header_copy)
+                    .map_err(|e| QEMUDiskError::TemporaryImageRemoveError(header_copy.clone(), e))?;
+                table
+            }
+        };
+
+        let (_, partition) = partitions
+            .first_key_value()
+            .ok_or(QEMUDiskError::GPTErrorNoPartitions())?;
+
+        Ok((header.disk_guid, partition.part_guid))
+    }
+
+    fn open_partition_table(path: &Path) -> Result<(Header, BTreeMap<u32, Partition>), QEMUDiskError> {
+        let mut file = File::open(path)
+            .map_err(QEMUDiskError::DiskFileOpenError)?;
+        Self::read_partition_table(&mut file)
+    }
+
+    // Only the primary header is used, the backup one is not where the gpt
+    // crate expects it once the image was grown
+    fn read_partition_table(file: &mut File) -> Result<(Header, BTreeMap<u32, Partition>), QEMUDiskError> {
+        let header = gpt::header::read_header_from_arbitrary_device(file, gpt::disk::LogicalBlockSize::Lb512)
+            .map_err(QEMUDiskError::GptOpenError)?;
+        let partitions = gpt::partition::file_read_partitions(file, &header, gpt::disk::LogicalBlockSize::Lb512)
+            .map_err(QEMUDiskError::GptOpenError)?;
+
+        Ok((header, partitions))
+    }
+
+    fn image_size(path: &Path, format: DiskFormat) -> Result<u64, QEMUDiskError> {
+        match format {
+            DiskFormat::Raw => Ok(File::open(path).map_err(QEMUDiskError::DiskFileOpenError)?
+                .metadata().map_err(QEMUDiskError::DiskFileStatsError)?
+                .size()),
+            DiskFormat::Qcow2 => Ok(Self::image_info(path)?.virtual_size)
+        }
+    }
+
+    fn image_info(path: &Path) -> Result<QemuImgInfo, QEMUDiskError> {
+        let output = qemu_img_output(&["info", "--output=json", &path.to_string_lossy()])?;
+        serde_json::from_slice(&output).map_err(QEMUDiskError::QemuImgInfoParseError)
+    }
+
+    fn temporary_path(path: &Path) -> PathBuf {
+        path.with_extension("tmp.raw")
+    }
+
     pub fn path(&self) -> &PathBuf { &self.path }
+    pub fn format(&self) -> DiskFormat { self.format }
     pub fn part_uuid(&self) -> &Uuid { &self.part_uuid }
-    pub fn disk_uuid(&self) -> &Uuid { &self.part_uuid }
+    pub fn disk_uuid(&self) -> &Uuid { &self.disk_uuid }
+}
+
+// Best effort, the error being returned matters more than a leftover file
+fn discard(paths: &[&Path]) {
+    for path in paths {
+        let _ = remove_file(path);
+    }
+}
+
+fn qemu_img(args: &[&str]) -> Result<(), QEMUDiskError> {
+    qemu_img_output(args).map(|_| ())
+}
+
+fn qemu_img_output(args: &[&str]) -> Result<Vec<u8>, QEMUDiskError> {
+    let mut cmd = Command::new(env::var("QEMU_IMG_BIN").unwrap_or(QEMU_IMG_BIN.to_string()));
+    cmd.args(args);
+    debug!("Running {:?}", cmd);
+
+    let output = cmd.output().map_err(QEMUDiskError::QemuImgSpawnError)?;
+
+    if !output.status.success() {
+        return Err(QEMUDiskError::QemuImgFailed(output.status, String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    fn raw_disk(dir: &tempfile::TempDir, size_mb: usize) -> QEMUDisk {
+        QEMUDisk::new(dir.path().join("disk.raw"), DiskFormat::Raw, size_mb, None).unwrap()
+    }
+
+    fn relayout(disk: &QEMUDisk, size_b: u64, fresh_guids: bool) {
+        let mut file = OpenOptions::new().read(true).write(true).open(disk.path()).unwrap();
+        QEMUDisk::relayout_gpt(&mut file, size_b, fresh_guids).unwrap();
+    }
+
+    fn last_partition(disk: &QEMUDisk) -> (Header, Partition) {
+        let (header, partitions) = QEMUDisk::open_partition_table(disk.path()).unwrap();
+        (header, partitions.into_values().next_back().unwrap())
+    }
+
+    #[test]
+    fn grow_keeps_guids_and_stretches_the_partition() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = raw_disk(&dir, 8);
+        let (_, before) = last_partition(&disk);
+
+        relayout(&disk, 16 * MB, false);
+
+        let (header, after) = last_partition(&disk);
+        assert_eq!(std::fs::metadata(disk.path()).unwrap().len(), 16 * MB);
+        assert_eq!(QEMUDisk::read_gpt(disk.path(), DiskFormat::Raw).unwrap(), (*disk.disk_uuid(), *disk.part_uuid()));
+        assert_eq!(after.first_lba, before.first_lba);
+        assert_eq!(after.last_lba, header.last_usable);
+        assert!(after.last_lba > before.last_lba);
+    }
+
+    #[test]
+    fn grow_with_fresh_guids() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = raw_disk(&dir, 8);
+
+        relayout(&disk, 16 * MB, true);
+
+        let (disk_uuid, part_uuid) = QEMUDisk::read_gpt(disk.path(), DiskFormat::Raw).unwrap();
+        assert_ne!(disk_uuid, *disk.disk_uuid());
+        assert_ne!(part_uuid, *disk.part_uuid());
+        assert_eq!(last_partition(&disk).1.last_lba, last_partition(&disk).0.last_usable);
+    }
+
+    #[test]
+    fn shrink_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = raw_disk(&dir, 16);
+
+        assert!(matches!(disk.resize(8), Err(QEMUDiskError::ShrinkNotSupported(_, _, _))));
+        assert_eq!(std::fs::metadata(disk.path()).unwrap().len(), 16 * MB);
+        disk.resize(32).unwrap();
+        assert_eq!(QEMUDisk::read_gpt(disk.path(), DiskFormat::Raw).unwrap(), (*disk.disk_uuid(), *disk.part_uuid()));
+    }
 }
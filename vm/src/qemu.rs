@@ -1,8 +1,11 @@
 use std::{env, process::Stdio};
 use tokio::process::{Child, Command};
 
+use protocol::transport::SERIAL_PORT_NAME;
 use thiserror::Error;
 
+use crate::{qdisk::DiskFormat, vmm::{VMBuilder, VMMError}};
+
 const QEMU_BIN: &'static str = "/usr/bin/qemu-system-aarch64";
 
 #[derive(Error, Debug)]
@@ -12,42 +15,18 @@ pub enum QEMUError {
 }
 
 pub struct QEMURunner {
-    command: Command
-}
-
-pub trait VMBuilder {
-    fn cpu(&mut self, ty: &dyn AsRef<str>);
-    fn machine(&mut self, ty: &dyn AsRef<str>);
-    fn core_count(&mut self, n: usize);
-    fn ram_size(&mut self, size_mb: usize);
-    fn tap_device(&mut self, name: &dyn AsRef<str>);
-    fn mac_addr(&mut self, addr: &dyn AsRef<str>);
-    fn vsock_cid(&mut self, cid: usize);
-    fn kernel(&mut self, image: &dyn AsRef<str>);
-    fn block_device(&mut self, path: &dyn AsRef<str>);
-    fn stdout(&mut self, path: &dyn AsRef<str>);
-    fn arg(&mut self, arg: &dyn AsRef<str>);
+    command: Command,
+    kernel_params: Vec<String>
 }
 
 impl QEMURunner {
     pub fn new() -> Self {
         let qemu = env::var("QEMU_BIN").unwrap_or(QEMU_BIN.to_string());
 
-        Self {
-            command: Command::new(qemu)
-        }
-    }
-
-    pub fn launch(&mut self) -> Result<Child, QEMUError> {
-        println!("cmd: {:?}", self.command);
+        let mut command = Command::new(qemu);
+        command.arg("-nographic");
 
-        self.command.stdin(Stdio::null());
-        self.command.stdout(Stdio::piped());
-        self.command.stderr(Stdio::piped());
-
-        Ok(self.command.spawn()
-                .map_err(QEMUError::FailedToStart)?
-        )
+        Self { command, kernel_params: Vec::new() }
     }
 }
 
@@ -84,15 +63,37 @@ impl VMBuilder for QEMURunner {
         self.command.arg("-kernel").arg(image.as_ref());
     }
 
-    fn block_device(&mut self, path: &dyn AsRef<str>) {
-        self.command.arg("-drive").arg(format!("file={}", path.as_ref()));
+    fn block_device(&mut self, path: &dyn AsRef<str>, format: DiskFormat) {
+        self.command.arg("-drive").arg(format!("file={},format={}", path.as_ref(), format));
     }
 
     fn stdout(&mut self, path: &dyn AsRef<str>) {
         self.command.arg("-serial").arg(format!("file:{}", path.as_ref()));
     }
 
-    fn arg(&mut self, arg: &dyn AsRef<str>) {
-        self.command.arg(arg.as_ref());
+    fn serial_channel(&mut self, socket: &dyn AsRef<str>) {
+        self.command.arg("-chardev").arg(format!("socket,id=channel0,path={}", socket.as_ref()));
+        self.command.arg("-device").arg("virtio-serial-pci,id=virtio-serial0");
+        self.command.arg("-device").arg(format!("virtserialport,bus=virtio-serial0.0,chardev=channel0,name={}", SERIAL_PORT_NAME));
+    }
+
+    fn kernel_param(&mut self, param: &dyn AsRef<str>) {
+        self.kernel_params.push(param.as_ref().to_owned());
+    }
+
+    fn launch(&mut self) -> Result<Child, VMMError> {
+        if !self.kernel_params.is_empty() {
+            self.command.arg("-append").arg(self.kernel_params.join(" "));
+        }
+
+        println!("cmd: {:?}", self.command);
+
+        self.command.stdin(Stdio::null());
+        self.command.stdout(Stdio::piped());
+        self.command.stderr(Stdio::piped());
+
+        Ok(self.command.spawn()
+                .map_err(QEMUError::FailedToStart)?
+        )
     }
 }
@@ -1,15 +1,21 @@
-use std::{collections::HashMap, fs::create_dir, path::PathBuf, sync::Arc, time::Duration};
+use std::{collections::{HashMap, HashSet}, fs::{create_dir, remove_dir_all, rename}, net::SocketAddr, path::{Path, PathBuf}, process::ExitStatus, sync::Arc, time::Duration};
 
 use thiserror::Error;
-use tokio::{io::BufReader, process::Child, select, spawn, sync::{mpsc::{self, channel, Receiver, Sender}, oneshot::error::RecvError, Mutex}, task::{JoinHandle, JoinSet}, time};
+use tokio::{io::{split, BufReader}, process::Child, select, spawn, sync::{mpsc::{self, channel, Receiver, Sender}, oneshot::error::RecvError, Mutex}, task::{JoinHandle, JoinSet}, time};
 use tokio_vsock::VsockStream;
 use log::{debug, error, info, warn};
-use tokio::io::AsyncBufReadExt;
+use futures_util::TryStreamExt;
+use tokio_serde::{formats::SymmetricalJson, SymmetricallyFramed};
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
+use uuid::Uuid;
 
-use crate::{app::{Application, ApplicationConfig, ApplicationError}, daemon::DaemonContext, qemu::{QEMUError, QEMURunner, VMBuilder}, utils::{serde_write, UtilitiesError}, vsock::{ConnectionDispatcher, ConnectionDispatcherError}};
-use protocol::{Command, RealmInfo};
+use crate::{app::{Application, ApplicationConfig, ApplicationError}, bundle::{read_bundle, BundleError}, daemon::DaemonContext, transport::{TransportKind, VsockAcceptor, REALM_SOCKET}, utils::{random_bytes, read_line_opt, serde_write, UtilitiesError}, vmm::{VMBackend, VMBuilder, VMMError}, vsock::ConnectionDispatcher};
+use protocol::{attestation::CHALLENGE_LEN, transport::{Acceptor, Stream, TcpAcceptor, Transport, TransportError, UnixAcceptor, SERIAL_PORT_NAME}, AppStatus, Command, ProvisionRequest, ProvisionResponse, RealmInfo, StopPath};
 use crate::utils::serde_read;
 
+// Configuration the app-manager reported, saved in the realm workdir
+const EFFECTIVE_CONFIG: &str = "app-manager.yaml";
+
 #[derive(Error, Debug)]
 pub enum RealmError {
     #[error("Application id {0} already exists")]
@@ -21,6 +27,18 @@ pub enum RealmError {
     #[error("Cannot create workdir")]
     WorkdirMkdirFail(#[source] std::io::Error),
 
+    #[error("Cannot import into {0:?}, it already exists")]
+    ImportTargetExists(PathBuf),
+
+    #[error("Partition {0} is already used by application {1}")]
+    PartitionInUse(Uuid, String),
+
+    #[error("Cannot move imported application into {0:?}")]
+    ImportRenameError(PathBuf, #[source] std::io::Error),
+
+    #[error("Application bundle error")]
+    BundleError(#[from] BundleError),
+
     #[error("Error while modifing application")]
     AppError(#[from] ApplicationError),
 
@@ -34,10 +52,10 @@ pub enum RealmError {
     RealmIsNotRunning(),
 
     #[error("Realm launching error")]
-    RealmLaunchingError(#[from] QEMUError),
+    RealmLaunchingError(#[from] VMMError),
 
-    #[error("Error receiving Vsock stream")]
-    VsockStreamRecv(#[source] ConnectionDispatcherError),
+    #[error("Realm transport error")]
+    TransportError(#[from] TransportError),
 
     #[error("Error while reading tokio oneshot channel")]
     ChannelError(#[from] RecvError),
@@ -48,6 +66,18 @@ pub enum RealmError {
     #[error("Realm didn't connect")]
     VsockTimeout(),
 
+    #[error("Unexpected response from realm: {0}")]
+    UnexpectedResponse(String),
+
+    #[error("Realm failed the command: {0}")]
+    CommandFailed(String),
+
+    #[error("Realm provisioning task failed")]
+    ProvisioningTaskError(#[from] tokio::task::JoinError),
+
+    #[error("Cannot generate a nonce")]
+    NonceGenerationError(#[source] std::io::Error),
+
     #[error("Utilities error")]
     UtilitiesError(#[from] UtilitiesError),
 
@@ -75,6 +105,8 @@ pub struct NetworkConfig {
 
 #[derive(Debug)]
 pub struct RealmConfig {
+    pub backend: VMBackend,
+
     pub cpu: String,
     pub machine: String,
 
@@ -84,19 +116,47 @@ pub struct RealmConfig {
     pub network_config: NetworkConfig,
     pub vsock_cid: usize,
 
+    pub transport: TransportKind,
+    pub tcp_address: SocketAddr,
+
     pub kernel: PathBuf,
+    pub kernel_params: Vec<String>,
+
+    /// Settings the app-manager applies once connected
+    pub app_manager_config: HashMap<String, String>
 }
 
 enum Request {
     StartApp(String),
-    TerminateApp(String),
+    TerminateApp(String, Option<u64>),
     KillApp(String),
+    RotateKey(String),
+    UpdateApp(String, Uuid),
+    RetryProvision(String),
+    AppStatus(String),
+    Attest(Vec<u8>),
     Shutdown()
 }
 
+#[derive(Debug)]
 enum Response {
     RealmNotConnected,
-    Ok
+    Ok,
+    AttestationToken(Vec<u8>),
+    ExitStatus(ExitStatus),
+    Terminated(ExitStatus, StopPath),
+    AppStatus(AppStatus),
+    Failed(String),
+    Unexpected(protocol::Response)
+}
+
+impl Response {
+    fn unexpected(self) -> RealmError {
+        match self {
+            Response::Unexpected(resp) => RealmError::UnexpectedResponse(format!("{:?}", resp)),
+            resp => RealmError::UnexpectedResponse(format!("{:?}", resp))
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -135,15 +195,94 @@ impl Realm {
         }
     }
 
-    fn configure(&self, builder: &mut dyn VMBuilder) -> Result<(), RealmError> {
+    pub async fn export_application(&self, id: String, path: PathBuf) -> Result<(), RealmError> {
+        // Disks of a running realm are not consistent
+        if self.is_running() {
+            return Err(RealmError::RealmAlreadyRunning());
+        }
+
+        self.apps.get(&id)
+            .ok_or(RealmError::AppDoesNotExist(id.clone()))?
+            .export(&id, &path).await?;
+
+        Ok(())
+    }
+
+    /// Imports an exported application, under `id` or the id it was exported with
+    pub async fn import_application(&mut self, path: PathBuf, id: Option<String>) -> Result<String, RealmError> {
+        // The application id is only known after reading the manifest
+        let staging = self.workdir.join(format!(".import-{}", Uuid::new_v4()));
+        let result = self.import_staged(&path, &staging, id).await;
+
+        if staging.exists() {
+            let _ = remove_dir_all(&staging);
+        }
+
+        result
+    }
+
+    async fn import_staged(&mut self, path: &Path, staging: &Path, id: Option<String>) -> Result<String, RealmError> {
+        let manifest = read_bundle(path, staging).await?;
+        let id = id.unwrap_or(manifest.id.clone());
+
+        if self.apps.contains_key(&id) {
+            return Err(RealmError::AppExists(id));
+        }
+
+        // The app-manager finds the disks by partition GUID, they must stay unique in a realm
+        for (app_id, app) in self.apps.iter() {
+            let info = app.application_info(false);
+            for uuid in [manifest.main_storage.part_uuid, manifest.secure_storage.part_uuid] {
+                if uuid == info.main_partition_uuid || uuid == info.secure_partition_uuid {
+                    return Err(RealmError::PartitionInUse(uuid, app_id.clone()));
+                }
+            }
+        }
+
+        let workdir = self.workdir.join(&id);
+        if workdir.exists() {
+            return Err(RealmError::ImportTargetExists(workdir));
+        }
+
+        rename(staging, &workdir)
+            .map_err(|e| RealmError::ImportRenameError(workdir.clone(), e))?;
+
+        match Application::import(workdir.clone(), manifest).await {
+            Ok(app) => {
+                self.apps.insert(id.clone(), app);
+                Ok(id)
+            },
+            Err(e) => {
+                let _ = remove_dir_all(&workdir);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub async fn resize_application_storage(&mut self, id: String, main_storage_size_mb: Option<usize>, secure_storage_size_mb: Option<usize>) -> Result<(), RealmError> {
+        // The disks can't change under a running VMM
+        if self.is_running() {
+            return Err(RealmError::RealmAlreadyRunning());
+        }
+
+        self.apps.get_mut(&id)
+            .ok_or(RealmError::AppDoesNotExist(id))?
+            .resize(main_storage_size_mb, secure_storage_size_mb).await?;
+
+        Ok(())
+    }
+
+    // The realm handler drops its receiver once the realm exits
+    fn is_running(&self) -> bool {
+        self.txrx.as_ref().is_some_and(|(tx, _)| !tx.is_closed())
+    }
+
+    fn configure(&self, builder: &mut dyn VMBuilder, transport: &Transport) -> Result<(), RealmError> {
         let log = self.workdir.join("console.log");
         builder.stdout(
             &log.to_str()
                 .ok_or(RealmError::PathDecodingError(log.clone()))?
         );
-        // builder.arg(&"-serial");
-        // builder.arg(&"tcp:localhost:1337");
-
 
         builder.cpu(&self.config.cpu);
         builder.machine(&self.config.machine);
@@ -153,6 +292,18 @@ impl Realm {
         builder.mac_addr(&self.config.network_config.mac_addr);
         builder.vsock_cid(self.config.vsock_cid);
 
+        if let Transport::Serial(_) = transport {
+            let socket = self.workdir.join(REALM_SOCKET);
+            builder.serial_channel(
+                &socket.to_str()
+                    .ok_or(RealmError::PathDecodingError(socket.clone()))?
+            );
+        }
+        builder.kernel_param(&format!("app_manager.transport={}", transport));
+        for param in self.config.kernel_params.iter() {
+            builder.kernel_param(param);
+        }
+
         let kernel_path = &self.config.kernel;
         builder.kernel(
             &kernel_path.to_str()
@@ -166,16 +317,40 @@ impl Realm {
         Ok(())
     }
 
-    pub fn launch(&mut self, runner: &mut QEMURunner, ctx: Arc<DaemonContext>, taskset: &mut JoinSet<Result<(), RealmError>>) -> Result<(), RealmError> {
-        if self.txrx.is_some() {
+    // Binds the host end before the realm is started so it can connect right away
+    fn open_channel(&self, ctx: &Arc<DaemonContext>) -> Result<(Box<dyn Acceptor>, Transport), RealmError> {
+        Ok(match self.config.transport {
+            TransportKind::Vsock => (
+                Box::new(VsockAcceptor { cid: self.config.vsock_cid as u32, ctx: ctx.clone() }),
+                Transport::Vsock(ctx.vsock_port)
+            ),
+
+            TransportKind::Serial => (
+                Box::new(UnixAcceptor::bind(&self.workdir.join(REALM_SOCKET))?),
+                Transport::Serial(SERIAL_PORT_NAME.to_owned())
+            ),
+
+            TransportKind::Tcp => (
+                Box::new(TcpAcceptor::bind(self.config.tcp_address)?),
+                Transport::Tcp(self.config.tcp_address)
+            )
+        })
+    }
+
+    /// With `reformat` the storage of every application is wiped and provisioned again
+    pub fn launch(&mut self, ctx: Arc<DaemonContext>, taskset: &mut JoinSet<Result<(), RealmError>>, reformat: bool) -> Result<(), RealmError> {
+        if self.is_running() {
             return Err(RealmError::RealmAlreadyRunning());
         }
 
-        self.configure(runner)?;
-        let process = runner.launch()?;
+        let (host_channel, transport) = self.open_channel(&ctx)?;
 
-        let cid = self.config.vsock_cid as u32;
-        let realm_info = self.realm_info();
+        let mut runner = self.config.backend.builder(&self.workdir);
+        self.configure(runner.as_mut(), &transport)?;
+
+        let process = runner.launch()?;
+        let realm_info = self.realm_info(reformat);
+        let effective_config = self.workdir.join(EFFECTIVE_CONFIG);
 
         let (tx1, rx1) = channel(1);
         let (tx2, rx2) = channel(1);
@@ -183,45 +358,50 @@ impl Realm {
         self.txrx = Some((tx1, rx2));
 
         taskset.spawn(async move {
-            Self::handle_realm(ctx.clone(), process, tx2, rx1, realm_info, cid).await
+            Self::handle_realm(ctx.clone(), process, tx2, rx1, realm_info, host_channel, effective_config).await
         });
 
         Ok(())
     }
 
-    async fn handle_realm(ctx: Arc<DaemonContext>, mut process: Child, tx: Sender<Response>, mut rx: Receiver<Request>, info: RealmInfo, cid: u32) -> Result<(), RealmError> {
-        let mut stream_request = ctx.dispatcher
-            .lock().await
-            .request_stream(cid)
-            .map_err(RealmError::VsockStreamRecv)?;
+    async fn handle_realm(ctx: Arc<DaemonContext>, mut process: Child, tx: Sender<Response>, mut rx: Receiver<Request>, info: RealmInfo, mut channel: Box<dyn Acceptor>, effective_config: PathBuf) -> Result<(), RealmError> {
+        let stream_request = channel.accept();
+        tokio::pin!(stream_request);
 
         let timeout = time::sleep(Duration::from_secs(90));
         tokio::pin!(timeout);
 
+        let mut connected = false;
         let mut stream = None;
 
-        let mut stdout = BufReader::new(process.stdout.take().unwrap());
-        let mut stderr = BufReader::new(process.stderr.take().unwrap());
+        // Owns the stream until the realm finishes provisioning
+        let mut provisioning: Option<JoinHandle<Result<Box<dyn Stream>, RealmError>>> = None;
 
-        let mut stdout_open = true;
-        let mut stderr_open = true;
+        // Backends that log the console to a file don't pipe stdout
+        let mut stdout = process.stdout.take().map(BufReader::new);
+        let mut stderr = process.stderr.take().map(BufReader::new);
 
         loop {
             let mut stdout_line = String::new();
             let mut stderr_line = String::new();
 
             select! {
-                v = &mut stream_request, if stream.is_none() => {
+                v = &mut stream_request, if !connected => {
                     let mut socket = v?;
                     serde_write(&mut socket, &info).await?;
-                    stream = Some(socket);
+                    connected = true;
+                    provisioning = Some(spawn(Self::serve_provisioning(ctx.clone(), socket, effective_config.clone())));
                 }
 
-                _ = &mut timeout => {
-                    if stream.is_none() {
-                        warn!("Timeout watiting for realm to connect to vsock");
-                        break;
-                    }
+                v = async { provisioning.as_mut().unwrap().await }, if provisioning.is_some() => {
+                    provisioning = None;
+                    stream = Some(v??);
+                    info!("Realm provisioned");
+                }
+
+                _ = &mut timeout, if !connected => {
+                    warn!("Timeout watiting for realm to connect");
+                    break;
                 }
 
                 v = process.wait() => {
@@ -230,18 +410,18 @@ impl Realm {
                     break;
                 }
 
-                v = stdout.read_line(&mut stdout_line), if stdout_open => {
+                v = read_line_opt(&mut stdout, &mut stdout_line) => {
                     if v.map_err(RealmError::RealmIOReadError)? == 0 {
-                        stdout_open = false;
+                        stdout = None;
                         continue;
                     }
 
                     info!("stdout: {}", stdout_line);
                 }
 
-                v = stderr.read_line(&mut stderr_line), if stderr_open => {
+                v = read_line_opt(&mut stderr, &mut stderr_line) => {
                     if v.map_err(RealmError::RealmIOReadError)? == 0 {
-                        stderr_open = false;
+                        stderr = None;
                         continue;
                     }
 
@@ -262,12 +442,15 @@ impl Realm {
                                 }
                             }
 
-                            Request::TerminateApp(id) => {
+                            Request::TerminateApp(id, grace) => {
                                 if let Some(mut s) = stream.as_mut() {
-                                    serde_write(&mut s, Command::TerminateApp(id)).await?;
-                                    let _ = serde_read::<protocol::Response>(&mut s).await?;
+                                    serde_write(&mut s, Command::TerminateApp { app: id, grace }).await?;
 
-                                    Response::Ok
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::Terminated { status, stop } => Response::Terminated(status, stop),
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
                                 } else {
                                     Response::RealmNotConnected
                                 }
@@ -276,9 +459,12 @@ impl Realm {
                             Request::KillApp(id) => {
                                 if let Some(mut s) = stream.as_mut() {
                                     serde_write(&mut s, Command::KillApp(id)).await?;
-                                    let _ = serde_read::<protocol::Response>(&mut s).await?;
 
-                                    Response::Ok
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::ExitStatus(status) => Response::ExitStatus(status),
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
                                 } else {
                                     Response::RealmNotConnected
                                 }
@@ -287,9 +473,80 @@ impl Realm {
                             Request::StartApp(id) => {
                                 if let Some(mut s) = stream.as_mut() {
                                     serde_write(&mut s, Command::StartApp(id)).await?;
-                                    let _ = serde_read::<protocol::Response>(&mut s).await?;
 
-                                    Response::Ok
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        _ => Response::Ok
+                                    }
+                                } else {
+                                    Response::RealmNotConnected
+                                }
+                            },
+
+                            Request::Attest(challenge) => {
+                                if let Some(mut s) = stream.as_mut() {
+                                    serde_write(&mut s, Command::Attest { challenge }).await?;
+
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::AttestationToken(token) => Response::AttestationToken(token),
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
+                                } else {
+                                    Response::RealmNotConnected
+                                }
+                            },
+
+                            Request::RotateKey(id) => {
+                                if let Some(mut s) = stream.as_mut() {
+                                    serde_write(&mut s, Command::RotateKey(id)).await?;
+
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::Ok => Response::Ok,
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
+                                } else {
+                                    Response::RealmNotConnected
+                                }
+                            },
+
+                            Request::UpdateApp(id, image_uuid) => {
+                                if let Some(mut s) = stream.as_mut() {
+                                    serde_write(&mut s, Command::UpdateApp { app: id, image_uuid }).await?;
+
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::Ok => Response::Ok,
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
+                                } else {
+                                    Response::RealmNotConnected
+                                }
+                            },
+
+                            Request::RetryProvision(id) => {
+                                if let Some(mut s) = stream.as_mut() {
+                                    serde_write(&mut s, Command::RetryProvision(id)).await?;
+
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::AppStatus(status) => Response::AppStatus(status),
+                                        protocol::Response::Error(e) => Response::Failed(e),
+                                        resp => Response::Unexpected(resp)
+                                    }
+                                } else {
+                                    Response::RealmNotConnected
+                                }
+                            },
+
+                            Request::AppStatus(id) => {
+                                if let Some(mut s) = stream.as_mut() {
+                                    serde_write(&mut s, Command::AppStatus(id)).await?;
+
+                                    match serde_read::<protocol::Response>(&mut s).await? {
+                                        protocol::Response::AppStatus(status) => Response::AppStatus(status),
+                                        resp => Response::Unexpected(resp)
+                                    }
                                 } else {
                                     Response::RealmNotConnected
                                 }
@@ -311,9 +568,86 @@ impl Realm {
     }
 
 
-    fn realm_info(&self) -> RealmInfo {
+    // Answers the realm's requests while it provisions its applications,
+    // images are released only to realms the verifier accepts
+    async fn serve_provisioning(ctx: Arc<DaemonContext>, stream: Box<dyn Stream>, effective_config: PathBuf) -> Result<Box<dyn Stream>, RealmError> {
+        let mut nonces = HashMap::new();
+        let mut released = HashSet::new();
+
+        // The effective config is not answered and the next request may
+        // arrive in the same read, a single framed reader keeps it
+        let (reader, mut writer) = split(stream);
+        let mut requests = SymmetricallyFramed::new(
+            FramedRead::new(reader, LengthDelimitedCodec::new()),
+            SymmetricalJson::<ProvisionRequest>::default()
+        );
+
+        loop {
+            let req = requests.try_next().await
+                .map_err(UtilitiesError::SerdeReadError)?
+                .ok_or(UtilitiesError::StreamIsClosed())?;
+            debug!("Received provisioning request: {:?}", req);
+
+            let resp = match req {
+                // The realm waits for commands after this, nothing is buffered
+                ProvisionRequest::Done() => break Ok(requests.into_inner().into_inner().unsplit(writer)),
+
+                // Informational, kept next to the console log
+                ProvisionRequest::EffectiveConfig(config) => {
+                    info!("Realm app-manager config:\n{}", config);
+                    if let Err(e) = tokio::fs::write(&effective_config, config).await {
+                        warn!("Cannot save app-manager config to {:?}: {}", effective_config, e);
+                    }
+                    continue;
+                },
+
+                ProvisionRequest::ImageNonce(uuid) => {
+                    let nonce = random_bytes(CHALLENGE_LEN).await
+                        .map_err(RealmError::NonceGenerationError)?;
+                    nonces.insert(uuid, nonce.clone());
+                    ProvisionResponse::Nonce(nonce)
+                },
+
+                ProvisionRequest::ReleaseImage { uuid, token } => {
+                    match (ctx.verifier.as_ref(), nonces.remove(&uuid)) {
+                        (None, _) => ProvisionResponse::Denied("no reference values configured".to_owned()),
+                        (_, None) => ProvisionResponse::Denied(format!("no nonce issued for {}", uuid)),
+                        (Some(verifier), Some(nonce)) => match verifier.release(&uuid, &token, &nonce) {
+                            Ok(rot) => {
+                                released.insert(uuid);
+                                ProvisionResponse::RootOfTrust(rot)
+                            },
+                            Err(e) => {
+                                warn!("Refusing to release image {}: {}", uuid, e);
+                                ProvisionResponse::Denied(e.to_string())
+                            }
+                        }
+                    }
+                },
+
+                // Only images released to this realm are served
+                ProvisionRequest::ImageChunk { uuid, offset } => {
+                    match ctx.verifier.as_ref().filter(|_| released.contains(&uuid)) {
+                        None => ProvisionResponse::Denied(format!("image {} was not released", uuid)),
+                        Some(verifier) => match verifier.image_chunk(&uuid, offset).await {
+                            Ok(chunk) => ProvisionResponse::ImageChunk(chunk),
+                            Err(e) => {
+                                warn!("Cannot serve image {}: {}", uuid, e);
+                                ProvisionResponse::Denied(e.to_string())
+                            }
+                        }
+                    }
+                }
+            };
+
+            serde_write(&mut writer, resp).await?;
+        }
+    }
+
+    fn realm_info(&self, reformat: bool) -> RealmInfo {
         RealmInfo {
-            apps: self.apps.iter().map(|(id, app)| (id.clone(), app.application_info())).collect()
+            apps: self.apps.iter().map(|(id, app)| (id.clone(), app.application_info(reformat))).collect(),
+            config: self.config.app_manager_config.clone()
         }
     }
 
@@ -330,21 +664,82 @@ impl Realm {
     pub async fn start_app(&mut self, id: String) -> Result<(), RealmError> {
         match self.send_request(Request::StartApp(id)).await? {
             Response::Ok => Ok(()),
-            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning())
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
         }
     }
 
-    pub async fn terminate_app(&mut self, id: String) -> Result<(), RealmError> {
-        match self.send_request(Request::TerminateApp(id)).await? {
-            Response::Ok => Ok(()),
-            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning())
+    /// The realm's grace period applies unless `grace` is given, an
+    /// application that already exited reports how it ended
+    pub async fn terminate_app(&mut self, id: String, grace: Option<u64>) -> Result<(ExitStatus, StopPath), RealmError> {
+        match self.send_request(Request::TerminateApp(id, grace)).await? {
+            Response::Terminated(status, stop) => Ok((status, stop)),
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
         }
     }
 
-    pub async fn kill_app(&mut self, id: String) -> Result<(), RealmError> {
+    pub async fn kill_app(&mut self, id: String) -> Result<ExitStatus, RealmError> {
         match self.send_request(Request::KillApp(id)).await? {
+            Response::ExitStatus(status) => Ok(status),
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
+        }
+    }
+
+    pub async fn rotate_key(&mut self, id: String) -> Result<(), RealmError> {
+        match self.send_request(Request::RotateKey(id)).await? {
             Response::Ok => Ok(()),
-            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning())
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
+        }
+    }
+
+    /// The application keeps the new image on later launches
+    pub async fn update_app(&mut self, id: String, image_uuid: Uuid) -> Result<(), RealmError> {
+        if !self.apps.contains_key(&id) {
+            return Err(RealmError::AppDoesNotExist(id));
+        }
+
+        match self.send_request(Request::UpdateApp(id.clone(), image_uuid)).await? {
+            Response::Ok => {
+                self.apps.get_mut(&id).unwrap().set_image(image_uuid);
+                Ok(())
+            },
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
+        }
+    }
+
+    /// Status of the application after another attempt, the retry itself may fail again
+    pub async fn retry_provision(&mut self, id: String) -> Result<AppStatus, RealmError> {
+        match self.send_request(Request::RetryProvision(id)).await? {
+            Response::AppStatus(status) => Ok(status),
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
+        }
+    }
+
+    pub async fn app_status(&mut self, id: String) -> Result<AppStatus, RealmError> {
+        match self.send_request(Request::AppStatus(id)).await? {
+            Response::AppStatus(status) => Ok(status),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
+        }
+    }
+
+    pub async fn attest(&mut self, challenge: Vec<u8>) -> Result<Vec<u8>, RealmError> {
+        match self.send_request(Request::Attest(challenge)).await? {
+            Response::AttestationToken(token) => Ok(token),
+            Response::Failed(e) => Err(RealmError::CommandFailed(e)),
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
         }
     }
 
@@ -352,7 +747,8 @@ impl Realm {
         debug!("Sending shutdown request");
         match self.send_request(Request::Shutdown()).await? {
             Response::Ok => Ok(()),
-            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning())
+            Response::RealmNotConnected => Err(RealmError::RealmIsNotRunning()),
+            resp => Err(resp.unexpected())
         }
     }
 }
@@ -0,0 +1,45 @@
+use std::{io, sync::Arc};
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use protocol::transport::{Acceptor, Stream, TransportError};
+
+use crate::daemon::DaemonContext;
+
+/// Name of the unix socket in the realm workdir serving the serial channel
+pub const REALM_SOCKET: &str = "realm.sock";
+
+/// How the realm connects back to the host
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum TransportKind {
+    /// vsock, needs vhost_vsock on the host
+    Vsock,
+
+    /// virtio-serial port backed by a unix socket in the realm workdir
+    Serial,
+
+    /// TCP over the realm's network, unauthenticated and for testing only
+    Tcp
+}
+
+/// Host end of the vsock transport, the daemon owns the only vsock listener
+/// and hands out connections by the cid of the realm
+pub struct VsockAcceptor {
+    pub cid: u32,
+    pub ctx: Arc<DaemonContext>
+}
+
+#[async_trait]
+impl Acceptor for VsockAcceptor {
+    async fn accept(&mut self) -> Result<Box<dyn Stream>, TransportError> {
+        let request = self.ctx.dispatcher
+            .lock().await
+            .request_stream(self.cid)
+            .map_err(|e| TransportError::AcceptError(io::Error::other(e)))?;
+
+        let stream = request.await
+            .map_err(|e| TransportError::AcceptError(io::Error::other(e)))?;
+
+        Ok(Box::new(stream))
+    }
+}
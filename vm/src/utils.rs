@@ -2,7 +2,7 @@ use thiserror::Error;
 use tokio_serde::{formats::SymmetricalJson, Framed, SymmetricallyFramed};
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite};
 use futures_util::{SinkExt, TryStreamExt};
 
 #[derive(Error, Debug)]
@@ -31,3 +31,17 @@ pub async fn serde_write(stream: impl AsyncWrite + Unpin, obj: impl Serialize +
     let mut serialized = SymmetricallyFramed::new(length_delimited, SymmetricalJson::default());
     serialized.send(obj).await.map_err(UtilitiesError::SerdeWriteError)
 }
+
+pub async fn read_line_opt<T: AsyncBufRead + Unpin>(reader: &mut Option<T>, buf: &mut String) -> std::io::Result<usize> {
+    match reader.as_mut() {
+        Some(reader) => reader.read_line(buf).await,
+        None => std::future::pending().await
+    }
+}
+
+pub async fn random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    tokio::fs::File::open("/dev/urandom").await?
+        .read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
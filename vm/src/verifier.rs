@@ -0,0 +1,610 @@
+use std::{collections::HashMap, io::SeekFrom, path::{Path, PathBuf}};
+
+use log::{debug, info, warn};
+use p384::ecdsa::{signature::Verifier as _, Signature, VerifyingKey};
+use protocol::attestation::{pad_challenge, MockToken};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use uuid::Uuid;
+
+// CCA attestation token claims, see the RMM specification
+const CCA_TOKEN_TAG: u64 = 399;
+const CCA_PLATFORM_TOKEN: i64 = 44234;
+const CCA_REALM_TOKEN: i64 = 44241;
+const COSE_SIGN1_TAG: u64 = 18;
+const COSE_ALG: i64 = 1;
+const COSE_ES384: i64 = -35;
+const CHALLENGE: i64 = 10;
+const REALM_PUBLIC_KEY: i64 = 44237;
+const REALM_INITIAL_MEASUREMENT: i64 = 44238;
+const REALM_PUBLIC_KEY_HASH_ALGO: i64 = 44240;
+
+// COSE_Key labels of an EC2 key on P-384
+const COSE_KEY_KTY: i64 = 1;
+const COSE_KEY_CRV: i64 = -1;
+const COSE_KEY_X: i64 = -2;
+const COSE_KEY_Y: i64 = -3;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P384: i64 = 2;
+
+/// Images are handed out in chunks of this size over the provisioning channel
+const IMAGE_CHUNK: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum VerifierError {
+    #[error("Cannot read reference values from {0:?}")]
+    ReadError(PathBuf, #[source] std::io::Error),
+
+    #[error("Invalid reference values in {0:?}")]
+    ParseError(PathBuf, #[source] serde_json::Error),
+
+    #[error("Invalid hex string `{0}` in reference values")]
+    HexError(String),
+
+    #[error("Invalid platform key in reference values")]
+    InvalidPlatformKey(),
+
+    #[error("No platform key configured, CCA tokens can't be verified")]
+    NoPlatformKey(),
+
+    #[error("Mock attestation tokens are not accepted")]
+    MockTokenRejected(),
+
+    #[error("Malformed attestation token: {0}")]
+    MalformedToken(&'static str),
+
+    #[error("Signature of the {0} token doesn't verify")]
+    BadSignature(&'static str),
+
+    #[error("Unsupported realm public key hash algorithm `{0}`")]
+    UnsupportedHashAlgorithm(String),
+
+    #[error("Platform token doesn't vouch for the realm attestation key")]
+    UnboundRealmKey(),
+
+    #[error("Token is not bound to the issued nonce")]
+    ChallengeMismatch(),
+
+    #[error("Realm measurement {0} doesn't match any reference value")]
+    UnknownMeasurement(String),
+
+    #[error("No root of trust known for image {0}")]
+    UnknownImage(Uuid),
+
+    #[error("Cannot read image {0:?}")]
+    ImageReadError(PathBuf, #[source] std::io::Error)
+}
+
+#[derive(Deserialize)]
+struct ReferenceValues {
+    platform_key: Option<String>,
+    measurements: Vec<String>,
+    images: HashMap<Uuid, ImageReference>
+}
+
+#[derive(Deserialize)]
+struct ImageReference {
+    root_of_trust: String,
+    image: PathBuf
+}
+
+#[derive(Debug)]
+struct ReleasedImage {
+    root_of_trust: Vec<u8>,
+    image: PathBuf
+}
+
+/// Local stand-in for a verifier in front of the image registry: releases
+/// the root of trust and the contents of an image only to realms presenting
+/// a CCA token signed by the platform, bound to the issued nonce and
+/// carrying a known initial measurement.
+#[derive(Debug)]
+pub struct Verifier {
+    platform_key: Option<VerifyingKey>,
+    accept_mock_tokens: bool,
+    measurements: Vec<Vec<u8>>,
+    images: HashMap<Uuid, ReleasedImage>
+}
+
+impl Verifier {
+    /// Image paths are relative to the reference values file
+    pub fn load(path: &Path, accept_mock_tokens: bool) -> Result<Self, VerifierError> {
+        let content = std::fs::read(path)
+            .map_err(|e| VerifierError::ReadError(path.to_owned(), e))?;
+        let values: ReferenceValues = serde_json::from_slice(&content)
+            .map_err(|e| VerifierError::ParseError(path.to_owned(), e))?;
+
+        let decode = |v: &String| hex::decode(v).map_err(|_| VerifierError::HexError(v.clone()));
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        let platform_key = values.platform_key.as_ref()
+            .map(|key| VerifyingKey::from_sec1_bytes(&decode(key)?).map_err(|_| VerifierError::InvalidPlatformKey()))
+            .transpose()?;
+
+        let verifier = Self {
+            platform_key,
+            accept_mock_tokens,
+            measurements: values.measurements.iter().map(decode).collect::<Result<_, _>>()?,
+            images: values.images.iter()
+                .map(|(uuid, image)| Ok((*uuid, ReleasedImage { root_of_trust: decode(&image.root_of_trust)?, image: dir.join(&image.image) })))
+                .collect::<Result<_, VerifierError>>()?
+        };
+
+        if verifier.platform_key.is_none() {
+            warn!("No platform key in {:?}, only mock tokens can be verified", path);
+        }
+        if accept_mock_tokens {
+            warn!("Accepting unsigned mock attestation tokens");
+        }
+
+        info!("Loaded {} reference measurements and {} images from {:?}", verifier.measurements.len(), verifier.images.len(), path);
+        Ok(verifier)
+    }
+
+    /// Root of trust of the image, the caller may serve its chunks once this succeeds
+    pub fn release(&self, uuid: &Uuid, token: &[u8], nonce: &[u8]) -> Result<Vec<u8>, VerifierError> {
+        let claims = self.verify(token)?;
+
+        if pad_challenge(nonce).map(|n| n.to_vec()) != Some(claims.challenge) {
+            return Err(VerifierError::ChallengeMismatch());
+        }
+
+        if !self.measurements.contains(&claims.measurement) {
+            return Err(VerifierError::UnknownMeasurement(hex::encode(&claims.measurement)));
+        }
+
+        debug!("Realm with measurement {} attested for image {}", hex::encode(&claims.measurement), uuid);
+        self.images.get(uuid)
+            .map(|image| image.root_of_trust.clone())
+            .ok_or(VerifierError::UnknownImage(*uuid))
+    }
+
+    /// Part of the image starting at `offset`, empty past its end
+    pub async fn image_chunk(&self, uuid: &Uuid, offset: u64) -> Result<Vec<u8>, VerifierError> {
+        let path = &self.images.get(uuid).ok_or(VerifierError::UnknownImage(*uuid))?.image;
+        let err = |e| VerifierError::ImageReadError(path.clone(), e);
+
+        let mut file = tokio::fs::File::open(path).await.map_err(err)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(err)?;
+
+        let mut chunk = Vec::with_capacity(IMAGE_CHUNK);
+        file.take(IMAGE_CHUNK as u64).read_to_end(&mut chunk).await.map_err(err)?;
+        Ok(chunk)
+    }
+
+    // Claims of a token whose signatures check out
+    fn verify(&self, token: &[u8]) -> Result<Claims, VerifierError> {
+        if let Ok(mock) = serde_json::from_slice::<MockToken>(token) {
+            if !self.accept_mock_tokens {
+                return Err(VerifierError::MockTokenRejected());
+            }
+            return Ok(Claims { challenge: mock.challenge, measurement: mock.measurement });
+        }
+
+        let platform_key = self.platform_key.as_ref().ok_or(VerifierError::NoPlatformKey())?;
+        Claims::parse(token, platform_key)
+    }
+}
+
+struct Claims {
+    challenge: Vec<u8>,
+    measurement: Vec<u8>
+}
+
+impl Claims {
+    // The realm token is signed by the realm attestation key (RAK), the
+    // platform token by the platform key and bound to a hash of the RAK
+    fn parse(token: &[u8], platform_key: &VerifyingKey) -> Result<Self, VerifierError> {
+        let (token, _) = Cbor::decode(token)?;
+        let token = token.untag(CCA_TOKEN_TAG)?;
+        let platform = Sign1::decode(token.get(CCA_PLATFORM_TOKEN)?.bytes()?)?;
+        let realm = Sign1::decode(token.get(CCA_REALM_TOKEN)?.bytes()?)?;
+
+        let (claims, _) = Cbor::decode(&realm.payload)?;
+        let rak = claims.get(REALM_PUBLIC_KEY)?.bytes()?;
+        realm.verify(&public_key(rak)?, "realm")?;
+        platform.verify(platform_key, "platform")?;
+
+        let (platform_claims, _) = Cbor::decode(&platform.payload)?;
+        let rak_hash = hash(claims.get(REALM_PUBLIC_KEY_HASH_ALGO)?.text()?, rak)?;
+        if platform_claims.get(CHALLENGE)?.bytes()? != rak_hash.as_slice() {
+            return Err(VerifierError::UnboundRealmKey());
+        }
+
+        Ok(Self {
+            challenge: claims.get(CHALLENGE)?.bytes()?.to_vec(),
+            measurement: claims.get(REALM_INITIAL_MEASUREMENT)?.bytes()?.to_vec()
+        })
+    }
+}
+
+// The RAK comes either as a SEC1 point or as a COSE_Key depending on the RMM version
+fn public_key(key: &[u8]) -> Result<VerifyingKey, VerifierError> {
+    if let Ok(key) = VerifyingKey::from_sec1_bytes(key) {
+        return Ok(key);
+    }
+
+    let (key, _) = Cbor::decode(key)?;
+    if key.get(COSE_KEY_KTY)?.int() != Some(COSE_KTY_EC2) || key.get(COSE_KEY_CRV)?.int() != Some(COSE_CRV_P384) {
+        return Err(VerifierError::MalformedToken("realm public key is not on P-384"));
+    }
+
+    let point = [&[0x04][..], key.get(COSE_KEY_X)?.bytes()?, key.get(COSE_KEY_Y)?.bytes()?].concat();
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| VerifierError::MalformedToken("invalid realm public key"))
+}
+
+fn hash(algorithm: &str, data: &[u8]) -> Result<Vec<u8>, VerifierError> {
+    match algorithm {
+        "sha-256" => Ok(Sha256::digest(data).to_vec()),
+        "sha-384" => Ok(Sha384::digest(data).to_vec()),
+        "sha-512" => Ok(Sha512::digest(data).to_vec()),
+        _ => Err(VerifierError::UnsupportedHashAlgorithm(algorithm.to_owned()))
+    }
+}
+
+// COSE_Sign1: [protected, unprotected, payload, signature]
+struct Sign1 {
+    protected: Vec<u8>,
+    payload: Vec<u8>,
+    signature: Vec<u8>
+}
+
+impl Sign1 {
+    fn decode(data: &[u8]) -> Result<Self, VerifierError> {
+        let (item, _) = Cbor::decode(data)?;
+        let sign1 = match item.untag(COSE_SIGN1_TAG)? {
+            Cbor::Array(items) if items.len() == 4 => Self {
+                protected: items[0].bytes()?.to_vec(),
+                payload: items[2].bytes()?.to_vec(),
+                signature: items[3].bytes()?.to_vec()
+            },
+            _ => return Err(VerifierError::MalformedToken("token is not a COSE_Sign1"))
+        };
+
+        let (protected, _) = Cbor::decode(&sign1.protected)?;
+        if protected.get(COSE_ALG)?.int() != Some(COSE_ES384) {
+            return Err(VerifierError::MalformedToken("token is not signed with ES384"));
+        }
+
+        Ok(sign1)
+    }
+
+    // Sig_structure: ["Signature1", protected, external_aad, payload]
+    fn verify(&self, key: &VerifyingKey, token: &'static str) -> Result<(), VerifierError> {
+        let mut tbs = vec![0x84, 0x6a];
+        tbs.extend_from_slice(b"Signature1");
+        Cbor::encode_bytes(&mut tbs, &self.protected);
+        Cbor::encode_bytes(&mut tbs, &[]);
+        Cbor::encode_bytes(&mut tbs, &self.payload);
+
+        let signature = Signature::from_slice(&self.signature)
+            .map_err(|_| VerifierError::MalformedToken("invalid ES384 signature"))?;
+        key.verify(&tbs, &signature).map_err(|_| VerifierError::BadSignature(token))
+    }
+}
+
+// Just enough CBOR to pull claims out of a token, definite lengths only
+enum Cbor {
+    Uint(u64),
+
+    /// -1 - n
+    Negative(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Tag(u64, Box<Cbor>),
+
+    /// Floats and simple values, claims we look at are never one
+    Other
+}
+
+impl Cbor {
+    fn decode(data: &[u8]) -> Result<(Cbor, &[u8]), VerifierError> {
+        let (&head, mut rest) = data.split_first().ok_or(VerifierError::MalformedToken("truncated CBOR"))?;
+        let major = head >> 5;
+
+        let arg = match head & 0x1f {
+            n @ 0..=23 => n as u64,
+            n @ 24..=27 => {
+                let len = 1usize << (n - 24);
+                if rest.len() < len {
+                    return Err(VerifierError::MalformedToken("truncated CBOR"));
+                }
+                let (bytes, tail) = rest.split_at(len);
+                rest = tail;
+                bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+            },
+            _ => return Err(VerifierError::MalformedToken("indefinite length CBOR"))
+        };
+
+        Ok(match major {
+            0 => (Cbor::Uint(arg), rest),
+            1 => (Cbor::Negative(arg), rest),
+            2 => {
+                let (bytes, rest) = Cbor::take(rest, arg)?;
+                (Cbor::Bytes(bytes), rest)
+            },
+            3 => {
+                let (bytes, rest) = Cbor::take(rest, arg)?;
+                let text = String::from_utf8(bytes).map_err(|_| VerifierError::MalformedToken("invalid UTF-8 in CBOR text"))?;
+                (Cbor::Text(text), rest)
+            },
+            4 => {
+                let mut items = Vec::new();
+                for _ in 0..arg {
+                    let (item, tail) = Cbor::decode(rest)?;
+                    items.push(item);
+                    rest = tail;
+                }
+                (Cbor::Array(items), rest)
+            },
+            5 => {
+                let mut entries = Vec::new();
+                for _ in 0..arg {
+                    let (key, tail) = Cbor::decode(rest)?;
+                    let (value, tail) = Cbor::decode(tail)?;
+                    entries.push((key, value));
+                    rest = tail;
+                }
+                (Cbor::Map(entries), rest)
+            },
+            6 => {
+                let (item, rest) = Cbor::decode(rest)?;
+                (Cbor::Tag(arg, Box::new(item)), rest)
+            },
+            _ => (Cbor::Other, rest)
+        })
+    }
+
+    fn take(rest: &[u8], len: u64) -> Result<(Vec<u8>, &[u8]), VerifierError> {
+        if (rest.len() as u64) < len {
+            return Err(VerifierError::MalformedToken("truncated CBOR"));
+        }
+        let (bytes, tail) = rest.split_at(len as usize);
+        Ok((bytes.to_vec(), tail))
+    }
+
+    fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+        let len = bytes.len() as u64;
+        match len {
+            0..=23 => out.push(0x40 | len as u8),
+            24..=0xff => out.extend_from_slice(&[0x58, len as u8]),
+            0x100..=0xffff => { out.push(0x59); out.extend_from_slice(&(len as u16).to_be_bytes()); },
+            0x10000..=0xffff_ffff => { out.push(0x5a); out.extend_from_slice(&(len as u32).to_be_bytes()); },
+            _ => { out.push(0x5b); out.extend_from_slice(&len.to_be_bytes()); }
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    // Tags are optional on some token producers
+    fn untag(&self, tag: u64) -> Result<&Cbor, VerifierError> {
+        match self {
+            Cbor::Tag(t, item) if *t == tag => Ok(item),
+            Cbor::Tag(_, _) => Err(VerifierError::MalformedToken("unexpected CBOR tag")),
+            item => Ok(item)
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Cbor::Uint(n) => i64::try_from(*n).ok(),
+            Cbor::Negative(n) => i64::try_from(*n).ok().map(|n| -1 - n),
+            _ => None
+        }
+    }
+
+    fn get(&self, key: i64) -> Result<&Cbor, VerifierError> {
+        match self {
+            Cbor::Map(entries) => entries.iter()
+                .find(|(k, _)| k.int() == Some(key))
+                .map(|(_, v)| v)
+                .ok_or(VerifierError::MalformedToken("missing claim")),
+            _ => Err(VerifierError::MalformedToken("expected a CBOR map"))
+        }
+    }
+
+    fn bytes(&self) -> Result<&[u8], VerifierError> {
+        match self {
+            Cbor::Bytes(bytes) => Ok(bytes),
+            _ => Err(VerifierError::MalformedToken("expected a byte string"))
+        }
+    }
+
+    fn text(&self) -> Result<&str, VerifierError> {
+        match self {
+            Cbor::Text(text) => Ok(text),
+            _ => Err(VerifierError::MalformedToken("expected a text string"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p384::ecdsa::{signature::Signer, SigningKey};
+
+    use super::*;
+
+    const MEASUREMENT: [u8; 32] = [0xaa; 32];
+    const IMAGE: Uuid = Uuid::from_u128(1);
+
+    fn head(major: u8, arg: u64) -> Vec<u8> {
+        match arg {
+            0..=23 => vec![major << 5 | arg as u8],
+            24..=0xff => vec![major << 5 | 24, arg as u8],
+            _ => [&[major << 5 | 25][..], &(arg as u16).to_be_bytes()].concat()
+        }
+    }
+
+    fn int(n: i64) -> Vec<u8> {
+        match n {
+            0.. => head(0, n as u64),
+            _ => head(1, (-1 - n) as u64)
+        }
+    }
+
+    fn bstr(bytes: &[u8]) -> Vec<u8> {
+        [head(2, bytes.len() as u64), bytes.to_vec()].concat()
+    }
+
+    fn text(text: &str) -> Vec<u8> {
+        [head(3, text.len() as u64), text.as_bytes().to_vec()].concat()
+    }
+
+    fn map(entries: &[(i64, Vec<u8>)]) -> Vec<u8> {
+        let mut out = head(5, entries.len() as u64);
+        for (key, value) in entries {
+            out.extend(int(*key));
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 48]).unwrap()
+    }
+
+    fn sec1(key: &SigningKey) -> Vec<u8> {
+        VerifyingKey::from(key).to_encoded_point(false).as_bytes().to_vec()
+    }
+
+    fn sign1(key: &SigningKey, payload: &[u8]) -> Vec<u8> {
+        let protected = map(&[(COSE_ALG, int(COSE_ES384))]);
+        let tbs = [&[0x84][..], &text("Signature1"), &bstr(&protected), &bstr(&[]), &bstr(payload)].concat();
+        let signature: Signature = key.sign(&tbs);
+
+        [head(6, COSE_SIGN1_TAG), head(4, 4), bstr(&protected), map(&[]), bstr(payload), bstr(&signature.to_bytes())].concat()
+    }
+
+    fn realm_claims(rak: &[u8], challenge: &[u8]) -> Vec<u8> {
+        map(&[
+            (CHALLENGE, bstr(&pad_challenge(challenge).unwrap())),
+            (REALM_PUBLIC_KEY, bstr(rak)),
+            (REALM_INITIAL_MEASUREMENT, bstr(&MEASUREMENT)),
+            (REALM_PUBLIC_KEY_HASH_ALGO, text("sha-256"))
+        ])
+    }
+
+    fn platform_claims(rak: &[u8]) -> Vec<u8> {
+        map(&[(CHALLENGE, bstr(&Sha256::digest(rak)))])
+    }
+
+    fn cca(platform: &[u8], realm: &[u8]) -> Vec<u8> {
+        [head(6, CCA_TOKEN_TAG), map(&[(CCA_PLATFORM_TOKEN, bstr(platform)), (CCA_REALM_TOKEN, bstr(realm))])].concat()
+    }
+
+    // Token of a realm whose RAK is vouched for by `platform`
+    fn token(platform: &SigningKey, challenge: &[u8]) -> Vec<u8> {
+        let rak = key(2);
+        cca(&sign1(platform, &platform_claims(&sec1(&rak))), &sign1(&rak, &realm_claims(&sec1(&rak), challenge)))
+    }
+
+    fn verifier(accept_mock_tokens: bool) -> Verifier {
+        Verifier {
+            platform_key: Some(VerifyingKey::from(&key(1))),
+            accept_mock_tokens,
+            measurements: vec![MEASUREMENT.to_vec()],
+            images: HashMap::from([(IMAGE, ReleasedImage { root_of_trust: vec![1, 2, 3], image: PathBuf::from("/nonexistent") })])
+        }
+    }
+
+    fn mock_token(challenge: &[u8]) -> Vec<u8> {
+        serde_json::to_vec(&MockToken { challenge: pad_challenge(challenge).unwrap().to_vec(), measurement: MEASUREMENT.to_vec() }).unwrap()
+    }
+
+    #[test]
+    fn releases_to_signed_token() {
+        assert_eq!(verifier(false).release(&IMAGE, &token(&key(1), b"nonce"), b"nonce").unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_other_platform() {
+        let err = verifier(false).release(&IMAGE, &token(&key(3), b"nonce"), b"nonce").unwrap_err();
+        assert!(matches!(err, VerifierError::BadSignature("platform")));
+    }
+
+    #[test]
+    fn rejects_realm_token_not_signed_by_rak() {
+        let rak = sec1(&key(2));
+        let token = cca(&sign1(&key(1), &platform_claims(&rak)), &sign1(&key(3), &realm_claims(&rak, b"nonce")));
+
+        let err = verifier(false).release(&IMAGE, &token, b"nonce").unwrap_err();
+        assert!(matches!(err, VerifierError::BadSignature("realm")));
+    }
+
+    #[test]
+    fn rejects_rak_not_bound_to_platform() {
+        let rak = key(2);
+        let token = cca(&sign1(&key(1), &platform_claims(&sec1(&key(3)))), &sign1(&rak, &realm_claims(&sec1(&rak), b"nonce")));
+
+        let err = verifier(false).release(&IMAGE, &token, b"nonce").unwrap_err();
+        assert!(matches!(err, VerifierError::UnboundRealmKey()));
+    }
+
+    #[test]
+    fn rejects_stale_nonce() {
+        let err = verifier(false).release(&IMAGE, &token(&key(1), b"old"), b"nonce").unwrap_err();
+        assert!(matches!(err, VerifierError::ChallengeMismatch()));
+    }
+
+    #[test]
+    fn rejects_unknown_measurement_and_image() {
+        let mut verifier = verifier(false);
+        let token = token(&key(1), b"nonce");
+
+        assert!(matches!(verifier.release(&Uuid::from_u128(2), &token, b"nonce"), Err(VerifierError::UnknownImage(_))));
+
+        verifier.measurements.clear();
+        assert!(matches!(verifier.release(&IMAGE, &token, b"nonce"), Err(VerifierError::UnknownMeasurement(_))));
+    }
+
+    #[test]
+    fn mock_tokens_need_opt_in() {
+        let token = mock_token(b"nonce");
+
+        assert!(matches!(verifier(false).release(&IMAGE, &token, b"nonce"), Err(VerifierError::MockTokenRejected())));
+        assert!(verifier(true).release(&IMAGE, &token, b"nonce").is_ok());
+    }
+
+    #[test]
+    fn cca_tokens_need_platform_key() {
+        let mut verifier = verifier(true);
+        verifier.platform_key = None;
+
+        let err = verifier.release(&IMAGE, &token(&key(1), b"nonce"), b"nonce").unwrap_err();
+        assert!(matches!(err, VerifierError::NoPlatformKey()));
+    }
+
+    #[test]
+    fn realm_key_as_cose_key() {
+        let point = sec1(&key(2));
+        let cose = map(&[
+            (COSE_KEY_KTY, int(COSE_KTY_EC2)),
+            (COSE_KEY_CRV, int(COSE_CRV_P384)),
+            (COSE_KEY_X, bstr(&point[1..49])),
+            (COSE_KEY_Y, bstr(&point[49..]))
+        ]);
+
+        assert_eq!(public_key(&cose).unwrap(), VerifyingKey::from(&key(2)));
+    }
+
+    #[test]
+    fn truncated_token() {
+        let token = token(&key(1), b"nonce");
+        assert!(matches!(verifier(false).release(&IMAGE, &token[..token.len() - 1], b"nonce"), Err(VerifierError::MalformedToken(_))));
+    }
+
+    #[test]
+    fn byte_string_lengths() {
+        for len in [0, 23, 24, 255, 256, 65535, 65536] {
+            let mut encoded = Vec::new();
+            Cbor::encode_bytes(&mut encoded, &vec![7; len]);
+
+            let (decoded, rest) = Cbor::decode(&encoded).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(decoded.bytes().unwrap().len(), len);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use thiserror::Error;
+use tokio::process::Child;
+
+use crate::{fake::{FakeRealmError, FakeRunner}, kvmtool::{KvmtoolError, KvmtoolRunner}, qdisk::DiskFormat, qemu::{QEMUError, QEMURunner}, transport::TransportKind};
+
+#[derive(Error, Debug)]
+pub enum VMMError {
+    #[error("QEMU error")]
+    QEMUError(#[from] QEMUError),
+
+    #[error("kvmtool error")]
+    KvmtoolError(#[from] KvmtoolError),
+
+    #[error("Fake realm error")]
+    FakeRealmError(#[from] FakeRealmError)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum VMBackend {
+    /// qemu-system-aarch64
+    Qemu,
+
+    /// kvmtool (lkvm), the VMM used by the islet CCA stack
+    Kvmtool,
+
+    /// Host process emulating the realm, for testing without a hypervisor
+    Fake
+}
+
+impl VMBackend {
+    pub fn builder(&self, workdir: &Path) -> Box<dyn VMBuilder> {
+        match self {
+            VMBackend::Qemu => Box::new(QEMURunner::new()),
+            VMBackend::Kvmtool => Box::new(KvmtoolRunner::new()),
+            VMBackend::Fake => Box::new(FakeRunner::new(workdir))
+        }
+    }
+
+    pub fn default_transport(&self) -> TransportKind {
+        match self {
+            VMBackend::Qemu | VMBackend::Kvmtool => TransportKind::Vsock,
+            VMBackend::Fake => TransportKind::Serial
+        }
+    }
+}
+
+pub trait VMBuilder {
+    fn cpu(&mut self, ty: &dyn AsRef<str>);
+    fn machine(&mut self, ty: &dyn AsRef<str>);
+    fn core_count(&mut self, n: usize);
+    fn ram_size(&mut self, size_mb: usize);
+    fn tap_device(&mut self, name: &dyn AsRef<str>);
+    fn mac_addr(&mut self, addr: &dyn AsRef<str>);
+    fn vsock_cid(&mut self, cid: usize);
+    fn kernel(&mut self, image: &dyn AsRef<str>);
+    fn block_device(&mut self, path: &dyn AsRef<str>, format: DiskFormat);
+    fn stdout(&mut self, path: &dyn AsRef<str>);
+    fn serial_channel(&mut self, socket: &dyn AsRef<str>);
+    fn kernel_param(&mut self, param: &dyn AsRef<str>);
+    fn launch(&mut self) -> Result<Child, VMMError>;
+}